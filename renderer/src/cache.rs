@@ -0,0 +1,96 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
+use tokio::sync::Mutex;
+
+const INDEX_FILE: &str = "index.json";
+
+/// What we know about a cached URL: the file holding its bytes plus the validators
+/// needed for a conditional revalidation request.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub file: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Unix seconds until which the entry can be used without asking the server (Cache-Control max-age).
+    #[serde(default)]
+    pub fresh_until: Option<u64>,
+}
+
+impl CacheEntry {
+    pub fn has_validators(&self) -> bool { self.etag.is_some() || self.last_modified.is_some() }
+
+    pub fn is_fresh(&self) -> bool { self.fresh_until.is_some_and(|t| crate::jobs::now_since_epoch().as_secs() < t) }
+
+    /// Validators and freshness from response headers; None when the response says not to store it.
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Option<Self> {
+        let get = |name| headers.get(name).and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok()).map(|s| s.to_string());
+        let cache_control = get(reqwest::header::CACHE_CONTROL).unwrap_or_default().to_ascii_lowercase();
+        let directives: Vec<&str> = cache_control.split(',').map(|d| d.trim()).collect();
+        if directives.contains(&"no-store") { return None; }
+        let max_age = directives.iter().find_map(|d| d.strip_prefix("max-age=")).and_then(|v| v.parse::<u64>().ok());
+        let fresh_until = max_age.filter(|_| !directives.contains(&"no-cache")).map(|s| crate::jobs::now_since_epoch().as_secs() + s);
+        Some(Self { file: String::new(), etag: get(reqwest::header::ETAG), last_modified: get(reqwest::header::LAST_MODIFIED), fresh_until })
+    }
+}
+
+/// Asset cache shared across jobs (RENDER_ASSET_CACHE). Files are stored under their
+/// hash-prefixed names; `index.json` maps source URL to file and validators.
+#[derive(Clone, Debug)]
+pub struct AssetCache {
+    dir: PathBuf,
+    index: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl AssetCache {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let index = std::fs::read(dir.join(INDEX_FILE)).ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default();
+        Ok(Self { dir, index: Arc::new(Mutex::new(index)) })
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var("RENDER_ASSET_CACHE").ok().filter(|s| !s.is_empty())?;
+        match Self::open(PathBuf::from(&dir)) {
+            Ok(c) => Some(c),
+            Err(e) => { tracing::warn!("asset cache disabled, cannot open {}: {}", dir, e); None }
+        }
+    }
+
+    /// Entry for `url` whose file is still present.
+    pub async fn lookup(&self, url: &str) -> Option<CacheEntry> {
+        let entry = self.index.lock().await.get(url).cloned()?;
+        self.dir.join(&entry.file).is_file().then_some(entry)
+    }
+
+    /// Hardlink (or copy, across filesystems) the cached file into `dest_dir`.
+    pub async fn link_into(&self, entry: &CacheEntry, dest_dir: &Path) -> Result<PathBuf> {
+        let src = self.dir.join(&entry.file);
+        let dest = dest_dir.join(&entry.file);
+        if !dest.exists() { link_or_copy(&src, &dest).await?; }
+        Ok(dest)
+    }
+
+    pub async fn size(&self, entry: &CacheEntry) -> Result<u64> { Ok(tokio::fs::metadata(self.dir.join(&entry.file)).await?.len()) }
+
+    /// Record a freshly downloaded file for `url`. `entry` carries the response's validators.
+    pub async fn store(&self, url: &str, path: &Path, mut entry: CacheEntry) -> Result<()> {
+        entry.file = path.file_name().map(|n| n.to_string_lossy().to_string()).ok_or_else(|| anyhow::anyhow!("no file name"))?;
+        let cached = self.dir.join(&entry.file);
+        if !cached.exists() { link_or_copy(path, &cached).await?; }
+        let mut index = self.index.lock().await;
+        index.insert(url.to_string(), entry);
+        // persist while holding the lock so concurrent stores can't interleave writes
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        tokio::fs::write(&tmp, serde_json::to_vec(&*index)?).await?;
+        tokio::fs::rename(&tmp, self.dir.join(INDEX_FILE)).await?;
+        Ok(())
+    }
+}
+
+pub async fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if tokio::fs::hard_link(src, dest).await.is_err() { tokio::fs::copy(src, dest).await?; }
+    Ok(())
+}
@@ -1,82 +1,1580 @@
-use crate::types::{Design, TrackItem, TrackType};
+use crate::{cache::{link_or_copy, AssetCache, CacheEntry}, net::{UrlError, UrlPolicy}, types::{Design, Details, FfprobeInfo, Fill, FrameSequence, KenBurns, Keyframe, Length, Quality, Rendition, Size, Subtitles, Timeline, TimelineItem, TrackItem, TrackType, Watermark}};
 use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
-use std::{fs, path::{Path, PathBuf}};
-use tokio::{io::AsyncWriteExt, process::Command};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, process::Command};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct BackendCaps {
+    /// `ffmpeg -encoders` ran successfully at startup.
+    pub ffmpeg: bool,
     pub nvenc: bool,
+    pub qsv: bool,
+    pub vaapi: bool,
+    pub videotoolbox: bool,
+    /// `ffprobe -version` ran; assets are probed for fps/duration only when set.
+    pub ffprobe: bool,
+}
+
+/// The ffmpeg binary to run: RENDER_FFMPEG_PATH, or `ffmpeg` from PATH.
+pub fn ffmpeg_bin() -> String {
+    std::env::var("RENDER_FFMPEG_PATH").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| "ffmpeg".into())
 }
 
-pub async fn detect_caps() -> BackendCaps {
+/// The ffprobe binary: RENDER_FFPROBE_PATH, or `ffprobe` from PATH.
+pub fn ffprobe_bin() -> String {
+    std::env::var("RENDER_FFPROBE_PATH").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| "ffprobe".into())
+}
+
+/// Whitespace-separated global options from RENDER_FFMPEG_EXTRA_ARGS (e.g. `-threads 4`), added ahead of the inputs.
+fn ffmpeg_extra_args() -> Vec<String> {
+    std::env::var("RENDER_FFMPEG_EXTRA_ARGS").map(|s| s.split_whitespace().map(String::from).collect()).unwrap_or_default()
+}
+
+/// What the `ffmpeg` and `ffprobe` binaries (normally `ffmpeg_bin()` and `ffprobe_bin()`) can do.
+pub async fn detect_caps(ffmpeg: &str, ffprobe: &str) -> BackendCaps {
     // Try to detect h264_nvenc support
-    let output = Command::new("ffmpeg").arg("-hide_banner").arg("-encoders").output().await;
-    let nvenc = output.ok()
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .map(|s| s.contains("h264_nvenc"))
-        .unwrap_or(false);
-    BackendCaps { nvenc }
+    let output = Command::new(ffmpeg).arg("-hide_banner").arg("-encoders").output().await;
+    let encoders = output.ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok());
+    let has = |name: &str| encoders.as_ref().map(|s| s.contains(name)).unwrap_or(false);
+    let ffprobe = Command::new(ffprobe).arg("-version").output().await.is_ok_and(|o| o.status.success());
+    BackendCaps {
+        ffprobe,
+        ffmpeg: encoders.is_some(),
+        nvenc: has("h264_nvenc"),
+        qsv: has("h264_qsv"),
+        vaapi: has("h264_vaapi"),
+        videotoolbox: has("h264_videotoolbox"),
+    }
+}
+
+/// H.264 encoder used for mp4/mov output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoEncoder { Nvenc, Qsv, VideoToolbox, Vaapi, X264 }
+
+impl VideoEncoder {
+    /// Priority: NVENC, QSV, VideoToolbox, VAAPI, then libx264. VAAPI comes last among the
+    /// hardware options because it needs a render node and an explicit upload step.
+    pub fn select(caps: &BackendCaps) -> Self {
+        if caps.nvenc { VideoEncoder::Nvenc }
+        else if caps.qsv { VideoEncoder::Qsv }
+        else if caps.videotoolbox { VideoEncoder::VideoToolbox }
+        else if caps.vaapi { VideoEncoder::Vaapi }
+        else { VideoEncoder::X264 }
+    }
+
+    /// Global args placed before the inputs.
+    fn global_args(self) -> Vec<String> {
+        match self {
+            VideoEncoder::Nvenc => vec!["-hwaccel".into(), "cuda".into()],
+            VideoEncoder::Vaapi => vec!["-vaapi_device".into(), std::env::var("RENDER_VAAPI_DEVICE").unwrap_or_else(|_| "/dev/dri/renderD128".into())],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Filter appended to the final video chain to get frames into the encoder's format.
+    fn upload_filter(self) -> Option<&'static str> {
+        match self { VideoEncoder::Vaapi => Some("format=nv12,hwupload"), _ => None }
+    }
+
+    fn codec_args(self) -> Vec<String> {
+        let args: &[&str] = match self {
+            VideoEncoder::Nvenc => &["-c:v", "h264_nvenc", "-preset", "p4", "-pix_fmt", "yuv420p"],
+            VideoEncoder::Qsv => &["-c:v", "h264_qsv", "-preset", "veryfast", "-pix_fmt", "nv12"],
+            VideoEncoder::VideoToolbox => &["-c:v", "h264_videotoolbox", "-pix_fmt", "yuv420p"],
+            VideoEncoder::Vaapi => &["-c:v", "h264_vaapi"],
+            VideoEncoder::X264 => &["-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p"],
+        };
+        args.iter().map(|a| a.to_string()).collect()
+    }
+}
+
+/// Limits and policies applied when fetching assets. Read once from the environment at startup.
+#[derive(Clone, Debug)]
+pub struct DownloadConfig {
+    pub max_data_uri_bytes: usize,
+    /// Extra attempts for transient HTTP failures (connect errors, timeouts, 5xx, 429).
+    pub retries: u32,
+    /// Delay before the first retry; doubles on each subsequent attempt.
+    pub retry_base_delay: Duration,
+    pub max_asset_bytes: u64,
+    /// Cap on the sum of all assets fetched for one job.
+    pub max_job_bytes: u64,
+    pub url_policy: UrlPolicy,
+    /// Shared cache for remote assets; None when RENDER_ASSET_CACHE is unset.
+    pub cache: Option<AssetCache>,
+    /// RENDER_ALLOWED_FORMATS, lowercased; None when set to `*` (no check).
+    pub allowed_formats: Option<Vec<String>>,
+    /// RENDER_LOCAL_ROOTS, canonicalized: the directories local paths and file:// sources may be read
+    /// from. Empty (the default) refuses local sources.
+    pub local_roots: Vec<PathBuf>,
+}
+
+impl DownloadConfig {
+    pub fn from_env() -> Self {
+        let env_num = |k: &str| std::env::var(k).ok().and_then(|s| s.parse::<usize>().ok());
+        Self {
+            max_data_uri_bytes: env_num("RENDER_MAX_DATA_URI_BYTES").unwrap_or(10 * 1024 * 1024),
+            retries: env_num("RENDER_DOWNLOAD_RETRIES").unwrap_or(3) as u32,
+            retry_base_delay: Duration::from_millis(env_num("RENDER_DOWNLOAD_RETRY_BASE_MS").unwrap_or(500) as u64),
+            max_asset_bytes: env_num("RENDER_MAX_ASSET_BYTES").map(|n| n as u64).unwrap_or(2 << 30),
+            max_job_bytes: env_num("RENDER_MAX_JOB_BYTES").map(|n| n as u64).unwrap_or(10 << 30),
+            url_policy: UrlPolicy::from_env(),
+            cache: AssetCache::from_env(),
+            allowed_formats: match std::env::var("RENDER_ALLOWED_FORMATS").ok().filter(|s| !s.trim().is_empty()) {
+                Some(s) if s.trim() == "*" => None,
+                s => Some(s.as_deref().unwrap_or(DEFAULT_ALLOWED_FORMATS).split(',').map(|f| f.trim().to_ascii_lowercase()).filter(|f| !f.is_empty()).collect()),
+            },
+            // separated like PATH; a root that doesn't exist can't hold anything and is dropped
+            local_roots: std::env::var_os("RENDER_LOCAL_ROOTS").map(|v| std::env::split_paths(&v).filter(|p| !p.as_os_str().is_empty())
+                .filter_map(|p| std::fs::canonicalize(&p).map_err(|e| tracing::warn!("ignoring local root {}: {}", p.display(), e)).ok())
+                .collect()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Inputs ffmpeg may open unless RENDER_ALLOWED_FORMATS says otherwise: the usual delivery containers,
+/// audio files and web images, by ffprobe format name, image codec and file extension.
+pub const DEFAULT_ALLOWED_FORMATS: &str = "mov,mp4,m4a,matroska,webm,mkv,mp3,wav,ogg,oga,opus,flac,aac,gif,apng,png,png_pipe,jpg,jpeg,jpeg_pipe,mjpeg,webp,webp_pipe,image2";
+
+/// Why a downloaded input isn't on the format allowlist; None when it is. With ffprobe's answer the
+/// container decides (any of its aliases); image2, which opens dozens of image formats by file name,
+/// also needs the codec listed. Without one (no ffprobe, frame sequences) the file extension decides.
+pub fn format_error(path: &Path, probe: Option<&FfprobeInfo>, allowed: &[String]) -> Option<String> {
+    let ok = |name: &str| allowed.iter().any(|a| a.eq_ignore_ascii_case(name));
+    match probe.and_then(|p| p.format_name.as_deref()) {
+        Some(format) => {
+            let codec = probe.and_then(|p| p.video_codec.as_deref()).unwrap_or("unknown");
+            if !format.split(',').any(ok) { Some(format!("format '{}' is not allowed", format)) }
+            else if format.split(',').any(|f| f == "image2") && !ok(codec) { Some(format!("image codec '{}' is not allowed", codec)) }
+            else { None }
+        }
+        None => {
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+            (!ok(ext)).then(|| format!("file type '.{}' is not allowed", ext))
+        }
+    }
+}
+
+/// Byte accounting for a single asset against the per-asset limit and the job-wide total
+/// shared by concurrent downloads. Bytes are refunded if the asset fails.
+struct ByteBudget<'a> {
+    cfg: &'a DownloadConfig,
+    job_bytes: &'a AtomicU64,
+    written: u64,
+}
+
+impl<'a> ByteBudget<'a> {
+    fn new(cfg: &'a DownloadConfig, job_bytes: &'a AtomicU64) -> Self { Self { cfg, job_bytes, written: 0 } }
+
+    /// Reject up front when the size is known (Content-Length, file metadata).
+    fn check_expected(&self, len: u64) -> Result<()> {
+        if len > self.cfg.max_asset_bytes { return Err(anyhow!("asset is {} bytes, limit is {}", len, self.cfg.max_asset_bytes)); }
+        let used = self.job_bytes.load(Ordering::Relaxed);
+        if used + len > self.cfg.max_job_bytes { return Err(anyhow!("job download total would exceed {} bytes", self.cfg.max_job_bytes)); }
+        Ok(())
+    }
+
+    fn add(&mut self, n: u64) -> Result<()> {
+        self.written += n;
+        let total = self.job_bytes.fetch_add(n, Ordering::Relaxed) + n;
+        if self.written > self.cfg.max_asset_bytes { return Err(anyhow!("asset exceeds {} bytes", self.cfg.max_asset_bytes)); }
+        if total > self.cfg.max_job_bytes { return Err(anyhow!("job download total exceeds {} bytes", self.cfg.max_job_bytes)); }
+        Ok(())
+    }
+
+    fn refund(&mut self) {
+        self.job_bytes.fetch_sub(self.written, Ordering::Relaxed);
+        self.written = 0;
+    }
 }
 
-pub async fn download_asset(url: &str, dest_dir: &Path) -> Result<PathBuf> {
-    let resp = reqwest::get(url).await.context("download request failed")?;
+/// `download_asset`, except that a file an earlier run of the job got from the same URL (`reuse`,
+/// when retrying) is linked into `dest_dir` instead. Falls back to fetching if that file is gone.
+pub async fn download_or_reuse(url: &str, headers: &HeaderMap, dest_dir: &Path, cfg: &DownloadConfig, job_bytes: &AtomicU64, reuse: &HashMap<String, PathBuf>) -> Result<PathBuf> {
+    if let Some((prev, name)) = reuse.get(url).and_then(|p| Some((p, p.file_name()?))) {
+        let dest = dest_dir.join(name);
+        match link_or_copy(prev, &dest).await {
+            Ok(()) => return Ok(dest),
+            Err(e) => tracing::debug!("not reusing {}: {:#}", prev.display(), e),
+        }
+    }
+    download_asset(url, headers, dest_dir, cfg, job_bytes).await
+}
+
+/// Fetch `url` into `dest_dir`. `headers` go with remote requests only; `job_bytes` is the running
+/// total for the job, shared across concurrent calls.
+pub async fn download_asset(url: &str, headers: &HeaderMap, dest_dir: &Path, cfg: &DownloadConfig, job_bytes: &AtomicU64) -> Result<PathBuf> {
+    if url.len() > 5 && url[..5].eq_ignore_ascii_case("data:") { return write_data_uri(url, dest_dir, cfg, job_bytes).await; }
+    let parsed = url::Url::parse(url).ok();
+    // Local sources: file:// URLs, or anything that isn't a URL is treated as a filesystem path
+    let local = match &parsed {
+        Some(u) if u.scheme() == "file" => Some(u.to_file_path().map_err(|_| anyhow!("invalid file url: {}", url))?),
+        // Windows drive paths (C:\clips\a.mp4) parse as a one-letter scheme
+        Some(u) if u.scheme().len() == 1 && Path::new(url).exists() => Some(PathBuf::from(url)),
+        None => Some(PathBuf::from(url)),
+        _ => None,
+    };
+    if let Some(src) = local { return copy_local_asset(&local_source(&src, &cfg.local_roots).await?, dest_dir, cfg, job_bytes).await; }
+    let remote = parsed.ok_or_else(|| anyhow!("invalid asset url: {}", url))?;
+
+    // requests with credentials get their own cache entries, keyed by a hash rather than the secrets themselves
+    let cache_key = cache_key(url, headers);
+    let cached = match &cfg.cache { Some(cache) => cache.lookup(&cache_key).await, None => None };
+    if let (Some(cache), Some(entry)) = (&cfg.cache, &cached) {
+        if entry.is_fresh() { return link_cached(cache, entry, dest_dir, cfg, job_bytes).await; }
+    }
+    // stale entries without validators can't be revalidated, so they're fetched again in full
+    let validators = cached.as_ref().filter(|e| e.has_validators());
+
+    let mut attempt = 0u32;
+    loop {
+        match fetch_http(&remote, headers, dest_dir, cfg, ByteBudget::new(cfg, job_bytes), validators).await {
+            Ok(Fetched::Downloaded(path, fresh)) => {
+                if let (Some(cache), Some(entry)) = (&cfg.cache, fresh) {
+                    if let Err(e) = cache.store(&cache_key, &path, entry).await { tracing::warn!("could not cache {}: {:#}", url, e); }
+                }
+                return Ok(path);
+            }
+            Ok(Fetched::NotModified(fresh)) => {
+                let (cache, entry) = cfg.cache.as_ref().zip(cached.as_ref()).ok_or_else(|| anyhow!("unexpected 304 for {}", url))?;
+                let path = link_cached(cache, entry, dest_dir, cfg, job_bytes).await?;
+                // keep the old validators if the 304 didn't repeat them
+                let entry = CacheEntry { etag: fresh.etag.or(entry.etag.clone()), last_modified: fresh.last_modified.or(entry.last_modified.clone()), ..fresh };
+                if let Err(e) = cache.store(&cache_key, &path, entry).await { tracing::warn!("could not update cache entry for {}: {:#}", url, e); }
+                return Ok(path);
+            }
+            Err(FetchError { err, retryable }) if retryable && attempt < cfg.retries => {
+                attempt += 1;
+                let delay = cfg.retry_base_delay * 2u32.saturating_pow(attempt - 1);
+                tracing::warn!(attempt, max = cfg.retries, ?delay, "retrying download of {}: {:#}", url, err);
+                tokio::time::sleep(delay).await;
+            }
+            Err(FetchError { err, .. }) => return Err(err),
+        }
+    }
+}
+
+/// `template` with its frame number placeholder (`%d`, `%04d`) replaced by `n`; None without one.
+pub fn sequence_frame_src(template: &str, n: u32) -> Option<String> {
+    let (head, rest) = template.split_once('%')?;
+    let digits = rest.find('d')?;
+    let width = &rest[..digits];
+    if !width.chars().all(|c| c.is_ascii_digit()) || width.len() > 2 { return None; }
+    let width: usize = width.parse().unwrap_or(0);
+    Some(format!("{}{:0width$}{}", head, n, &rest[digits + 1..], width = width))
+}
+
+/// Download every frame of a sequence into `dest_dir` (created here) as `000000.<ext>`, `000001.<ext>`, ...
+/// keeping the source frame numbers, and return the `%06d` pattern for ffmpeg's image2 input.
+pub async fn download_sequence(template: &str, seq: &FrameSequence, headers: &HeaderMap, dest_dir: &Path, cfg: &DownloadConfig, job_bytes: &AtomicU64, concurrency: usize) -> Result<PathBuf> {
+    use futures_util::{StreamExt, TryStreamExt};
+    tokio::fs::create_dir_all(dest_dir).await?;
+    let ext = Path::new(template.split(['?', '#']).next().unwrap_or(template)).extension().and_then(|e| e.to_str()).unwrap_or("png").to_string();
+    let start = seq.start.unwrap_or(0);
+    futures_util::stream::iter(start..start + seq.count)
+        .map(|n| {
+            let ext = &ext;
+            async move {
+                let src = sequence_frame_src(template, n).ok_or_else(|| anyhow!("src has no frame number pattern"))?;
+                let path = download_asset(&src, headers, dest_dir, cfg, job_bytes).await.with_context(|| format!("frame {}", n))?;
+                tokio::fs::rename(&path, dest_dir.join(format!("{:06}.{}", n, ext))).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect::<()>()
+        .await?;
+    Ok(dest_dir.join(format!("%06d.{}", ext)))
+}
+
+/// Frame rate of a sequence item: its own, else the output's.
+fn sequence_fps(seq: &FrameSequence, design: &Design) -> f64 {
+    seq.fps.filter(|f| *f > 0.0).map(|f| f as f64).unwrap_or(design.fps.unwrap_or(30) as f64)
+}
+
+fn cache_key(url: &str, headers: &HeaderMap) -> String {
+    if headers.is_empty() { return url.to_string(); }
+    let mut pairs: Vec<(&str, &[u8])> = headers.iter().map(|(k, v)| (k.as_str(), v.as_bytes())).collect();
+    pairs.sort();
+    let mut hasher = Sha256::new();
+    for (k, v) in pairs { hasher.update(k.as_bytes()); hasher.update(b":"); hasher.update(v); hasher.update(b"\n"); }
+    format!("{}#headers={}", url, &hex::encode(hasher.finalize())[..16])
+}
+
+/// Link a cached asset into the job dir, counting it against the job's byte budget like a download.
+async fn link_cached(cache: &AssetCache, entry: &CacheEntry, dest_dir: &Path, cfg: &DownloadConfig, job_bytes: &AtomicU64) -> Result<PathBuf> {
+    let mut budget = ByteBudget::new(cfg, job_bytes);
+    let len = cache.size(entry).await?;
+    budget.check_expected(len)?;
+    budget.add(len)?;
+    cache.link_into(entry, dest_dir).await
+}
+
+enum Fetched {
+    /// New body written to the path; the entry is None when the response forbids caching.
+    Downloaded(PathBuf, Option<CacheEntry>),
+    /// 304 to a conditional request: the cached copy is still good.
+    NotModified(CacheEntry),
+}
+
+struct FetchError { err: anyhow::Error, retryable: bool }
+
+impl FetchError {
+    fn fatal(err: impl Into<anyhow::Error>) -> Self { Self { err: err.into(), retryable: false } }
+    fn retryable(err: impl Into<anyhow::Error>) -> Self { Self { err: err.into(), retryable: true } }
+    /// Connection resets, timeouts and body read failures are worth another try
+    fn from_reqwest(e: reqwest::Error) -> Self {
+        let retryable = e.is_connect() || e.is_timeout() || e.is_request() || e.is_body();
+        Self { err: anyhow::Error::new(e).context("download request failed"), retryable }
+    }
+}
+
+const MAX_REDIRECTS: usize = 10;
+
+/// GET `url`, following redirects by hand so every hop goes through the URL policy and
+/// connects to the addresses that were actually checked.
+/// Caller headers are dropped once a redirect leaves the original origin, so credentials
+/// only go where they were meant to.
+async fn send_checked(url: &url::Url, cfg: &DownloadConfig, mut headers: HeaderMap) -> std::result::Result<reqwest::Response, FetchError> {
+    let mut current = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        if current.origin() != url.origin() {
+            headers = [reqwest::header::IF_NONE_MATCH, reqwest::header::IF_MODIFIED_SINCE].into_iter()
+                .filter_map(|name| headers.get(&name).cloned().map(|v| (name, v)))
+                .collect();
+        }
+        let client = cfg.url_policy.client_for(&current).await.map_err(FetchError::fatal)?;
+        let resp = client.get(current.clone()).headers(headers.clone()).send().await.map_err(FetchError::from_reqwest)?;
+        if !resp.status().is_redirection() || resp.status() == reqwest::StatusCode::NOT_MODIFIED { return Ok(resp); }
+        let location = resp.headers().get(reqwest::header::LOCATION).and_then(|v| v.to_str().ok())
+            .ok_or_else(|| FetchError::fatal(anyhow!("redirect {} without Location", resp.status())))?;
+        current = current.join(location).map_err(FetchError::fatal)?;
+    }
+    Err(FetchError::fatal(anyhow!("too many redirects")))
+}
+
+/// GET a small http(s) document (a design) into memory under the URL policy, failing once it
+/// passes `limit` bytes. No retries or caching.
+pub async fn fetch_to_memory(url: &str, headers: &HeaderMap, cfg: &DownloadConfig, limit: usize) -> Result<Vec<u8>> {
+    let parsed = url::Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") { return Err(anyhow!("only http(s) URLs are supported")); }
+    let resp = send_checked(&parsed, cfg, headers.clone()).await.map_err(|e| e.err)?;
     if !resp.status().is_success() { return Err(anyhow!("bad status {}", resp.status())); }
+    if resp.content_length().is_some_and(|len| len > limit as u64) { return Err(anyhow!("larger than {} bytes", limit)); }
+    let mut body = Vec::new();
+    let bytes_stream = resp.bytes_stream();
+    use futures_util::StreamExt;
+    futures_util::pin_mut!(bytes_stream);
+    while let Some(chunk) = bytes_stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limit { return Err(anyhow!("larger than {} bytes", limit)); }
+    }
+    Ok(body)
+}
+
+async fn fetch_http(url: &url::Url, extra_headers: &HeaderMap, dest_dir: &Path, cfg: &DownloadConfig, mut budget: ByteBudget<'_>, validators: Option<&CacheEntry>) -> std::result::Result<Fetched, FetchError> {
+    let mut headers = extra_headers.clone();
+    if let Some(v) = validators {
+        let mut set = |name, value: &Option<String>| if let Some(h) = value.as_deref().and_then(|s| reqwest::header::HeaderValue::from_str(s).ok()) { headers.insert(name, h); };
+        set(reqwest::header::IF_NONE_MATCH, &v.etag);
+        set(reqwest::header::IF_MODIFIED_SINCE, &v.last_modified);
+    }
+    let resp = send_checked(url, cfg, headers).await?;
+    let status = resp.status();
+    let fresh = if cfg.cache.is_some() { CacheEntry::from_headers(resp.headers()) } else { None };
+    if status == reqwest::StatusCode::NOT_MODIFIED && validators.is_some() { return Ok(Fetched::NotModified(fresh.unwrap_or_default())); }
+    if !status.is_success() {
+        let err = anyhow!("bad status {}", status);
+        // 5xx and 429 are transient; any other 4xx won't get better by asking again
+        return Err(if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS { FetchError::retryable(err) } else { FetchError::fatal(err) });
+    }
+    // Content-Length lets us bail before writing anything; the streaming check below still applies since it can lie
+    if let Some(len) = resp.content_length() { budget.check_expected(len).map_err(FetchError::fatal)?; }
     let bytes_stream = resp.bytes_stream();
     let mut hasher = Sha256::new();
-    let parsed = url::Url::parse(url).ok();
-    let filename = parsed
-        .as_ref()
-        .and_then(|u| u.path_segments().and_then(|mut s| s.next_back().map(|x| x.to_string())))
+    let filename = url
+        .path_segments()
+        .and_then(|mut s| s.next_back().map(|x| x.to_string()))
         .filter(|s| !s.is_empty())
         .unwrap_or_else(|| "asset.bin".to_string());
-    let mut out_path = dest_dir.join(&filename);
-    // ensure unique name
-    let mut idx = 0;
-    while out_path.exists() { idx += 1; out_path = dest_dir.join(format!("{}-{}", idx, filename)); }
-
-    let mut file = tokio::fs::File::create(&out_path).await?;
+    let (out_path, mut file) = create_part_file(dest_dir).await.map_err(FetchError::fatal)?;
     use futures_util::StreamExt;
     futures_util::pin_mut!(bytes_stream);
-    while let Some(chunk) = bytes_stream.next().await { let b = chunk?; hasher.update(&b); file.write_all(&b).await?; }
-    file.flush().await?;
+    while let Some(chunk) = bytes_stream.next().await {
+        let res = match chunk {
+            Ok(b) => match budget.add(b.len() as u64) {
+                Ok(()) => { hasher.update(&b); file.write_all(&b).await.map_err(FetchError::fatal) }
+                Err(e) => Err(FetchError::fatal(e)),
+            },
+            Err(e) => Err(FetchError::from_reqwest(e)),
+        };
+        if let Err(e) = res {
+            budget.refund();
+            drop(file);
+            let _ = tokio::fs::remove_file(&out_path).await;
+            return Err(e);
+        }
+    }
+    file.flush().await.map_err(FetchError::fatal)?;
+
+    let path = rename_with_hash(&out_path, &filename, hasher).await.map_err(FetchError::fatal)?;
+    Ok(Fetched::Downloaded(path, fresh))
+}
+
+async fn write_data_uri(uri: &str, dest_dir: &Path, cfg: &DownloadConfig, job_bytes: &AtomicU64) -> Result<PathBuf> {
+    // data:[<mediatype>][;base64],<payload>
+    let (meta, payload) = uri[5..].split_once(',').ok_or_else(|| anyhow!("malformed data URI: missing ','"))?;
+    let mut parts = meta.split(';');
+    let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    if !parts.any(|p| p.trim().eq_ignore_ascii_case("base64")) { return Err(anyhow!("only base64 data URIs are supported")); }
+    // cheap upper bound before decoding anything
+    if payload.len() / 4 * 3 > cfg.max_data_uri_bytes + 3 { return Err(anyhow!("data URI exceeds {} bytes", cfg.max_data_uri_bytes)); }
+    use base64::Engine;
+    let payload: String = payload.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    let bytes = base64::engine::general_purpose::STANDARD.decode(payload.as_bytes()).context("malformed base64 in data URI")?;
+    if bytes.len() > cfg.max_data_uri_bytes { return Err(anyhow!("data URI exceeds {} bytes", cfg.max_data_uri_bytes)); }
+    let mut budget = ByteBudget::new(cfg, job_bytes);
+    if let Err(e) = budget.add(bytes.len() as u64) { budget.refund(); return Err(e); }
+
+    let (out_path, mut file) = create_part_file(dest_dir).await?;
+    let written = async { file.write_all(&bytes).await?; file.flush().await }.await;
+    drop(file);
+    if let Err(e) = written { let _ = tokio::fs::remove_file(&out_path).await; return Err(e.into()); }
+    let mut hasher = Sha256::new();
+    hasher.update(&bytes);
+    rename_with_hash(&out_path, &format!("inline.{}", extension_for_media_type(&media_type)), hasher).await
+}
+
+fn extension_for_media_type(media_type: &str) -> &str {
+    match media_type {
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/gif" => "gif",
+        "image/apng" => "apng",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/quicktime" => "mov",
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/ogg" => "ogg",
+        "audio/aac" => "aac",
+        "font/ttf" | "application/x-font-ttf" | "application/font-sfnt" => "ttf",
+        "font/otf" | "application/x-font-otf" => "otf",
+        "font/woff" | "application/font-woff" => "woff",
+        "font/woff2" => "woff2",
+        other => other.split_once('/').map(|(_, sub)| sub).filter(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric())).unwrap_or("bin"),
+    }
+}
+
+/// `src` with symlinks and `..` resolved, if that lies under one of `roots`. Missing files and files
+/// outside the roots get the same error, so requests can't probe the server's filesystem.
+async fn local_source(src: &Path, roots: &[PathBuf]) -> Result<PathBuf> {
+    if roots.is_empty() { return Err(UrlError::Scheme("file".into()).into()); }
+    match tokio::fs::canonicalize(src).await {
+        Ok(real) if roots.iter().any(|r| real.starts_with(r)) => Ok(real),
+        _ => Err(anyhow!("local asset {} is not a file under RENDER_LOCAL_ROOTS", src.display())),
+    }
+}
+
+async fn copy_local_asset(src: &Path, dest_dir: &Path, cfg: &DownloadConfig, job_bytes: &AtomicU64) -> Result<PathBuf> {
+    if !src.is_file() { return Err(anyhow!("local asset not found: {}", src.display())); }
+    let mut budget = ByteBudget::new(cfg, job_bytes);
+    budget.check_expected(tokio::fs::metadata(src).await?.len())?;
+    let filename = src.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "asset.bin".to_string());
+    let (out_path, mut file) = create_part_file(dest_dir).await?;
+
+    let copied = async {
+        let mut input = tokio::fs::File::open(src).await.with_context(|| format!("open {}", src.display()))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = input.read(&mut buf).await?;
+            if n == 0 { break; }
+            budget.add(n as u64)?;
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).await?;
+        }
+        file.flush().await?;
+        Ok::<_, anyhow::Error>(hasher)
+    }.await;
+    match copied {
+        Ok(hasher) => rename_with_hash(&out_path, &filename, hasher).await,
+        Err(e) => {
+            budget.refund();
+            drop(file);
+            let _ = tokio::fs::remove_file(&out_path).await;
+            Err(e)
+        }
+    }
+}
+
+/// A new, exclusively created `<uuid>.part` file in `dest_dir`, so parallel downloads in the same job
+/// dir never write to the same file whatever their source names are.
+async fn create_part_file(dest_dir: &Path) -> Result<(PathBuf, tokio::fs::File)> {
+    let path = dest_dir.join(format!("{}.part", uuid::Uuid::new_v4().simple()));
+    let file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await.with_context(|| format!("create {}", path.display()))?;
+    Ok((path, file))
+}
+
+/// Move a finished part file to `<hash prefix>-<filename>`. An existing file of that name has the same
+/// content (same source name, same hash), so the new copy is dropped and the existing one used.
+async fn rename_with_hash(part_path: &Path, filename: &str, hasher: Sha256) -> Result<PathBuf> {
+    let hash = hex::encode(hasher.finalize());
+    let new_path = part_path.with_file_name(format!("{}-{}", &hash[..16], filename));
+    if tokio::fs::try_exists(&new_path).await.unwrap_or(false) {
+        let _ = tokio::fs::remove_file(part_path).await;
+        return Ok(new_path);
+    }
+    if let Err(e) = tokio::fs::rename(part_path, &new_path).await {
+        // lost a race to an identical download (rename doesn't replace on Windows)
+        let _ = tokio::fs::remove_file(part_path).await;
+        if !tokio::fs::try_exists(&new_path).await.unwrap_or(false) { return Err(e.into()); }
+    }
+    Ok(new_path)
+}
+
+/// Grab a single JPEG frame from `input` at `at_s` seconds into `out`.
+pub async fn extract_frame(input: &Path, at_s: f64, out: &Path) -> Result<()> {
+    // write to a temp name first so a concurrent request never serves a half-written file
+    let tmp = out.with_extension("tmp.jpg");
+    let output = Command::new(ffmpeg_bin())
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-ss", &format!("{:.3}", at_s), "-i"])
+        .arg(input)
+        .args(["-frames:v", "1", "-q:v", "3"])
+        .arg(&tmp)
+        .output()
+        .await
+        .context("spawn ffmpeg")?;
+    if !output.status.success() || !tmp.exists() {
+        return Err(anyhow!("frame extraction failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    tokio::fs::rename(&tmp, out).await?;
+    Ok(())
+}
+
+/// Tracks ffmpeg `-progress` output. `out_time_us` is preferred; `out_time_ms` is only used when a build
+/// doesn't emit it, and despite its name ffmpeg has always written microseconds there too.
+pub struct ProgressParser { total_ms: f64, seen_us: bool }
+
+impl ProgressParser {
+    pub fn new(total_ms: u64) -> Self { Self { total_ms: total_ms.max(1) as f64, seen_us: false } }
+
+    /// Percent complete if `line` carries a position, 100 on `progress=end`.
+    pub fn feed(&mut self, line: &str) -> Option<u32> {
+        let (key, val) = line.trim().split_once('=')?;
+        let us = match key {
+            "progress" if val == "end" => return Some(100),
+            "out_time_us" => { self.seen_us = true; val.parse::<i64>().ok()? }
+            "out_time_ms" if !self.seen_us => val.parse::<i64>().ok()?,
+            _ => return None,
+        };
+        // negative (and N/A, rejected above) values show up before the first frame is written
+        Some(((us.max(0) as f64 / 1000.0 / self.total_ms) * 100.0).clamp(0.0, 100.0) as u32)
+    }
+}
+
+/// Every item in a reproducible order: `trackItems` as given, or `trackItemsMap` by key (HashMap
+/// iteration order changes between runs). Items with an explicit `order` come first, sorted by it.
+pub fn ordered_items(design: &Design) -> Vec<&TrackItem> {
+    let mut items: Vec<&TrackItem> = if !design.trackItems.is_empty() {
+        design.trackItems.iter().collect()
+    } else {
+        let mut entries: Vec<(&String, &TrackItem)> = design.trackItemsMap.iter().collect();
+        entries.sort_by_key(|(k, _)| *k);
+        entries.into_iter().map(|(_, it)| it).collect()
+    };
+    // stable sort, so ties and unordered items keep the order above
+    items.sort_by_key(|it| (it.order.is_none(), it.order));
+    items
+}
+
+/// `ordered_items`, mutably.
+fn ordered_items_mut(design: &mut Design) -> Vec<&mut TrackItem> {
+    let mut items: Vec<&mut TrackItem> = if !design.trackItems.is_empty() {
+        design.trackItems.iter_mut().collect()
+    } else {
+        let mut entries: Vec<(&String, &mut TrackItem)> = design.trackItemsMap.iter_mut().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries.into_iter().map(|(_, it)| it).collect()
+    };
+    items.sort_by_key(|it| (it.order.is_none(), it.order));
+    items
+}
+
+/// Stream facts for an item's downloaded source: from ffprobe, or, for a frame sequence, from its
+/// frame count and rate.
+pub async fn probe_asset(design: &Design, item: &TrackItem, path: &Path) -> Result<FfprobeInfo> {
+    match item.details.as_ref().and_then(|d| d.sequence.as_ref()) {
+        Some(seq) => {
+            let fps = sequence_fps(seq, design);
+            Ok(FfprobeInfo { fps: Some(fps), duration_ms: Some((seq.count as f64 * 1000.0 / fps).round() as u64), has_video: true, has_audio: false, ..Default::default() })
+        }
+        None => probe_media(path).await,
+    }
+}
+
+/// Run ffprobe on a downloaded asset for its streams, frame rate and duration. Errors carry ffprobe's
+/// own message when it can't read the file.
+pub async fn probe_media(path: &Path) -> Result<FfprobeInfo> {
+    let output = Command::new(ffprobe_bin())
+        .args(["-v", "error", "-show_entries", "stream=codec_type,codec_name,width,height,avg_frame_rate,color_transfer,channels:format=format_name,duration", "-of", "json"])
+        .arg(path)
+        .output()
+        .await?;
+    if !output.status.success() { return Err(anyhow!("ffprobe failed: {}", String::from_utf8_lossy(&output.stderr).trim())); }
+    let v: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let has = |kind: &str| v["streams"].as_array().into_iter().flatten().any(|s| s["codec_type"] == kind);
+    let video_stream = v["streams"].as_array().into_iter().flatten().find(|s| s["codec_type"] == "video");
+    let video_codec = video_stream.and_then(|s| s["codec_name"].as_str()).map(String::from);
+    let color_transfer = video_stream.and_then(|s| s["color_transfer"].as_str()).filter(|t| *t != "unknown").map(String::from);
+    // "30000/1001"; "0/0" for streams without a rate
+    let fps = video_stream
+        .and_then(|s| s["avg_frame_rate"].as_str())
+        .and_then(|r| r.split_once('/'))
+        .and_then(|(n, d)| Some(n.parse::<f64>().ok()? / d.parse::<f64>().ok()?))
+        .filter(|f| f.is_finite() && *f > 0.0);
+    let duration_ms = v["format"]["duration"].as_str().and_then(|d| d.parse::<f64>().ok()).filter(|d| d.is_finite() && *d > 0.0).map(|d| (d * 1000.0).round() as u64);
+    let format_name = v["format"]["format_name"].as_str().map(String::from);
+    let audio_stream = v["streams"].as_array().into_iter().flatten().find(|s| s["codec_type"] == "audio");
+    let audio_channels = audio_stream.and_then(|s| s["channels"].as_u64()).map(|c| c as u32);
+    let audio_codec = audio_stream.and_then(|s| s["codec_name"].as_str()).map(String::from);
+    let dim = |k: &str| video_stream.and_then(|s| s[k].as_u64()).map(|v| v as u32);
+    Ok(FfprobeInfo { fps, duration_ms, has_video: has("video"), has_audio: has("audio"), video_codec, color_transfer, format_name, audio_channels, audio_codec, width: dim("width"), height: dim("height") })
+}
+
+/// Why `probe` (or the probe failure) means the file can't be used for `item`, naming the item and its
+/// redacted source; None when it's fine.
+pub fn probe_error(item: &TrackItem, probe: &Result<FfprobeInfo>) -> Option<String> {
+    let name = item_label(item);
+    let (kind, ok) = match item.kind {
+        TrackType::Video => ("video", probe.as_ref().is_ok_and(|p| p.has_video)),
+        TrackType::Image => ("image", probe.as_ref().is_ok_and(|p| p.has_video)),
+        TrackType::Audio => ("audio", probe.as_ref().is_ok_and(|p| p.has_audio)),
+        TrackType::Text => return None,
+    };
+    if ok { return None; }
+    Some(match probe {
+        Err(e) => format!("asset {} is not a valid {}: {:#}", name, kind, e),
+        Ok(_) => format!("asset {} is not a valid {}: no {} stream", name, kind, if kind == "audio" { "audio" } else { "video" }),
+    })
+}
+
+/// An item for error messages: its id, else its redacted source.
+pub fn item_label(item: &TrackItem) -> String {
+    item.id.clone().or_else(|| item.details.as_ref()?.src.as_deref().map(redact_urls)).unwrap_or_default()
+}
+
+/// Attach probe results to the design's src items (`probes` is indexed like `asset_sources`) and snap
+/// video trim points to the source's frame boundaries.
+pub fn apply_probes(design: &mut Design, probes: &[Option<FfprobeInfo>]) {
+    let items = ordered_items_mut(design).into_iter().filter(|it| it.details.as_ref().is_some_and(|d| d.src.is_some()));
+    for (it, probe) in items.zip(probes) {
+        let Some(probe) = probe else { continue };
+        if let (TrackType::Video, Some(fps)) = (&it.kind, probe.fps) {
+            let snap = |ms: u64| ((ms as f64 * fps / 1000.0).round() * 1000.0 / fps).round() as u64;
+            it.trim.from = it.trim.from.map(snap);
+            it.trim.to = it.trim.to.map(snap);
+        }
+        it.probe = Some(probe.clone());
+    }
+}
+
+/// Timeline length when nothing in the design has a known end and options.defaultDurationMs is unset.
+const DEFAULT_DURATION_MS: u64 = 10_000;
+
+/// Timeline length: the latest item end (display.to, else the end of its trim window or probed
+/// source), or the default duration when no item has one. Items without a known end run to the
+/// end of the timeline; one starting at or past that end gets the default duration from its start.
+pub fn compute_duration_ms(design: &Design) -> u64 {
+    let default = design.defaultDurationMs.unwrap_or(DEFAULT_DURATION_MS);
+    let mut max_end = 0u64;
+    let mut open_start = None;
+    for it in ordered_items(design) {
+        let trim_end = trim_end_ms(it).unwrap_or(0);
+        if trim_end > max_end { max_end = trim_end; }
+        // Consider display window if provided
+        let disp_end = it.display.to.unwrap_or(0);
+        if disp_end > max_end { max_end = disp_end; }
+        if item_end_ms(it).is_none() { open_start = open_start.max(Some(item_start_ms(it))); }
+    }
+    let end = if max_end == 0 { default } else { max_end };
+    match open_start { Some(start) if start >= end => start + default, _ => end }
+}
+
+/// Demuxer for an animated GIF/APNG image item: by the file extension, or by ffprobe's codec for
+/// APNGs saved as .png. None for stills.
+fn animated_image(it: &TrackItem, path: &Path) -> Option<&'static str> {
+    let ext = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    let codec = it.probe.as_ref().and_then(|p| p.video_codec.as_deref());
+    match (ext.as_deref(), codec) {
+        (_, Some("gif")) | (Some("gif"), None) => Some("gif"),
+        (_, Some("apng")) | (Some("apng"), None) => Some("apng"),
+        _ => None,
+    }
+}
+
+/// Motion-compensated `minterpolate` up to the output fps for a video item, when options.interpolate
+/// is set and the probed source (at the item's speed) has fewer frames per second than the output.
+fn interpolate_filter(design: &Design, it: &TrackItem, fps: u32) -> Option<String> {
+    if design.interpolate != Some(true) || is_draft(design) || !matches!(it.kind, TrackType::Video) { return None; }
+    let source_fps = it.probe.as_ref()?.fps? * item_speed(it);
+    (fps as f64 > source_fps + 0.01).then(|| format!("minterpolate=fps={}:mi_mode=mci:mc_mode=aobmc:me_mode=bidir:vsbmc=1", fps))
+}
+
+/// Tone-mapping curves accepted in options.toneMap, besides "none".
+pub const TONE_MAP_CURVES: [&str; 4] = ["hable", "mobius", "reinhard", "clip"];
+
+/// zscale transfer name of an HDR video/image item, from details.colorSpace or else the probed
+/// color_transfer; None for SDR (or unknown) sources.
+fn hdr_transfer(it: &TrackItem) -> Option<&'static str> {
+    let transfer = match it.details.as_ref().and_then(|d| d.colorSpace.as_deref()) {
+        Some(cs) => cs,
+        None => it.probe.as_ref()?.color_transfer.as_deref()?,
+    };
+    match transfer { "bt2020-pq" | "smpte2084" => Some("smpte2084"), "bt2020-hlg" | "arib-std-b67" => Some("arib-std-b67"), _ => None }
+}
+
+/// Tone map an HDR item (BT.2020 PQ/HLG) into the SDR BT.709 output: linearize, map the highlights
+/// with the options.toneMap curve, then back to BT.709. The input side is spelled out because many
+/// files carry no color tags. Needs ffmpeg with zimg.
+fn tonemap_filter(design: &Design, it: &TrackItem) -> Option<String> {
+    let curve = design.toneMap.as_deref().unwrap_or("hable");
+    if curve == "none" || !matches!(it.kind, TrackType::Video | TrackType::Image) { return None; }
+    let tin = hdr_transfer(it)?;
+    Some(format!("zscale=tin={}:min=bt2020nc:pin=bt2020:t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap={}:desat=0,zscale=t=bt709:m=bt709:r=tv", tin, curve))
+}
+
+/// CSS blend mode names (details.blendMode) and their ffmpeg `blend` modes; "normal" is a plain overlay.
+pub const BLEND_MODES: [(&str, &str); 12] = [
+    ("multiply", "multiply"), ("screen", "screen"), ("overlay", "overlay"), ("add", "addition"), ("darken", "darken"), ("lighten", "lighten"),
+    ("color-dodge", "dodge"), ("color-burn", "burn"), ("hard-light", "hardlight"), ("soft-light", "softlight"), ("difference", "difference"), ("exclusion", "exclusion"),
+];
+
+/// ffmpeg blend mode for a video/image item; None for "normal" or unset.
+fn blend_mode(it: &TrackItem) -> Option<&'static str> {
+    let name = it.details.as_ref()?.blendMode.as_deref()?;
+    BLEND_MODES.iter().find(|(css, _)| css.eq_ignore_ascii_case(name)).map(|(_, ff)| *ff)
+}
+
+/// Channel layouts accepted in details.channelLayout.
+pub const CHANNEL_LAYOUTS: [&str; 10] = ["mono", "stereo", "2.1", "3.0", "quad", "4.0", "5.0", "5.1", "6.1", "7.1"];
+
+/// Filters (each followed by a comma) bringing an audio item to the layout it is mixed in:
+/// details.channelLayout relabels the source, then details.downmix folds it down. Sources with more
+/// than two channels, as labeled or probed, are folded to stereo unless downmix says otherwise.
+fn channel_filters(it: &TrackItem) -> String {
+    let d = it.details.as_ref();
+    let layout = d.and_then(|d| d.channelLayout.as_deref());
+    let mut out = layout.map(|l| format!("channelmap=channel_layout={},", l)).unwrap_or_default();
+    let surround = match layout {
+        Some(l) => !matches!(l, "mono" | "stereo"),
+        None => it.probe.as_ref().and_then(|p| p.audio_channels).is_some_and(|c| c > 2),
+    };
+    match d.and_then(|d| d.downmix.as_deref()) {
+        Some("mono") => out.push_str("aformat=channel_layouts=mono,"),
+        Some("left") => out.push_str("pan=stereo|c0=c0|c1=c0,"),
+        Some("right") => out.push_str("pan=stereo|c0=c1|c1=c1,"),
+        Some("stereo") => out.push_str("aformat=channel_layouts=stereo,"),
+        _ if surround => out.push_str("aformat=channel_layouts=stereo,"),
+        _ => {}
+    }
+    out
+}
+
+/// Playback rate of an item; 1 unless details.speed says otherwise.
+fn item_speed(it: &TrackItem) -> f64 { it.details.as_ref().and_then(|d| d.speed).filter(|s| *s > 0.0).unwrap_or(1.0) as f64 }
+
+/// Timeline end of a trim window played from the item's start: trim.to (or, without it, the probed
+/// duration of a video/audio source that doesn't loop), moved by the item's speed.
+fn trim_end_ms(it: &TrackItem) -> Option<u64> {
+    let probed = it.probe.as_ref().and_then(|p| p.duration_ms)
+        .filter(|_| matches!(it.kind, TrackType::Video | TrackType::Audio) && video_on_end(it) != Some("loop"));
+    let (from, to) = (it.trim.from.unwrap_or(0), it.trim.to.or(probed)?);
+    Some(item_start_ms(it) + (to.saturating_sub(from) as f64 / item_speed(it)).round() as u64)
+}
+
+/// Where the item stops showing: display.to, else the (speed-adjusted) end of its trim window.
+pub fn item_end_ms(it: &TrackItem) -> Option<u64> { it.display.to.or_else(|| trim_end_ms(it)) }
+
+/// What a video does once its source runs out inside the display window; `loop: true` is `loop`.
+fn video_on_end(it: &TrackItem) -> Option<&str> {
+    let d = it.details.as_ref()?;
+    if d.r#loop == Some(true) { return Some("loop"); }
+    d.onEnd.as_deref()
+}
+
+pub fn item_start_ms(it: &TrackItem) -> u64 { it.display.from.or(it.trim.from).unwrap_or(0) }
+
+/// Crossfade length into the item, capped so it doesn't start before the timeline does.
+fn transition_lead_ms(it: &TrackItem) -> u64 {
+    let d = it.details.as_ref().and_then(|d| d.transition.as_ref()).map(|t| t.durationMs).unwrap_or(0);
+    d.min(item_start_ms(it))
+}
+
+/// For an audio item, the `(start, length)` in timeline ms of the fade-out for the next audio
+/// item's crossfade: one that starts (with a transition) where this one ends.
+fn outgoing_crossfade_ms(design: &Design, item: &TrackItem) -> Option<(u64, u64)> {
+    let (a_start, a_end) = (item_start_ms(item), item_end_ms(item)?);
+    ordered_items(design).into_iter()
+        .filter(|b| matches!(b.kind, TrackType::Audio) && item_start_ms(b) > a_start)
+        .filter_map(|b| {
+            let (b_start, lead) = (item_start_ms(b), transition_lead_ms(b));
+            (lead > 0 && a_end >= b_start && a_end <= b_start + lead).then(|| (b_start - lead, lead))
+        })
+        .next()
+}
+
+/// atempo only takes 0.5-2, so larger changes are a chain of steps.
+fn atempo_chain(speed: f64) -> String {
+    let mut parts = Vec::new();
+    let mut rest = speed;
+    while rest > 2.0 { parts.push("atempo=2".to_string()); rest /= 2.0; }
+    while rest < 0.5 { parts.push("atempo=0.5".to_string()); rest /= 0.5; }
+    if (rest - 1.0).abs() > 1e-6 || parts.is_empty() { parts.push(format!("atempo={}", rest)); }
+    parts.join(",")
+}
+
+pub fn asset_sources(design: &Design) -> Vec<(TrackItem, String)> {
+    let items = ordered_items(design);
+    // a muted render never reads its audio items, so they aren't downloaded either
+    let mute = design.mute.unwrap_or(false);
+    items.into_iter().filter(|it| !(mute && matches!(it.kind, TrackType::Audio))).filter_map(|it| it.details.as_ref().and_then(|d| d.src.clone()).map(|src| (it.clone(), src))).collect()
+}
+
+/// `(item id, fontUrl, request headers)` for every text item that has an id and a fontUrl.
+pub fn font_sources(design: &Design) -> Vec<(String, String, HeaderMap)> {
+    let items = ordered_items(design);
+    items.into_iter()
+        .filter(|it| matches!(it.kind, TrackType::Text))
+        .filter_map(|it| Some((it.id.clone()?, it.details.as_ref()?.fontUrl.clone()?, asset_headers(design, it))))
+        .collect()
+}
+
+/// Request headers for an item's remote sources: the design-wide ones with the item's on top.
+/// Invalid names/values are dropped here; validation reports them.
+pub fn asset_headers(design: &Design, item: &TrackItem) -> HeaderMap {
+    header_map(design.headers.iter().chain(item.details.as_ref().map(|d| &d.headers).into_iter().flatten()))
+}
+
+/// The design's background when it is an image rather than a color: a URL, data: URI or path.
+pub fn background_image(design: &Design) -> Option<&str> {
+    let bg = design.background.as_deref()?.trim();
+    let lower = bg.to_ascii_lowercase();
+    let is_image = ["http://", "https://", "file://", "data:"].iter().any(|p| lower.starts_with(p)) || bg.starts_with('/') || bg.starts_with('.');
+    is_image.then_some(bg)
+}
+
+/// Whether `c` is a color `ff_color` understands (parsed, or a bare name left to ffmpeg).
+pub fn is_color(c: &str) -> bool { try_parse_rgba(c).is_some() || (!c.trim().is_empty() && c.trim().chars().all(|ch| ch.is_ascii_alphabetic())) }
+
+/// Request headers for design-level sources (the subtitle file, a background image).
+pub fn design_headers(design: &Design) -> HeaderMap { header_map(design.headers.iter()) }
+
+/// Valid name/value pairs as a HeaderMap with sensitive values.
+pub fn header_map<'a>(pairs: impl Iterator<Item = (&'a String, &'a String)>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (k, v) in pairs {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(v)) {
+            let mut value = value;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// Named output sizes for `options.resolution`.
+pub const RESOLUTION_PRESETS: [(&str, (u32, u32)); 14] = [
+    ("480p", (854, 480)), ("720p", (1280, 720)), ("1080p", (1920, 1080)), ("1440p", (2560, 1440)), ("4k", (3840, 2160)), ("2160p", (3840, 2160)),
+    ("vertical-720", (720, 1280)), ("vertical-1080", (1080, 1920)), ("vertical-4k", (2160, 3840)),
+    ("square-720", (720, 720)), ("square-1080", (1080, 1080)),
+    ("portrait-1080", (1080, 1350)), ("landscape-1080", (1920, 1080)), ("landscape-720", (1280, 720)),
+];
+
+/// Size for a resolution preset name (case-insensitive).
+pub fn resolution_preset(name: &str) -> Option<Size> {
+    let name = name.trim().to_ascii_lowercase();
+    RESOLUTION_PRESETS.iter().find(|(n, _)| *n == name).map(|(_, (width, height))| Size { width: *width, height: *height })
+}
+
+/// Output width and height; 1080x1920 when the design doesn't say. Odd sizes are rounded up to
+/// even, which yuv420p (and so libx264/libvpx) requires.
+pub fn output_size(design: &Design) -> (u32, u32) {
+    (even(design.size.as_ref().map(|s| s.width).unwrap_or(1080)), even(design.size.as_ref().map(|s| s.height).unwrap_or(1920)))
+}
+
+/// Frame rate cap for drafts.
+const DRAFT_MAX_FPS: u32 = 15;
+
+fn is_draft(design: &Design) -> bool { design.draft == Some(true) }
+
+/// Size of the encoded frames: the rendition's size or the output size, halved (still even) for
+/// drafts. Both compose at the full size so pixel positions stay put and only scale down before encoding.
+pub fn encoded_size(design: &Design) -> (u32, u32) {
+    let (w, h) = design.encodeSize.as_ref().map(|s| (even(s.width), even(s.height))).unwrap_or_else(|| output_size(design));
+    if is_draft(design) { (even(w / 2), even(h / 2)) } else { (w, h) }
+}
+
+/// Quality for drafts, replacing the user's: low constant quality and the fastest preset.
+fn draft_quality(encoder: VideoEncoder) -> Quality {
+    let preset = match encoder { VideoEncoder::X264 => Some("ultrafast"), VideoEncoder::Nvenc => Some("p1"), _ => None };
+    let crf = matches!(encoder, VideoEncoder::X264 | VideoEncoder::Nvenc).then_some(30);
+    Quality { crf, preset: preset.map(String::from), ..Default::default() }
+}
+
+/// `v` rounded up to the next even number.
+fn even(v: u32) -> u32 { v.saturating_add(1) & !1 }
+
+/// Timeline range `[start, end)` in ms that the output covers, from the design's startMs/endMs.
+pub fn render_range_ms(design: &Design) -> (u64, u64) {
+    let timeline = compute_duration_ms(design);
+    let start = design.startMs.unwrap_or(0).min(timeline.saturating_sub(1));
+    let end = design.endMs.filter(|e| *e > start).unwrap_or(timeline);
+    (start, end)
+}
+
+/// Length of the rendered output in ms.
+pub fn output_duration_ms(design: &Design) -> u64 { let (start, end) = render_range_ms(design); end - start }
+
+/// CSS length in pixels: "100px", a bare "100", or "50%" of `extent` (the output width or height).
+/// None for anything else.
+pub fn resolve_length(s: &str, extent: u32) -> Option<f32> {
+    let s = s.trim();
+    let v = if let Some(p) = s.strip_suffix('%') { p.trim().parse::<f32>().ok()? * extent as f32 / 100.0 } else { s.strip_suffix("px").unwrap_or(s).trim().parse::<f32>().ok()? };
+    v.is_finite().then_some(v)
+}
+
+/// Position in pixels against `extent`; missing or unknown units are 0 (with a warning for the latter).
+fn parse_px(s: &Option<String>, extent: u32) -> i32 {
+    let Some(s) = s else { return 0 };
+    resolve_length(s, extent).map(|f| f.round() as i32).unwrap_or_else(|| { tracing::warn!("unsupported length {:?}, using 0", s); 0 })
+}
+
+/// Item width/height in pixels against `extent`; None when unset or not understood.
+pub fn length_px(l: &Length, extent: u32) -> Option<f32> {
+    match l { Length::Px(v) => Some(*v), Length::Css(s) => resolve_length(s, extent) }
+}
+fn parse_scale(s: &Option<String>) -> f32 {
+    if let Some(t) = s { if let Some(start) = t.find("scale(") { if let Some(end) = t[start+6..].find(')') { return t[start+6..start+6+end].parse::<f32>().unwrap_or(1.0); } } }
+    1.0
+}
+/// drawtext x expression for each line. With a `maxWidth` box the line is aligned inside
+/// [left, left+maxWidth]; otherwise `left` is the anchor (center point or right edge), and
+/// without `left` the line aligns to the canvas.
+fn text_x_expr(align: Option<&str>, left: i32, has_left: bool, box_width: Option<u32>) -> String {
+    match (align.map(|a| a.trim().to_ascii_lowercase()).as_deref(), box_width) {
+        (Some("center"), Some(bw)) => format!("{}+({}-text_w)/2", left, bw),
+        (Some("center"), None) if has_left => format!("{}-text_w/2", left),
+        (Some("center"), None) => "(w-text_w)/2".into(),
+        (Some("right"), Some(bw)) => format!("{}+{}-text_w", left, bw),
+        (Some("right"), None) if has_left => format!("{}-text_w", left),
+        (Some("right"), None) => "w-text_w".into(),
+        _ => left.to_string(),
+    }
+}
+
+/// geq that clears alpha outside a rounded rectangle. Radius is "Npx"/"N" or "N%" of the shorter
+/// side, capped at half of it; None for a missing or zero radius.
+fn rounded_corners_filter(radius: &Option<String>) -> Option<String> {
+    let v = radius.as_deref()?.trim();
+    let r = if let Some(p) = v.strip_suffix('%') {
+        let pct = p.trim().parse::<f32>().ok().filter(|p| *p > 0.0)?;
+        format!("min(W,H)*{}", pct / 100.0)
+    } else {
+        v.trim_end_matches("px").trim().parse::<f32>().ok().filter(|p| *p > 0.0)?.to_string()
+    };
+    let r = format!("min({},min(W,H)/2)", r);
+    // distance from the center, minus the straight part of the edge; only corners get the circle test
+    let (dx, dy) = (format!("abs(X-W/2)-(W/2-{})", r), format!("abs(Y-H/2)-(H/2-{})", r));
+    Some(format!(
+        "geq=r='r(X,Y)':g='g(X,Y)':b='b(X,Y)':a='if(gt({dx},0)*gt({dy},0)*gt(hypot({dx},{dy}),{r}),0,alpha(X,Y))'",
+        dx = dx, dy = dy, r = r
+    ))
+}
+
+/// Image items drawn from a fill instead of a src.
+pub fn is_fill(it: &TrackItem) -> bool {
+    matches!(it.kind, TrackType::Image) && it.details.as_ref().is_some_and(|d| d.fill.is_some() && d.src.is_none())
+}
+
+/// The src and fill items in item order, numbered as they are composited: the i-th src item is
+/// `asset_sources` entry i, and fills follow in their own order.
+fn layer_items(design: &Design) -> Vec<&TrackItem> {
+    let mute = design.mute.unwrap_or(false);
+    ordered_items(design).into_iter()
+        .filter(|it| is_fill(it) || (it.details.as_ref().is_some_and(|d| d.src.is_some()) && !(mute && matches!(it.kind, TrackType::Audio))))
+        .collect()
+}
+
+/// Sort `(position, ffmpeg input, item)` layers bottom-to-top: explicit zIndex wins, otherwise the
+/// position in `layer_items`. Ties keep that order. An item that comes in with a transition fades in
+/// over the clip it replaces, so it goes just above that clip when it would otherwise be under it.
+fn sort_layers(layers: &mut [(usize, usize, &TrackItem)]) {
+    let mut keys: Vec<(i32, usize, u32)> = layers.iter().map(|(p, _, it)| (it.details.as_ref().and_then(|d| d.zIndex).unwrap_or(*p as i32), *p, 0)).collect();
+    // in start order, so each clip of a chain of transitions is lifted above the already-lifted one before it
+    let drawn = |i: usize| matches!(layers[i].2.kind, TrackType::Video | TrackType::Image);
+    let mut incoming: Vec<usize> = (0..layers.len()).filter(|&i| drawn(i) && transition_lead_ms(layers[i].2) > 0).collect();
+    incoming.sort_by_key(|&i| item_start_ms(layers[i].2));
+    for b in incoming {
+        let (b_start, lead) = (item_start_ms(layers[b].2), transition_lead_ms(layers[b].2));
+        let outgoing = (0..layers.len())
+            .filter(|&a| a != b && drawn(a) && item_start_ms(layers[a].2) < b_start)
+            .filter(|&a| item_end_ms(layers[a].2).is_some_and(|end| end > b_start - lead && end <= b_start + lead))
+            .map(|a| keys[a])
+            .max();
+        if let Some(top) = outgoing.filter(|top| *top >= keys[b]) { keys[b] = (top.0, top.1, top.2 + 1); }
+    }
+    let mut order: Vec<usize> = (0..layers.len()).collect();
+    order.sort_by_key(|&i| keys[i]);
+    let sorted: Vec<_> = order.into_iter().map(|i| layers[i]).collect();
+    layers.copy_from_slice(&sorted);
+}
+
+/// How `build_ffmpeg_command` schedules the design: each item's resolved window, its place in the
+/// compositing order and its ffmpeg input. Text is drawn above every video/image layer, in item order.
+pub fn resolve_timeline(design: &Design) -> Timeline {
+    let timeline_ms = compute_duration_ms(design);
+    let (range_start_ms, range_end_ms) = render_range_ms(design);
+    let video = output_format(design).map(|f| f.has_video()).unwrap_or(true);
+    let input_base = if video { 1 } else { 0 };
+    let items = layer_items(design);
+    let assets_len = items.iter().filter(|it| !is_fill(it)).count();
+    let (mut a, mut f) = (0, 0);
+    let mut layered: Vec<(usize, usize, &TrackItem)> = Vec::new();
+    for (pos, it) in items.into_iter().enumerate() {
+        if !is_fill(it) { layered.push((pos, input_base + a, it)); a += 1; }
+        else if video { layered.push((pos, input_base + assets_len + f, it)); f += 1; }
+    }
+    sort_layers(&mut layered);
+    // layers by item identity; only video/image layers are drawn
+    let drawn: Vec<(usize, &TrackItem)> = layered.iter().filter(|(_, _, it)| video && matches!(it.kind, TrackType::Video | TrackType::Image)).map(|(_, i, it)| (*i, *it)).collect();
+    let mut text_layer = drawn.len();
+    let items = ordered_items(design).into_iter().map(|it| {
+        let input = layered.iter().find(|(_, _, l)| std::ptr::eq(*l, it)).map(|(_, i, _)| *i);
+        let layer = match it.kind {
+            TrackType::Text if video && it.id.is_some() && it.details.as_ref().is_some_and(|d| d.fontUrl.is_some()) => { text_layer += 1; Some(text_layer - 1) }
+            _ => drawn.iter().position(|(_, l)| std::ptr::eq(*l, it)),
+        };
+        let start_ms = item_start_ms(it);
+        TimelineItem {
+            id: it.id.clone(),
+            kind: it.kind.clone(),
+            start_ms,
+            end_ms: item_end_ms(it).unwrap_or(timeline_ms),
+            shown_from_ms: start_ms - transition_lead_ms(it),
+            layer,
+            input,
+        }
+    }).collect();
+    Timeline { duration_ms: timeline_ms, range_start_ms, range_end_ms, items }
+}
+
+/// The item's box in output pixels: details.width/height, else the output size.
+fn item_box(it: &TrackItem, out_w: u32, out_h: u32) -> (u32, u32) {
+    let d = it.details.as_ref();
+    (
+        d.and_then(|d| d.width.as_ref()).and_then(|l| length_px(l, out_w)).map(|v| v.round().max(1.0) as u32).unwrap_or(out_w),
+        d.and_then(|d| d.height.as_ref()).and_then(|l| length_px(l, out_h)).map(|v| v.round().max(1.0) as u32).unwrap_or(out_h),
+    )
+}
+
+/// lavfi source for a fill: `color` for a solid fill, a static `gradients` for a linear gradient whose
+/// line runs through the center at the CSS angle and is long enough to reach the corners.
+fn fill_source(fill: &Fill, w: u32, h: u32, fps: u32, duration_s: f64) -> String {
+    match fill {
+        Fill::Color(c) => format!("color=c={}:s={}x{}:r={}:d={:.3}", ff_color(c, 1.0), w, h, fps, duration_s),
+        Fill::Gradient(g) => {
+            let rad = (g.angle.unwrap_or(180.0) as f64).to_radians();
+            let (dx, dy) = (rad.sin(), -rad.cos());
+            let half = ((w as f64 * dx).abs() + (h as f64 * dy).abs()) / 2.0;
+            let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+            // + 0.0 turns a rounded -0 into 0
+            let px = |v: f64| v.round() + 0.0;
+            let (x0, y0, x1, y1) = (px(cx - dx * half), px(cy - dy * half), px(cx + dx * half), px(cy + dy * half));
+            let colors: String = g.stops.iter().take(8).enumerate().map(|(i, c)| format!(":c{}={}", i, ff_color(c, 1.0))).collect();
+            format!("gradients=s={}x{}:r={}:d={:.3}:speed=0:n={}:x0={}:y0={}:x1={}:y1={}{}", w, h, fps, duration_s, g.stops.len().min(8), x0, y0, x1, y1, colors)
+        }
+    }
+}
+
+/// Filters fitting the source into `w`x`h` without distortion: letterboxed with transparent bars
+/// ("contain") or cropped to fill ("cover"). None for "stretch"/"fill", which the plain scale does.
+fn fit_filter(fit: Option<&str>, w: u32, h: u32) -> Option<String> {
+    let (w, h) = (w.max(1), h.max(1));
+    match fit? {
+        "contain" => Some(format!("scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2:color=black@0")),
+        "cover" => Some(format!("scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h}")),
+        _ => None,
+    }
+}
+
+/// zoompan moving linearly from the start to the end zoom/pan over `frames` output frames of
+/// `w`x`h`. The source is a single image frame; zoompan emits all `frames` from it.
+fn ken_burns_filter(kb: &KenBurns, w: u32, h: u32, fps: u32, frames: u64) -> String {
+    let last = frames.saturating_sub(1).max(1);
+    let lerp = |a: f32, b: f32| format!("({}+({})*min(on/{},1))", a, b - a, last);
+    let (z, x, y) = (
+        lerp(kb.startZoom.unwrap_or(1.0), kb.endZoom.unwrap_or(1.2)),
+        lerp(kb.startX.unwrap_or(0.5), kb.endX.unwrap_or(0.5)),
+        lerp(kb.startY.unwrap_or(0.5), kb.endY.unwrap_or(0.5)),
+    );
+    format!("zoompan=z='{}':x='(iw-iw/zoom)*{}':y='(ih-ih/zoom)*{}':d={}:s={}x{}:fps={}", z, x, y, frames, w.max(1), h.max(1), fps)
+}
+
+/// Escape a value for a filter option inside a filtergraph: once for the option parser (`\ ' :` and
+/// whitespace, which it would otherwise trim from the ends), then again for the graph parser
+/// (`\ ' [ ] , ;` and whitespace, which it trims as well). Windows paths like `C:\jobs\x.srt` need both.
+fn escape_filter_value(v: &str) -> String {
+    const WHITESPACE: [char; 4] = [' ', '\t', '\n', '\r'];
+    let escape = |s: &str, special: &[char]| s.chars().fold(String::new(), |mut out, c| { if special.contains(&c) || WHITESPACE.contains(&c) { out.push('\\'); } out.push(c); out });
+    escape(&escape(v, &['\\', '\'', ':']), &['\\', '\'', '[', ']', ',', ';'])
+}
+
+/// Lines longer than this are passed to drawtext through a file rather than inline.
+const TEXTFILE_MIN_CHARS: usize = 200;
+
+/// Watermark anchors as `(horizontal, vertical)` parts: -1 start, 0 center, 1 end.
+pub const WATERMARK_POSITIONS: [(&str, (i8, i8)); 9] = [
+    ("top-left", (-1, -1)), ("top", (0, -1)), ("top-right", (1, -1)),
+    ("left", (-1, 0)), ("center", (0, 0)), ("right", (1, 0)),
+    ("bottom-left", (-1, 1)), ("bottom", (0, 1)), ("bottom-right", (1, 1)),
+];
+
+/// overlay `x`/`y` expressions for the watermark: explicit pixels, or the anchor resolved against the
+/// output (`W`/`H`) and watermark (`w`/`h`) sizes.
+fn watermark_position(wm: &Watermark) -> (String, String) {
+    let (ax, ay) = WATERMARK_POSITIONS.iter().find(|(n, _)| Some(*n) == wm.position.as_deref()).map(|(_, a)| *a).unwrap_or((1, 1));
+    let m = wm.margin.unwrap_or(24);
+    let axis = |anchor: i8, outer: &str, inner: &str| match anchor {
+        -1 => m.to_string(),
+        0 => format!("({}-{})/2", outer, inner),
+        _ => format!("{}-{}-{}", outer, inner, m),
+    };
+    (wm.x.map(|x| x.to_string()).unwrap_or_else(|| axis(ax, "W", "w")), wm.y.map(|y| y.to_string()).unwrap_or_else(|| axis(ay, "H", "h")))
+}
+
+/// `subtitles` filter for the burned-in captions, with the optional style overrides.
+fn subtitles_filter(path: &Path, sub: &Subtitles) -> String {
+    let mut style = Vec::new();
+    if let Some(fs) = sub.fontSize { style.push(format!("FontSize={}", fs)); }
+    if let Some(c) = &sub.color { style.push(format!("PrimaryColour={}", ass_color(c, 1.0))); }
+    let mut f = format!("subtitles=filename={}", escape_filter_value(&path.to_string_lossy()));
+    if !style.is_empty() { f.push_str(&format!(":force_style={}", escape_filter_value(&style.join(",")))); }
+    f
+}
+
+/// ASS colors are &HAABBGGRR with inverted alpha (00 = opaque).
+fn ass_color(c: &str, opacity: f32) -> String {
+    let (r, g, b, a) = parse_rgba(c);
+    format!("&H{:02X}{:02X}{:02X}{:02X}", 255 - ((a * opacity).clamp(0.0, 1.0) * 255.0).round() as u8, b, g, r)
+}
+
+/// Whether a text item goes through libass instead of drawtext: details.shaping, else whether the
+/// text has characters drawtext can't lay out (RTL scripts, Indic and other scripts that need shaping).
+fn wants_shaping(d: Option<&Details>, text: &str) -> bool {
+    d.and_then(|d| d.shaping).unwrap_or_else(|| text.chars().any(|c| matches!(c as u32,
+        0x0590..=0x08FF     // Hebrew, Arabic, Syriac, Thaana, N'Ko, ...
+        | 0x0900..=0x0DFF   // Devanagari through Sinhala
+        | 0x0E00..=0x0FFF   // Thai, Lao, Tibetan
+        | 0x1000..=0x109F   // Myanmar
+        | 0x1780..=0x17FF   // Khmer
+        | 0xFB1D..=0xFDFF   // Hebrew/Arabic presentation forms
+        | 0xFE70..=0xFEFF)))
+}
+
+/// ASS `\an` alignment and x for a line, matching `text_x_expr`'s placement.
+fn ass_anchor(align: Option<&str>, left: i32, has_left: bool, box_width: Option<u32>, out_w: u32) -> (u8, i32) {
+    match (align.map(|a| a.trim().to_ascii_lowercase()).as_deref(), box_width) {
+        (Some("center"), Some(bw)) => (8, left + bw as i32 / 2),
+        (Some("center"), None) => (8, if has_left { left } else { out_w as i32 / 2 }),
+        (Some("right"), Some(bw)) => (9, left + bw as i32),
+        (Some("right"), None) => (9, if has_left { left } else { out_w as i32 }),
+        _ => (7, left),
+    }
+}
+
+/// One-style ASS script drawing `lines` of a text item, a Dialogue per line at `y + i * line_px`
+/// like the drawtext path. `window` is the output-time display window in seconds.
+fn ass_text_script(d: Option<&Details>, lines: &[String], font: &str, (an, x, y): (u8, i32, i32), line_px: f32, play_res: (u32, u32), window: (f64, f64)) -> String {
+    let alpha = opacity_alpha(d.and_then(|d| d.opacity));
+    let color = ass_color(d.and_then(|d| d.color.as_deref()).unwrap_or("white"), alpha);
+    // a box replaces the outline (BorderStyle 3 draws OutlineColour as an opaque box)
+    let (border_style, outline_color, outline) = match d.and_then(|d| d.backgroundColor.as_deref()) {
+        Some(bg) => (3, ass_color(bg, alpha), d.and_then(|d| d.boxPadding).unwrap_or(0)),
+        None => {
+            let widest = d.and_then(|d| d.strokes.iter().max_by_key(|s| s.width)).map(|s| (s.width, s.color.as_str()));
+            let border = d.and_then(|d| d.borderWidth.filter(|w| *w > 0).map(|w| (w, d.borderColor.as_deref().unwrap_or("black"))));
+            let (w, c) = widest.into_iter().chain(border).max_by_key(|(w, _)| *w).unwrap_or((0, "black"));
+            (1, ass_color(c, alpha), w)
+        }
+    };
+    let shadow = d.and_then(|d| Some((d.shadowColor.as_deref()?, d.shadowX.unwrap_or(0), d.shadowY.unwrap_or(0))));
+    let back = shadow.map(|(c, _, _)| ass_color(c, alpha)).unwrap_or_else(|| "&H80000000".into());
+    let shadow_tags = shadow.map(|(_, dx, dy)| format!("\\xshad{}\\yshad{}", dx, dy)).unwrap_or_default();
+    let time = |s: f64| { let cs = (s.max(0.0) * 100.0).round() as u64; format!("{}:{:02}:{:02}.{:02}", cs / 360000, cs / 6000 % 60, cs / 100 % 60, cs % 100) };
+    let fontsize = d.and_then(|d| d.fontSize).unwrap_or(48);
+    let mut script = format!(
+        "[Script Info]\nScriptType: v4.00+\nPlayResX: {}\nPlayResY: {}\nWrapStyle: 2\nScaledBorderAndShadow: yes\n\n\
+         [V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Default,{},{},{},{},{},{},0,0,0,0,100,100,0,0,{},{},0,7,0,0,0,1\n\n\
+         [Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        play_res.0, play_res.1, font.replace(',', " "), fontsize, color, color, outline_color, back, border_style, outline,
+    );
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() { continue; }
+        // braces open override blocks and a backslash could form \N or \h; a word joiner keeps it literal
+        let text = line.replace('\\', "\\\u{2060}").replace('{', "\\{").replace('}', "\\}");
+        let line_y = y + (i as f32 * line_px).round() as i32;
+        script.push_str(&format!("Dialogue: 0,{},{},Default,,0,0,0,,{{\\an{}\\pos({},{}){}}}{}\n", time(window.0), time(window.1), an, x, line_y, shadow_tags, text));
+    }
+    script
+}
+
+/// Family name (name ID 1) of a TrueType/OpenType font file, which libass needs to pick the font out of
+/// fontsdir. None for other formats (woff, collections) or unreadable files.
+fn font_family(path: &Path) -> Option<String> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut f = std::fs::File::open(path).ok()?;
+    let mut read_at = |pos: u64, len: usize| -> Option<Vec<u8>> { let mut buf = vec![0; len]; f.seek(SeekFrom::Start(pos)).ok()?; f.read_exact(&mut buf).ok()?; Some(buf) };
+    let u16_at = |b: &[u8], i: usize| -> Option<u16> { Some(u16::from_be_bytes([*b.get(i)?, *b.get(i + 1)?])) };
+    let head = read_at(0, 12)?;
+    if !matches!(&head[..4], [0, 1, 0, 0] | b"OTTO" | b"true") { return None; }
+    let tables = read_at(12, u16_at(&head, 4)? as usize * 16)?;
+    let rec = tables.chunks(16).find(|r| &r[..4] == b"name")?;
+    let (offset, len) = (u32::from_be_bytes(rec[8..12].try_into().ok()?), u32::from_be_bytes(rec[12..16].try_into().ok()?));
+    let name = read_at(offset as u64, len.min(1 << 20) as usize)?;
+    let (count, strings) = (u16_at(&name, 2)? as usize, u16_at(&name, 4)? as usize);
+    let mut best: Option<(bool, String)> = None;
+    for i in 0..count {
+        let r = 6 + i * 12;
+        let (platform, name_id, len, off) = (u16_at(&name, r)?, u16_at(&name, r + 6)?, u16_at(&name, r + 8)? as usize, u16_at(&name, r + 10)? as usize);
+        if name_id != 1 { continue; }
+        let Some(raw) = name.get(strings + off..strings + off + len) else { continue };
+        // Windows names are UTF-16BE and preferred; Mac Roman is close enough to Latin-1 for family names
+        let windows = platform == 3 || platform == 0;
+        let s = if windows { String::from_utf16_lossy(&raw.chunks(2).filter_map(|c| u16_at(c, 0)).collect::<Vec<_>>()) } else { raw.iter().map(|b| *b as char).collect() };
+        if !s.trim().is_empty() && best.as_ref().is_none_or(|(w, _)| !w && windows) { best = Some((windows, s)); }
+    }
+    best.map(|(_, s)| s)
+}
+
+/// CSS line-height to pixels: "normal", unitless multiplier, "Npx" or "N%".
+fn parse_line_height(s: &Option<String>, font_size: u32) -> f32 {
+    let fs = font_size as f32;
+    let v = s.as_deref().map(str::trim).unwrap_or("normal");
+    let px = if let Some(p) = v.strip_suffix("px") { p.trim().parse::<f32>().ok() }
+        else if let Some(p) = v.strip_suffix('%') { p.trim().parse::<f32>().ok().map(|n| n / 100.0 * fs) }
+        else { v.parse::<f32>().ok().map(|n| n * fs) };
+    px.filter(|p| *p > 0.0).unwrap_or(fs * 1.2)
+}
+
+/// Split on explicit newlines, then greedily word-wrap each paragraph to `max_width` px.
+/// Glyph widths are approximated as 0.55em, which is close enough for typical sans fonts.
+fn wrap_text(text: &str, font_size: u32, max_width: Option<u32>) -> Vec<String> {
+    let paragraphs = text.split('\n').map(|l| l.trim_end_matches('\r'));
+    let max_chars = max_width.map(|w| ((w as f32) / (font_size.max(1) as f32 * 0.55)).floor().max(1.0) as usize);
+    let Some(max_chars) = max_chars else { return paragraphs.map(str::to_string).collect() };
+    let mut lines = Vec::new();
+    for para in paragraphs {
+        let mut current = String::new();
+        for word in para.split_whitespace() {
+            let mut word = word.to_string();
+            // hard-break words that can't fit on a line by themselves
+            while word.chars().count() > max_chars {
+                if !current.is_empty() { lines.push(std::mem::take(&mut current)); }
+                let split_at = word.char_indices().nth(max_chars).map(|(i, _)| i).unwrap_or(word.len());
+                lines.push(word[..split_at].to_string());
+                word = word[split_at..].to_string();
+            }
+            if current.is_empty() { current = word; }
+            else if current.chars().count() + 1 + word.chars().count() <= max_chars { current.push(' '); current.push_str(&word); }
+            else { lines.push(std::mem::replace(&mut current, word)); }
+        }
+        lines.push(current);
+    }
+    lines
+}
+
+/// Piecewise-linear ffmpeg expression over `(time_s, value)` points in the time variable `var`.
+/// Holds the first value before the first point and the last value after the last one.
+fn lerp_expr(points: &[(f64, f64)], var: &str) -> Option<String> {
+    let mut pts = points.to_vec();
+    pts.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (first, last) = (pts.first()?, pts.last()?);
+    let mut expr = format!("{:.4}", last.1);
+    for seg in pts.windows(2).rev() {
+        let ((t0, v0), (t1, v1)) = (seg[0], seg[1]);
+        if t1 <= t0 { continue; }
+        let lerp = format!("{:.4}+({:.4})*({}-{:.3})/{:.3}", v0, v1 - v0, var, t0, t1 - t0);
+        expr = format!("if(lt({},{:.3}),{},{})", var, t1, lerp, expr);
+    }
+    Some(format!("if(lt({},{:.3}),{:.4},{})", var, first.0, first.1, expr))
+}
+
+/// ffmpeg color `0xRRGGBB@alpha` for a CSS color, with its own alpha multiplied by `opacity` (0..1).
+/// Names we don't know are passed through for ffmpeg to resolve; other garbage becomes black.
+fn ff_color(c: &str, opacity: f32) -> String {
+    let opacity = opacity.clamp(0.0, 1.0);
+    match try_parse_rgba(c) {
+        Some((r, g, b, a)) => format!("0x{:02x}{:02x}{:02x}@{}", r, g, b, a * opacity),
+        None if !c.trim().is_empty() && c.trim().chars().all(|ch| ch.is_ascii_alphabetic()) => format!("{}@{}", c.trim().to_ascii_lowercase(), opacity),
+        None => format!("0x000000@{}", opacity),
+    }
+}
+
+/// "#rgb", "#rrggbb", "#rrggbbaa", "rgb(r,g,b)", "rgba(r,g,b,a)" or a common CSS name to rgb + alpha 0..1.
+/// Anything else falls back to opaque black.
+fn parse_rgba(c: &str) -> (u8, u8, u8, f32) { try_parse_rgba(c).unwrap_or((0, 0, 0, 1.0)) }
+
+fn try_parse_rgba(c: &str) -> Option<(u8, u8, u8, f32)> {
+    let c = c.trim().to_ascii_lowercase();
+    let (r, g, b, a) = if let Some(h) = c.strip_prefix('#') { parse_hex_rgba(h)? }
+        else if let Some(inner) = c.strip_prefix("rgba(").or_else(|| c.strip_prefix("rgb(")).and_then(|s| s.strip_suffix(')')) { parse_rgb_fn(inner)? }
+        else { named_color(&c)? };
+    Some((r, g, b, a.clamp(0.0, 1.0)))
+}
+
+fn named_color(name: &str) -> Option<(u8, u8, u8, f32)> {
+    let rgb = match name {
+        "transparent" => return Some((0, 0, 0, 0.0)),
+        "black" => (0, 0, 0), "white" => (255, 255, 255), "red" => (255, 0, 0), "lime" => (0, 255, 0),
+        "green" => (0, 128, 0), "blue" => (0, 0, 255), "yellow" => (255, 255, 0), "cyan" | "aqua" => (0, 255, 255),
+        "magenta" | "fuchsia" => (255, 0, 255), "gray" | "grey" => (128, 128, 128), "silver" => (192, 192, 192),
+        "maroon" => (128, 0, 0), "olive" => (128, 128, 0), "navy" => (0, 0, 128), "purple" => (128, 0, 128),
+        "teal" => (0, 128, 128), "orange" => (255, 165, 0), "pink" => (255, 192, 203),
+        _ => return None,
+    };
+    Some((rgb.0, rgb.1, rgb.2, 1.0))
+}
+
+fn parse_hex_rgba(h: &str) -> Option<(u8, u8, u8, f32)> {
+    if !h.is_ascii() { return None; }
+    let byte = |i: usize| u8::from_str_radix(h.get(i..i + 2)?, 16).ok();
+    let nibble = |i: usize| u8::from_str_radix(h.get(i..i + 1)?, 16).ok().map(|v| v * 17);
+    match h.len() {
+        3 => Some((nibble(0)?, nibble(1)?, nibble(2)?, 1.0)),
+        6 => Some((byte(0)?, byte(2)?, byte(4)?, 1.0)),
+        8 => Some((byte(0)?, byte(2)?, byte(4)?, byte(6)? as f32 / 255.0)),
+        _ => None,
+    }
+}
+
+fn parse_rgb_fn(inner: &str) -> Option<(u8, u8, u8, f32)> {
+    let mut parts = inner.split(',').map(str::trim);
+    let (r, g, b) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+    let a = match parts.next() { Some(a) => a.parse().ok()?, None => 1.0 };
+    Some((r, g, b, a))
+}
+
+fn opacity_alpha(o: Option<f32>) -> f32 { let v = o.unwrap_or(100.0) / 100.0; v.clamp(0.0, 1.0) }
+fn brightness_offset(b: Option<f32>) -> f32 { (b.unwrap_or(100.0) - 100.0) / 100.0 }
+
+/// One `eq` filter for the color grade, with only the parameters that differ from neutral
+/// (clamped to eq's ranges); None when everything is neutral.
+fn eq_filter(d: &Details) -> Option<String> {
+    let b = brightness_offset(d.brightness).clamp(-1.0, 1.0);
+    let c = (d.contrast.unwrap_or(100.0) / 100.0).clamp(-1000.0, 1000.0);
+    let s = (d.saturation.unwrap_or(100.0) / 100.0).clamp(0.0, 3.0);
+    let g = d.gamma.unwrap_or(1.0).clamp(0.1, 10.0);
+    let parts: Vec<String> = [("brightness", b, 0.0), ("contrast", c, 1.0), ("saturation", s, 1.0), ("gamma", g, 1.0)]
+        .iter().filter(|(_, v, neutral)| (v - neutral).abs() > 0.001).map(|(k, v, _)| format!("{}={}", k, v)).collect();
+    (!parts.is_empty()).then(|| format!("eq={}", parts.join(":")))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat { Mp4, Webm, Mov, Gif, Mp3, M4a }
+
+impl OutputFormat {
+    pub fn from_name(name: Option<&str>) -> Result<Self> {
+        match name.map(|n| n.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("mp4") => Ok(OutputFormat::Mp4),
+            Some("webm") => Ok(OutputFormat::Webm),
+            Some("mov") => Ok(OutputFormat::Mov),
+            Some("gif") => Ok(OutputFormat::Gif),
+            Some("mp3") => Ok(OutputFormat::Mp3),
+            Some("m4a") => Ok(OutputFormat::M4a),
+            Some(other) => Err(anyhow!("unsupported output format '{}'", other)),
+        }
+    }
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension().and_then(|e| e.to_str()).and_then(|e| Self::from_name(Some(e)).ok())
+    }
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Mp4 => "mp4", OutputFormat::Webm => "webm", OutputFormat::Mov => "mov", OutputFormat::Gif => "gif",
+            OutputFormat::Mp3 => "mp3", OutputFormat::M4a => "m4a",
+        }
+    }
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Mp4 => "video/mp4", OutputFormat::Webm => "video/webm", OutputFormat::Mov => "video/quicktime", OutputFormat::Gif => "image/gif",
+            OutputFormat::Mp3 => "audio/mpeg", OutputFormat::M4a => "audio/mp4",
+        }
+    }
+    fn has_audio(self) -> bool { self != OutputFormat::Gif }
+    pub fn has_video(self) -> bool { !matches!(self, OutputFormat::Mp3 | OutputFormat::M4a) }
+    /// Containers with an alpha-capable encoder: ProRes 4444 in mov, VP9 in webm.
+    pub fn supports_alpha(self) -> bool { matches!(self, OutputFormat::Mov | OutputFormat::Webm) }
+    fn audio_codec_args(self) -> Vec<String> {
+        match self {
+            OutputFormat::Webm => vec!["-c:a".into(), "libopus".into(), "-b:a".into(), "128k".into()],
+            OutputFormat::Mp3 => vec!["-c:a".into(), "libmp3lame".into(), "-b:a".into(), "192k".into()],
+            _ => vec!["-c:a".into(), "aac".into(), "-b:a".into(), "192k".into()],
+        }
+    }
+}
+
+/// The design's output format. Without an explicit format, a design made only of audio items
+/// renders to m4a instead of a video with a black picture.
+pub fn output_format(design: &Design) -> Result<OutputFormat> {
+    if design.format.as_deref().is_none_or(|f| f.trim().is_empty()) {
+        let items = ordered_items(design);
+        if !items.is_empty() && items.iter().all(|it| matches!(it.kind, TrackType::Audio)) { return Ok(OutputFormat::M4a); }
+    }
+    OutputFormat::from_name(design.format.as_deref())
+}
+
+/// File name for the rendered output: `output.<ext>` by default, otherwise the last path component
+/// of `name` with control characters and quotes removed. The extension is appended when missing;
+/// a different output format's extension is an error.
+pub fn output_file_name(name: Option<&str>, format: OutputFormat) -> Result<String> {
+    let ext = format.extension();
+    let Some(name) = name else { return Ok(format!("output.{}", ext)) };
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let clean: String = base.chars().filter(|c| !c.is_control() && !matches!(c, '"' | ':' | '*' | '?' | '<' | '>' | '|')).collect();
+    let clean = clean.trim().trim_start_matches('.').trim();
+    if clean.is_empty() { return Err(anyhow!("outputName has no usable characters")); }
+    let clean: String = clean.chars().take(200).collect();
+    match Path::new(&clean).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase) {
+        Some(e) if e == ext => Ok(clean),
+        Some(e) if OutputFormat::from_name(Some(&e)).is_ok() => Err(anyhow!("outputName extension .{} doesn't match format {}", e, ext)),
+        _ => Ok(format!("{}.{}", clean, ext)),
+    }
+}
+
+/// Most renditions a job may ask for.
+pub const MAX_RENDITIONS: usize = 8;
 
-    // rename to include hash prefix for stability
-    let hash = hex::encode(hasher.finalize());
-    let new_name = format!("{}-{}", &hash[..16], out_path.file_name().unwrap().to_string_lossy());
-    let new_path = out_path.with_file_name(new_name);
-    tokio::fs::rename(&out_path, &new_path).await?;
-    Ok(new_path)
+/// Whether `name` can be a rendition name: it is used in the output URL and file name.
+pub fn valid_rendition_name(name: &str) -> bool {
+    (1..=32).contains(&name.len()) && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
 }
 
-pub fn compute_duration_ms(design: &Design) -> u64 {
-    let mut max_end = 0u64;
-    let items: Vec<&TrackItem> = if !design.trackItems.is_empty() {
-        design.trackItems.iter().collect()
-    } else {
-        design.trackItemsMap.values().collect()
+/// The design one rendition renders: the main output's settings with the rendition's overrides, saved
+/// as `<output name>-<rendition>.<ext>` next to the main output.
+pub fn rendition_design(design: &Design, r: &Rendition) -> Design {
+    let mut d = design.clone();
+    d.renditions = None;
+    d.output = None;
+    if r.format.is_some() { d.format = r.format.clone(); }
+    if r.videoBitrate.is_some() { d.quality.videoBitrate = r.videoBitrate.clone(); d.quality.crf = None; }
+    if r.audioBitrate.is_some() { d.quality.audioBitrate = r.audioBitrate.clone(); }
+    if r.size.is_some() { d.encodeSize = r.size.clone(); }
+    // drop the main output's extension, if it is one, so the rendition's format picks its own
+    let stem = match design.outputName.as_deref().map(|n| n.rsplit(['/', '\\']).next().unwrap_or(n)) {
+        Some(n) => match n.rsplit_once('.') { Some((stem, ext)) if OutputFormat::from_name(Some(ext)).is_ok() => stem, _ => n },
+        None => "output",
     };
-    for it in items {
-        let trim_end = it.trim.to.unwrap_or(0);
-        if trim_end > max_end { max_end = trim_end; }
-        // Consider display window if provided
-        let disp_end = it.display.to.unwrap_or(0);
-        if disp_end > max_end { max_end = disp_end; }
+    d.outputName = Some(format!("{}-{}", stem, r.name));
+    d
+}
+
+/// `attachment` disposition with an ASCII fallback name plus the exact name as RFC 5987 `filename*`.
+pub fn content_disposition(name: &str) -> String {
+    let fallback: String = name.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' }).collect();
+    let encoded: String = name.bytes().map(|b| if b.is_ascii_alphanumeric() || b"-._~".contains(&b) { (b as char).to_string() } else { format!("%{:02X}", b) }).collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// Replace the value following `flag`, or append the pair if absent.
+fn set_arg(args: &mut Vec<String>, flag: &str, value: &str) {
+    match args.iter().position(|a| a == flag) {
+        Some(i) if i + 1 < args.len() => args[i + 1] = value.to_string(),
+        _ => args.extend([flag.to_string(), value.to_string()]),
     }
-    // Fallback minimal duration
-    if max_end == 0 { 10_000 } else { max_end }
 }
 
-fn parse_px(s: &Option<String>) -> i32 { s.as_ref().and_then(|v| v.strip_suffix("px")).and_then(|p| p.parse::<f32>().ok()).map(|f| f.round() as i32).unwrap_or(0) }
-fn parse_scale(s: &Option<String>) -> f32 {
-    if let Some(t) = s { if let Some(start) = t.find("scale(") { if let Some(end) = t[start+6..].find(')') { return t[start+6..start+6+end].parse::<f32>().unwrap_or(1.0); } } }
-    1.0
+fn remove_arg(args: &mut Vec<String>, flag: &str) {
+    if let Some(i) = args.iter().position(|a| a == flag) { args.drain(i..(i + 2).min(args.len())); }
+}
+
+/// Layer the user's quality options over the default video codec args.
+fn apply_quality(args: &mut Vec<String>, q: &Quality, format: OutputFormat, encoder: VideoEncoder) {
+    if let Some(crf) = q.crf {
+        // each encoder spells constant-quality differently
+        let flag = match (format, encoder) {
+            (OutputFormat::Webm, _) | (_, VideoEncoder::X264) => "-crf",
+            (_, VideoEncoder::Nvenc) => "-cq",
+            (_, VideoEncoder::Qsv) => "-global_quality",
+            (_, VideoEncoder::Vaapi) => "-qp",
+            (_, VideoEncoder::VideoToolbox) => "-q:v",
+        };
+        set_arg(args, flag, &crf.to_string());
+    }
+    if let Some(br) = &q.videoBitrate {
+        set_arg(args, "-b:v", br);
+        // vp9 defaults to constant quality; a target bitrate replaces it
+        if format == OutputFormat::Webm { remove_arg(args, "-crf"); }
+    }
+    if let Some(p) = &q.preset { if format != OutputFormat::Webm { set_arg(args, "-preset", p); } }
+    if let Some(p) = &q.pixFmt { set_arg(args, "-pix_fmt", p); }
+    if let Some(p) = &q.profile { set_arg(args, "-profile:v", p); }
+}
+
+/// Strip credentials from URLs inside ffmpeg args: userinfo and query strings (signed URLs carry
+/// their tokens there) are replaced with REDACTED.
+pub fn redact_args(args: &[String]) -> Vec<String> { args.iter().map(|a| redact_urls(a)).collect() }
+
+fn redact_urls(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find("://") {
+        let (head, tail) = rest.split_at(i + 3);
+        out.push_str(head);
+        let end = tail.find(|c: char| c.is_whitespace() || "'\",;[]".contains(c)).unwrap_or(tail.len());
+        let (url, after) = tail.split_at(end);
+        let authority_end = url.find(['/', '?', '#']).unwrap_or(url.len());
+        let url = match url[..authority_end].rfind('@') { Some(at) => { out.push_str("REDACTED@"); &url[at + 1..] } None => url };
+        match url.find('?') { Some(q) => { out.push_str(&url[..q]); out.push_str("?REDACTED"); } None => out.push_str(url) }
+        rest = after;
+    }
+    out.push_str(rest);
+    out
 }
-fn opacity_alpha(o: Option<f32>) -> f32 { let v = o.unwrap_or(100.0) / 100.0; v.clamp(0.0, 1.0) }
-fn brightness_offset(b: Option<f32>) -> f32 { (b.unwrap_or(100.0) - 100.0) / 100.0 }
 
 pub struct BuiltCommand {
     pub args: Vec<String>,
+    /// Analysis pass run before `args` for two-pass encodes (VP9 at a target bitrate).
+    pub first_pass: Option<Vec<String>>,
+    pub output_path: PathBuf,
+    /// Files the graph reads (drawtext textfile=), to be written before ffmpeg runs.
+    pub text_files: Vec<(PathBuf, String)>,
+}
+
+/// Stats file prefix for two-pass encodes, relative to the job dir.
+const PASSLOG_PREFIX: &str = "ffmpeg2pass";
+
+/// Delete the two-pass stats files ffmpeg left in `workdir`.
+pub async fn remove_pass_logs(workdir: &Path) {
+    let Ok(mut entries) = tokio::fs::read_dir(workdir).await else { return };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with(PASSLOG_PREFIX) { let _ = tokio::fs::remove_file(entry.path()).await; }
+    }
+}
+
+/// Downloaded design-level files (not tied to an item), each set when the design asks for it.
+#[derive(Default, Clone, Copy)]
+pub struct DesignFiles<'a> {
+    pub subtitles: Option<&'a Path>,
+    pub background: Option<&'a Path>,
+    pub watermark: Option<&'a Path>,
 }
 
 pub fn build_ffmpeg_command(
@@ -85,96 +1583,296 @@ pub fn build_ffmpeg_command(
     assets: &[(usize, &TrackItem, PathBuf)],
     caps: &BackendCaps,
     font_map: &std::collections::HashMap<String, PathBuf>,
+    files: DesignFiles,
 ) -> Result<BuiltCommand> {
-    fs::create_dir_all(workdir).ok();
-    let out_path = workdir.join("output.mp4");
+    let DesignFiles { subtitles, background, watermark } = files;
+    let format = output_format(design)?;
+    // audio-only formats skip the canvas, overlays and text entirely
+    let video = format.has_video();
+    let out_path = workdir.join(output_file_name(design.outputName.as_deref(), format)?);
+    let alpha = design.alpha.unwrap_or(false);
+    if alpha && !format.supports_alpha() { return Err(anyhow!("alpha output needs format mov or webm")); }
+    let mute = design.mute.unwrap_or(false);
+    if mute && !video { return Err(anyhow!("mute needs a format with video")); }
     let mut args: Vec<String> = vec!["-y".into(), "-hide_banner".into(), "-loglevel".into(), "error".into()];
-    if caps.nvenc { args.extend(["-hwaccel".into(), "cuda".into()]); }
-    let fps = design.fps.unwrap_or(30);
-    let (out_w, out_h) = (
-        design.size.as_ref().map(|s| s.width).unwrap_or(1080),
-        design.size.as_ref().map(|s| s.height).unwrap_or(1920),
-    );
-    let duration_ms = compute_duration_ms(design);
+    args.extend(ffmpeg_extra_args());
+    // alpha mov is ProRes rather than H.264
+    let h264 = matches!(format, OutputFormat::Mp4 | OutputFormat::Mov) && !alpha;
+    let encoder = VideoEncoder::select(caps);
+    if h264 { args.extend(encoder.global_args()); }
+    let draft = is_draft(design);
+    let fps = if draft { design.fps.unwrap_or(30).min(DRAFT_MAX_FPS) } else { design.fps.unwrap_or(30) };
+    let (out_w, out_h) = output_size(design);
+    // Output t=0 is timeline `range_start`; every window below is shifted by `off`
+    let timeline_ms = compute_duration_ms(design);
+    let (range_start, range_end) = render_range_ms(design);
+    let duration_ms = range_end - range_start;
     let duration_s = (duration_ms as f64) / 1000.0;
+    let off = range_start as f64 / 1000.0;
 
-    // Base black canvas as input 0
-    args.extend([
-        "-f".into(), "lavfi".into(),
-        "-i".into(), format!("color=c=black:s={}x{}:r={}:d={}", out_w, out_h, fps, duration_s),
-    ]);
-
+    // Base canvas as input 0: the background image looped for the whole output, or a color source
+    // (black by default; fully transparent and kept rgba for alpha output)
+    let background = background.filter(|_| video && background_image(design).is_some());
+    if let Some(path) = background {
+        args.extend(["-loop".into(), "1".into(), "-framerate".into(), fps.to_string(), "-t".into(), format!("{:.3}", duration_s)]);
+        args.extend(["-i".into(), path.to_string_lossy().to_string()]);
+    } else if video {
+        let canvas = match design.background.as_deref() {
+            Some(c) => ff_color(c, 1.0),
+            None if alpha => "black@0.0".into(),
+            None => "black".into(),
+        };
+        let canvas_fmt = if alpha { ",format=rgba" } else { "" };
+        args.extend([
+            "-f".into(), "lavfi".into(),
+            "-i".into(), format!("color=c={}:s={}x{}:r={}:d={}{}", canvas, out_w, out_h, fps, duration_s, canvas_fmt),
+        ]);
+    }
+    let input_base = if video { 1 } else { 0 };
 
-    // Asset inputs start from index 1 (index 0 is the base canvas)
+    // Asset inputs start from index 1 (index 0 is the base canvas) unless the output is audio-only
     for (_, item, path) in assets {
+        if let Some(seq) = item.details.as_ref().and_then(|d| d.sequence.as_ref()) {
+            // numbered stills through the image2 demuxer, numbered from the sequence's first frame
+            args.extend(["-framerate".into(), format!("{}", sequence_fps(seq, design)), "-start_number".into(), seq.start.unwrap_or(0).to_string()]);
+        }
         match item.kind {
+            // ken burns images stay a single frame; zoompan generates the frames
+            TrackType::Image if item.details.as_ref().is_some_and(|d| d.kenBurns.is_some()) => {}
             TrackType::Image => {
-                // images need looping to act as a video stream; visibility is gated in filters
-                args.extend(["-loop".into(), "1".into()]);
+                if let Some(demuxer) = animated_image(item, path) {
+                    // animated GIF/APNG plays at its own frame timing and repeats, instead of holding frame one
+                    args.extend(["-f".into(), demuxer.into(), "-ignore_loop".into(), "0".into()]);
+                } else {
+                    // images need looping to act as a video stream; visibility is gated in filters
+                    args.extend(["-loop".into(), "1".into()]);
+                }
                 // ensure finite duration to avoid infinite streams that stall the graph
                 args.extend(["-t".into(), format!("{:.3}", duration_s)]);
             }
+            TrackType::Video if video_on_end(item) == Some("loop") => {
+                // repeat short clips across their display window; -t keeps the stream finite (in source time)
+                args.extend(["-stream_loop".into(), "-1".into()]);
+                args.extend(["-t".into(), format!("{:.3}", duration_s * item_speed(item))]);
+            }
             _ => {}
         }
+        // videos run in timeline time, so skip the part before the rendered range
+        if matches!(item.kind, TrackType::Video) && off > 0.0 { args.extend(["-ss".into(), format!("{:.3}", off)]); }
+        args.extend(["-i".into(), path.to_string_lossy().to_string()]);
+    }
+    // fills have no file; each is generated at its item's size as a lavfi input after the assets
+    let fills: Vec<(&TrackItem, &Fill)> = ordered_items(design).into_iter().filter(|it| video && is_fill(it))
+        .filter_map(|it| Some((it, it.details.as_ref()?.fill.as_ref()?))).collect();
+    for (it, fill) in &fills {
+        let (w, h) = item_box(it, out_w, out_h);
+        args.extend(["-f".into(), "lavfi".into(), "-i".into(), fill_source(fill, w, h, fps, duration_s)]);
+    }
+    // the watermark comes last, looped for the whole output like a background image
+    let watermark = watermark.filter(|_| video).zip(design.watermark.as_ref());
+    if let Some((path, _)) = watermark {
+        args.extend(["-loop".into(), "1".into(), "-framerate".into(), fps.to_string(), "-t".into(), format!("{:.3}", duration_s)]);
         args.extend(["-i".into(), path.to_string_lossy().to_string()]);
     }
 
     // Build filter graph
     // Label each input video/image as v{i}, audio as a{i}
     let mut filter_parts: Vec<String> = Vec::new();
+    let mut text_files: Vec<(PathBuf, String)> = Vec::new();
     let mut audio_labels: Vec<String> = Vec::new();
+    // audio chains are kept apart so a video-only first pass can leave them out
+    let mut audio_parts: Vec<String> = Vec::new();
 
     // Start from base canvas as the initial video
     let mut last = String::from("0:v");
+    if background.is_some() {
+        // cover the frame like CSS background-size: cover
+        filter_parts.push(format!("[0:v]scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h},setsar=1,format=rgba[bg]", w = out_w, h = out_h));
+        last = "bg".into();
+    }
 
-    for (idx0, item, _) in assets {
+    // Composite bottom-to-top. Entries are (position, ffmpeg input, item).
+    let (mut asset_pos, mut fill_pos) = (Vec::new(), Vec::new());
+    for (pos, it) in layer_items(design).into_iter().enumerate() {
+        if is_fill(it) { fill_pos.push(pos) } else { asset_pos.push(pos) }
+    }
+    let mut layered: Vec<(usize, usize, &TrackItem)> = assets.iter().map(|(i, it, _)| (asset_pos.get(*i).copied().unwrap_or(*i), input_base + i, *it))
+        .chain(fills.iter().zip(&fill_pos).enumerate().map(|(j, ((it, _), pos))| (*pos, input_base + assets.len() + j, *it)))
+        .collect();
+    sort_layers(&mut layered);
+
+    let mut tone_mapped = false;
+    for (_, ff_idx, item) in layered {
         match item.kind {
-            TrackType::Video | TrackType::Image => {
-                let ff_idx = idx0 + 1; // account for base canvas at 0
-                let mut chain = format!("[{}:v]format=rgba", ff_idx);
+            TrackType::Video | TrackType::Image if video => {
+                let mut chain = format!("[{}:v]", ff_idx);
+                let speed = item_speed(item);
+                if matches!(item.kind, TrackType::Video) && (speed - 1.0).abs() > 1e-6 {
+                    // retime around the display start so the clip starts where it did and plays faster/slower from there
+                    let from = item.display.from.or(item.trim.from).unwrap_or(0) as f64 / 1000.0 - off;
+                    chain.push_str(&format!("setpts={from:.3}/TB+(PTS-{from:.3}/TB)/{speed},"));
+                }
+                // after the retime, so slow motion gets interpolated too; minterpolate doesn't take rgba
+                if let Some(mi) = interpolate_filter(design, item, fps) { chain.push_str(&format!("{},", mi)); }
+                if let Some(tm) = tonemap_filter(design, item) {
+                    chain.push_str(&format!("{},", tm));
+                    tone_mapped = true;
+                }
+                chain.push_str("format=rgba");
+                // frames run in output time from 0, so cloning the last one up to the window end always covers it
+                let end = item_end_ms(item).unwrap_or(timeline_ms) as f64 / 1000.0 - off;
+                if matches!(item.kind, TrackType::Video) && video_on_end(item) == Some("hold") {
+                    chain.push_str(&format!(",tpad=stop_mode=clone:stop_duration={:.3}", end.max(0.0)));
+                }
+                // blank drops the layer when the clip ends instead of overlay's default repeat of the last frame
+                let eof = if matches!(item.kind, TrackType::Video) && video_on_end(item) == Some("blank") { ":eof_action=pass" } else { "" };
+                // key at source resolution, before scaling blurs the edges into the key color
+                if let Some(key) = item.details.as_ref().and_then(|d| d.chromaKey.as_ref()) {
+                    let (r, g, b, _) = parse_rgba(&key.color);
+                    chain.push_str(&format!(",colorkey=color=0x{:02x}{:02x}{:02x}:similarity={}:blend={}", r, g, b, key.similarity.unwrap_or(0.3), key.blend.unwrap_or(0.1)));
+                }
                 // scale
-                let (w, h) = (
-                    item.details.as_ref().and_then(|d| d.width).unwrap_or_else(|| design.size.as_ref().map(|s| s.width).unwrap_or(1080)),
-                    item.details.as_ref().and_then(|d| d.height).unwrap_or_else(|| design.size.as_ref().map(|s| s.height).unwrap_or(1920)),
-                );
+                let (w, h) = item_box(item, out_w, out_h);
+                // fit to the item box first; the scale below (or zoompan) then keeps the aspect
+                if let Some(f) = fit_filter(item.details.as_ref().and_then(|d| d.fit.as_deref()), w, h) { chain.push_str(&format!(",{}", f)); }
+                if let (TrackType::Image, Some(kb)) = (&item.kind, item.details.as_ref().and_then(|d| d.kenBurns.as_ref())) {
+                    // one zoompan frame per output frame of the display window, then moved to start there
+                    let from_ms = item_start_ms(item) - transition_lead_ms(item);
+                    let to_ms = item.display.to.or(item.trim.to).unwrap_or(timeline_ms);
+                    let frames = ((to_ms.saturating_sub(from_ms) as f64 / 1000.0) * fps as f64).round().max(1.0) as u64;
+                    chain.push_str(&format!(",{},setpts=PTS-STARTPTS+({:.3})/TB", ken_burns_filter(kb, w, h, fps, frames), from_ms as f64 / 1000.0 - off));
+                }
                 let scale = parse_scale(&item.details.as_ref().and_then(|d| d.transform.clone()));
-                let (sw, sh) = (((w as f32) * scale) as i32, ((h as f32) * scale) as i32);
-                chain.push_str(&format!(",scale={}:{}", sw.max(1), sh.max(1)));
+                // keyframe times are relative to the item's display start
+                let start = item.display.from.or(item.trim.from).unwrap_or(0) as f64 / 1000.0 - off;
+                let keyframes: &[Keyframe] = item.details.as_ref().map(|d| d.keyframes.as_slice()).unwrap_or(&[]);
+                let track = |f: fn(&Keyframe) -> Option<f32>| -> Vec<(f64, f64)> {
+                    keyframes.iter().filter_map(|k| f(k).map(|v| (start + k.timeMs as f64 / 1000.0, v as f64))).collect()
+                };
+                if let Some(sexpr) = lerp_expr(&track(|k| k.scale), "t") {
+                    chain.push_str(&format!(",scale=w='max(2,2*ceil({}*({})/2))':h='max(2,2*ceil({}*({})/2))':eval=frame", w, sexpr, h, sexpr));
+                } else {
+                    let (sw, sh) = (even(((w as f32) * scale) as u32), even(((h as f32) * scale) as u32));
+                    chain.push_str(&format!(",scale={}:{}", sw.max(2), sh.max(2)));
+                }
+                // flips happen in the item's own frame, so before rotation
+                if item.details.as_ref().and_then(|d| d.flipX).unwrap_or(false) { chain.push_str(",hflip"); }
+                if item.details.as_ref().and_then(|d| d.flipY).unwrap_or(false) { chain.push_str(",vflip"); }
+                // corner mask also lives in the item's frame so it turns with the rotation below
+                if let Some(mask) = rounded_corners_filter(&item.details.as_ref().and_then(|d| d.borderRadius.clone())) { chain.push_str(&format!(",{}", mask)); }
                 // rotate (degrees to radians)
                 if let Some(rot) = item.details.as_ref().and_then(|d| d.rotate.clone()) {
                     if let Ok(deg) = rot.trim().trim_end_matches("deg").parse::<f32>() { if deg.abs() > 0.01 { chain.push_str(&format!(",rotate={:.6}*PI/180", deg)); } }
                 }
-                // brightness (eq)
-                let b = brightness_offset(item.details.as_ref().and_then(|d| d.brightness));
-                if b.abs() > 0.001 { chain.push_str(&format!(",eq=brightness={}", b)); }
+                // color grade (eq)
+                if let Some(eq) = item.details.as_ref().and_then(eq_filter) { chain.push_str(&format!(",{}", eq)); }
+                // blur / sharpen on the final (scaled, rotated) layer, so sigma is in output pixels
+                if let Some(sigma) = item.details.as_ref().and_then(|d| d.blur).map(|s| s.clamp(0.0, 100.0)).filter(|s| *s > 0.01 && !draft) {
+                    chain.push_str(&format!(",gblur=sigma={}", sigma));
+                }
+                if let Some(amount) = item.details.as_ref().and_then(|d| d.sharpen).map(|s| s.clamp(0.0, 5.0)).filter(|s| *s > 0.01) {
+                    chain.push_str(&format!(",unsharp=lx=5:ly=5:la={}:cx=5:cy=5:ca=0", amount));
+                }
                 // opacity
-                let a = opacity_alpha(item.details.as_ref().and_then(|d| d.opacity));
-                if a < 0.999 { chain.push_str(&format!(",colorchannelmixer=aa={}", a)); }
+                if let Some(oexpr) = lerp_expr(&track(|k| k.opacity), "T") {
+                    // colorchannelmixer can't take expressions; scale alpha per pixel instead
+                    chain.push_str(&format!(",geq=r='r(X,Y)':g='g(X,Y)':b='b(X,Y)':a='alpha(X,Y)*clip(({})/100,0,1)'", oexpr));
+                } else {
+                    let a = opacity_alpha(item.details.as_ref().and_then(|d| d.opacity));
+                    if a < 0.999 { chain.push_str(&format!(",colorchannelmixer=aa={}", a)); }
+                }
+                // fades on the alpha channel, clamped to the window so fade-out ends exactly at `end`
+                let window = (end - start).max(0.0);
+                let fade_in = item.details.as_ref().and_then(|d| d.fadeInMs).map(|ms| (ms as f64 / 1000.0).min(window)).unwrap_or(0.0);
+                let fade_out = item.details.as_ref().and_then(|d| d.fadeOutMs).map(|ms| (ms as f64 / 1000.0).min(window)).unwrap_or(0.0);
+                // a transition shows the item early, fading in over whatever is underneath (the outgoing clip)
+                let lead = transition_lead_ms(item) as f64 / 1000.0;
+                let shown = start - lead;
+                if lead > 0.0 { chain.push_str(&format!(",fade=t=in:st={:.3}:d={:.3}:alpha=1", shown, lead)); }
+                if fade_in > 0.0 { chain.push_str(&format!(",fade=t=in:st={:.3}:d={:.3}:alpha=1", start, fade_in)); }
+                if fade_out > 0.0 { chain.push_str(&format!(",fade=t=out:st={:.3}:d={:.3}:alpha=1", end - fade_out, fade_out)); }
                 let vlabel = format!("v{}", ff_idx);
-                chain.push_str(&format!("[{}]", vlabel));
+                let shadow = item.details.as_ref().and_then(|d| Some((parse_rgba(d.shadowColor.as_deref()?), d.shadowX.unwrap_or(0), d.shadowY.unwrap_or(0), d.shadowBlur.unwrap_or(0))));
+                if shadow.is_some() { chain.push_str(&format!(",split[{}][s{}]", vlabel, ff_idx)); } else { chain.push_str(&format!("[{}]", vlabel)); }
                 filter_parts.push(chain);
 
                 // overlay onto last with timing window
-                let x = parse_px(&item.details.as_ref().and_then(|d| d.left.clone()));
-                let y = parse_px(&item.details.as_ref().and_then(|d| d.top.clone()));
-                let start = item.display.from.or(item.trim.from).unwrap_or(0) as f64 / 1000.0;
-                let end_ms = item.display.to.or(item.trim.to).unwrap_or(duration_ms);
-                let end = (end_ms as f64) / 1000.0;
+                let x = lerp_expr(&track(|k| k.left), "t").map(|e| format!("'{}'", e))
+                    .unwrap_or_else(|| parse_px(&item.details.as_ref().and_then(|d| d.left.clone()), out_w).to_string());
+                let y = lerp_expr(&track(|k| k.top), "t").map(|e| format!("'{}'", e))
+                    .unwrap_or_else(|| parse_px(&item.details.as_ref().and_then(|d| d.top.clone()), out_h).to_string());
+                if let Some(((r, g, b, a), dx, dy, blur)) = shadow {
+                    // tinted copy of the finished item (so it shares its alpha, fades and rotation), padded so the blur isn't cut off
+                    let pad = blur * 2;
+                    let mut s = format!("[s{}]lutrgb=r={}:g={}:b={},colorchannelmixer=aa={}", ff_idx, r, g, b, a);
+                    if blur > 0 { s.push_str(&format!(",pad=w=iw+{p}:h=ih+{p}:x={h}:y={h}:color=black@0,boxblur={b}:1", p = pad * 2, h = pad, b = blur)); }
+                    filter_parts.push(format!("{}[sh{}]", s, ff_idx));
+                    let (sx, sy) = (format!("'{}+({})'", x.trim_matches('\''), dx - pad as i32), format!("'{}+({})'", y.trim_matches('\''), dy - pad as i32));
+                    let shadowed = format!("ms{}", ff_idx);
+                    filter_parts.push(format!("[{}][sh{}]overlay=x={}:y={}:format=auto{}:enable='between(t,{:.3},{:.3})'[{}]", last, ff_idx, sx, sy, eof, shown, end, shadowed));
+                    last = shadowed;
+                }
                 let out = format!("m{}", ff_idx);
-                filter_parts.push(format!("[{}][{}]overlay={}:{}:format=auto:enable='between(t,{:.3},{:.3})'[{}]", last, vlabel, x, y, start, end, out));
+                if let Some(mode) = blend_mode(item) {
+                    // blend needs two full frames and has no per-pixel alpha: place the item on a clear frame
+                    // (so it's only there during its window), blend that with the whole frame, then keep the
+                    // blended pixels only where the item's alpha says so
+                    let i = ff_idx;
+                    filter_parts.push(format!("[{}]split=3[bb{i}][bc{i}][bs{i}];[bc{i}]format=rgba,colorchannelmixer=aa=0[bk{i}]", last));
+                    filter_parts.push(format!("[bk{i}][{}]overlay=x={}:y={}:format=auto{}:enable='between(t,{:.3},{:.3})',format=rgba,split[bl{i}][ba{i}]", vlabel, x, y, eof, shown, end));
+                    filter_parts.push(format!("[ba{i}]alphaextract[bm{i}];[bs{i}]format=gbrap[bsg{i}];[bl{i}]format=gbrap[blg{i}];[bsg{i}][blg{i}]blend=all_mode={}[bx{i}]", mode));
+                    filter_parts.push(format!("[bx{i}][bm{i}]alphamerge[by{i}];[bb{i}][by{i}]overlay=format=auto[{}]", out));
+                } else {
+                    filter_parts.push(format!("[{}][{}]overlay=x={}:y={}:format=auto{}:enable='between(t,{:.3},{:.3})'[{}]", last, vlabel, x, y, eof, shown, end, out));
+                }
                 last = out;
             }
-            TrackType::Audio => {
+            TrackType::Audio if !mute => {
                 // Will be handled in audio mixing section, collect labels then
-                let ff_idx = idx0 + 1;
                 let vol = item.details.as_ref().and_then(|d| d.volume).unwrap_or(100.0) / 100.0;
-                let start_ms = item.display.from.or(item.trim.from).unwrap_or(0);
-                let mut alabel = format!("a{}", ff_idx);
-                let mut chain = format!("[{}:a]volume={}", ff_idx, vol);
-                if let Some(from) = item.trim.from { chain.push_str(&format!(",atrim=start={:.3}", (from as f64)/1000.0)); chain.push_str(",asetpts=PTS-STARTPTS"); }
-                chain.push_str(&format!(",adelay={}:all=1", start_ms));
-                chain.push_str(&format!(",atrim=0:{:.3},asetpts=PTS-STARTPTS[{}]", duration_ms as f64 / 1000.0, alabel));
-                filter_parts.push(chain);
+                // a transition starts the clip early, fading in while the previous one fades out
+                let lead_ms = transition_lead_ms(item);
+                let start_ms = item_start_ms(item) - lead_ms;
+                let alabel = format!("a{}", ff_idx);
+                let mut chain = format!("[{}:a]{}volume={}", ff_idx, channel_filters(item), vol);
+                // the trim window in source time, then clip time from 0 whatever the source's first timestamp
+                let secs = |ms: u64| ms as f64 / 1000.0;
+                match (item.trim.from, item.trim.to) {
+                    (Some(from), Some(to)) => chain.push_str(&format!(",atrim=start={}:end={}", secs(from), secs(to))),
+                    (Some(from), None) => chain.push_str(&format!(",atrim=start={}", secs(from))),
+                    (None, Some(to)) => chain.push_str(&format!(",atrim=end={}", secs(to))),
+                    (None, None) => {}
+                }
+                chain.push_str(",asetpts=PTS-STARTPTS");
+                let speed = item_speed(item);
+                if (speed - 1.0).abs() > 1e-6 { chain.push_str(&format!(",{}", atempo_chain(speed))); }
+                // stop at the end of the display window; clip time here already runs at the item's speed
+                if let Some(to) = item.display.to { chain.push_str(&format!(",atrim=end={}", secs(to.saturating_sub(start_ms)))); }
+                // fades are in clip time (before adelay); fade-out lands on the end of the display/trim window
+                if let Some(fade_ms) = item.details.as_ref().and_then(|d| d.audioFadeMs).filter(|f| *f > 0) {
+                    let clip_ms = match (item.display.from, item.display.to, item.trim.from, item.trim.to) {
+                        (Some(f), Some(t), _, _) if t > f => Some(t - f),
+                        (_, _, Some(_), Some(_)) => trim_end_ms(item).map(|t| t.saturating_sub(item_start_ms(item))).filter(|c| *c > 0),
+                        (None, Some(t), _, _) => Some(t),
+                        _ => None,
+                    };
+                    let fade = clip_ms.map(|c| fade_ms.min(c)).unwrap_or(fade_ms) as f64 / 1000.0;
+                    chain.push_str(&format!(",afade=t=in:st=0:d={}", fade));
+                    if let Some(c) = clip_ms { chain.push_str(&format!(",afade=t=out:st={}:d={}", c as f64 / 1000.0 - fade, fade)); }
+                }
+                if lead_ms > 0 { chain.push_str(&format!(",afade=t=in:st=0:d={}", lead_ms as f64 / 1000.0)); }
+                if let Some((st, d)) = outgoing_crossfade_ms(design, item) {
+                    chain.push_str(&format!(",afade=t=out:st={}:d={}", st.saturating_sub(start_ms) as f64 / 1000.0, d as f64 / 1000.0));
+                }
+                // clips that begin before the rendered range lose their head instead of being delayed
+                let cut_ms = range_start.saturating_sub(start_ms);
+                if cut_ms > 0 { chain.push_str(&format!(",atrim=start={},asetpts=PTS-STARTPTS", secs(cut_ms))); }
+                // timestamps start at 0 here, so the silence adelay inserts puts the first sample exactly at
+                // the (range-relative, never negative) start; the output length caps the end
+                let delay_ms = start_ms.saturating_sub(range_start);
+                if delay_ms > 0 { chain.push_str(&format!(",adelay={}:all=1", delay_ms)); }
+                chain.push_str(&format!(",atrim=end={}[{}]", secs(duration_ms), alabel));
+                audio_parts.push(chain);
                 audio_labels.push(alabel);
             }
             _ => {}
@@ -182,76 +1880,1331 @@ pub fn build_ffmpeg_command(
     }
 
     // Text overlays
-    let items_all: Vec<&TrackItem> = if !design.trackItems.is_empty() { design.trackItems.iter().collect() } else { design.trackItemsMap.values().collect() };
+    let items_all = ordered_items(design);
     for it in items_all {
-        if let TrackType::Text = it.kind {
+        if let (true, TrackType::Text) = (video, &it.kind) {
             if let Some(id) = &it.id {
                 if let Some(font_path) = font_map.get(id) {
-                    let mut text = it.details.as_ref().and_then(|d| d.text.clone()).unwrap_or_default();
-                    text = text.replace("\\", "\\\\").replace("'", "\\'").replace(":", "\\:");
+                    let text = it.details.as_ref().and_then(|d| d.text.clone()).unwrap_or_default();
                     let fontsize = it.details.as_ref().and_then(|d| d.fontSize).unwrap_or(48);
-                    let x = parse_px(&it.details.as_ref().and_then(|d| d.left.clone()));
-                    let y = parse_px(&it.details.as_ref().and_then(|d| d.top.clone()));
+                    let x = parse_px(&it.details.as_ref().and_then(|d| d.left.clone()), out_w);
+                    let y = parse_px(&it.details.as_ref().and_then(|d| d.top.clone()), out_h);
                     let alpha = opacity_alpha(it.details.as_ref().and_then(|d| d.opacity));
                     let color = it.details.as_ref().and_then(|d| d.color.clone()).unwrap_or("white".into());
-                    let fontcolor = if color.starts_with('#') { format!("0x{}@{}", color.trim_start_matches('#'), alpha) } else { format!("{}@{}", color, alpha) };
+                    let fontcolor = ff_color(&color, alpha);
                     let borderw = it.details.as_ref().and_then(|d| d.borderWidth).unwrap_or(0);
                     let bordercolor = it.details.as_ref().and_then(|d| d.borderColor.clone()).unwrap_or("black".into());
-                    let bordercolor = if bordercolor.starts_with('#') { format!("0x{}", bordercolor.trim_start_matches('#')) } else { bordercolor };
-                    let start = it.display.from.unwrap_or(0) as f64 / 1000.0;
-                    let end = it.display.to.unwrap_or(duration_ms) as f64 / 1000.0;
-                    let out = format!("txt{}", id);
-                    filter_parts.push(format!(
-                        "[{}]drawtext=fontfile={}:text='{}':fontsize={}:fontcolor={}:borderw={}:bordercolor={}:x={}:y={}:enable='between(t,{:.3},{:.3})'[{}]",
-                        last, font_path.to_string_lossy(), text, fontsize, fontcolor, borderw, bordercolor, x, y, start, end, out
-                    ));
-                    last = out;
+                    // (fontcolor, borderw, bordercolor) per drawtext pass. A single outline is drawtext's own
+                    // border; layered strokes are passes of the text in the stroke color, widest first, then the fill
+                    let mut strokes: Vec<(u32, &str)> = it.details.as_ref().map(|d| d.strokes.iter().map(|s| (s.width, s.color.as_str())).collect()).unwrap_or_default();
+                    let passes: Vec<(String, u32, String)> = if strokes.is_empty() {
+                        vec![(fontcolor, borderw, ff_color(&bordercolor, alpha))]
+                    } else {
+                        if borderw > 0 { strokes.push((borderw, bordercolor.as_str())); }
+                        strokes.sort_by_key(|s| std::cmp::Reverse(s.0));
+                        strokes.iter().map(|(w, c)| (ff_color(c, alpha), *w, ff_color(c, alpha)))
+                            .chain([(fontcolor.clone(), 0, fontcolor)]).collect()
+                    };
+                    // optional background box, faded with the text
+                    let box_opts = it.details.as_ref().and_then(|d| d.backgroundColor.as_ref()).map(|bg| {
+                        let pad = it.details.as_ref().and_then(|d| d.boxPadding).unwrap_or(0);
+                        format!(":box=1:boxcolor={}:boxborderw={}", escape_filter_value(&ff_color(bg, alpha)), pad)
+                    }).unwrap_or_default();
+                    let shadow_opts = it.details.as_ref().and_then(|d| {
+                        let (r, g, b, a) = parse_rgba(d.shadowColor.as_deref()?);
+                        let color = format!("0x{:02x}{:02x}{:02x}@{}", r, g, b, a * alpha);
+                        Some(format!(":shadowcolor={}:shadowx={}:shadowy={}", escape_filter_value(&color), d.shadowX.unwrap_or(0), d.shadowY.unwrap_or(0)))
+                    }).unwrap_or_default();
+                    let start = it.display.from.unwrap_or(0) as f64 / 1000.0 - off;
+                    let end = it.display.to.unwrap_or(timeline_ms) as f64 / 1000.0 - off;
+                    // drawtext doesn't wrap, so lay out lines here and stack one drawtext per line
+                    let line_px = parse_line_height(&it.details.as_ref().and_then(|d| d.lineHeight.clone()), fontsize);
+                    let max_width = it.details.as_ref().and_then(|d| d.maxWidth);
+                    let lines = wrap_text(&text, fontsize, max_width);
+                    let has_left = it.details.as_ref().and_then(|d| d.left.as_ref()).is_some();
+                    if wants_shaping(it.details.as_ref(), &text) {
+                        // drawtext neither shapes nor reorders RTL text; libass does, so the item becomes a small ASS script
+                        let family = font_family(font_path)
+                            .or_else(|| it.details.as_ref().and_then(|d| d.fontFamily.as_deref()).and_then(|f| f.split(',').next()).map(|f| f.trim().trim_matches(['"', '\'']).to_string()))
+                            .unwrap_or_else(|| "sans-serif".into());
+                        let (an, ax) = ass_anchor(it.details.as_ref().and_then(|d| d.textAlign.as_deref()), x, has_left, max_width, out_w);
+                        let script = ass_text_script(it.details.as_ref(), &lines, &family, (an, ax, y), line_px, (out_w, out_h), (start, end));
+                        let path = workdir.join(format!("text{}.ass", text_files.len()));
+                        let fonts_dir = font_path.parent().unwrap_or(workdir);
+                        let out = format!("txt{}", id);
+                        filter_parts.push(format!("[{}]ass=filename={}:fontsdir={}[{}]", last, escape_filter_value(&path.to_string_lossy()), escape_filter_value(&fonts_dir.to_string_lossy()), out));
+                        text_files.push((path, script));
+                        last = out;
+                        continue;
+                    }
+                    let x_expr = text_x_expr(it.details.as_ref().and_then(|d| d.textAlign.as_deref()), x, has_left, max_width);
+                    let n_lines = lines.len();
+                    for (i, line) in lines.into_iter().enumerate() {
+                        if line.trim().is_empty() { continue; }
+                        // expansion=none keeps % and {} literal; long lines and ones heavy with quotes/backslashes go
+                        // through a file so nothing has to survive the quoting
+                        let text_opt = if line.chars().count() > TEXTFILE_MIN_CHARS || line.contains(['\\', '\'']) {
+                            let path = workdir.join(format!("drawtext{}.txt", text_files.len()));
+                            let opt = format!("textfile={}", escape_filter_value(&path.to_string_lossy()));
+                            text_files.push((path, line));
+                            opt
+                        } else {
+                            format!("text={}", escape_filter_value(&line))
+                        };
+                        let line_y = y + (i as f32 * line_px).round() as i32;
+                        for (p, (fontcolor, borderw, bordercolor)) in passes.iter().enumerate() {
+                            let out = match (i + 1 == n_lines, p + 1 == passes.len()) {
+                                (true, true) => format!("txt{}", id),
+                                (false, true) => format!("txt{}_{}", id, i),
+                                _ => format!("txt{}_{}_s{}", id, i, p),
+                            };
+                            // box and shadow belong under everything, so only on the first pass
+                            let (box_opts, shadow_opts) = if p == 0 { (box_opts.as_str(), shadow_opts.as_str()) } else { ("", "") };
+                            filter_parts.push(format!(
+                                "[{}]drawtext=fontfile={}:{}:expansion=none:fontsize={}:fontcolor={}:borderw={}:bordercolor={}{}{}:x={}:y={}:enable='between(t,{:.3},{:.3})'[{}]",
+                                last, escape_filter_value(&font_path.to_string_lossy()), text_opt, fontsize, escape_filter_value(fontcolor), borderw, escape_filter_value(bordercolor), box_opts, shadow_opts, x_expr, line_y, start, end, out
+                            ));
+                            last = out;
+                        }
+                    }
                 }
             }
         }
     }
 
+    // Burn in captions on top of everything; cue times are timeline times, so shift by the range start around the filter
+    if let (true, Some(path), Some(sub)) = (video, subtitles, &design.subtitles) {
+        let f = subtitles_filter(path, sub);
+        let f = if off > 0.0 { format!("setpts=PTS+{off:.3}/TB,{f},setpts=PTS-{off:.3}/TB") } else { f };
+        filter_parts.push(format!("[{}]{}[subs]", last, f));
+        last = "subs".into();
+    }
+
+    // Watermark is the topmost layer, after items, text and captions
+    if let Some((_, wm)) = watermark {
+        let scale = wm.scale.unwrap_or(1.0);
+        let mut chain = format!("[{}:v]format=rgba", input_base + assets.len() + fills.len());
+        if (scale - 1.0).abs() > 1e-6 { chain.push_str(&format!(",scale=w='max(1,iw*{s})':h='max(1,ih*{s})'", s = scale)); }
+        let a = opacity_alpha(wm.opacity);
+        if a < 0.999 { chain.push_str(&format!(",colorchannelmixer=aa={}", a)); }
+        let (x, y) = watermark_position(wm);
+        filter_parts.push(format!("{}[wm];[{}][wm]overlay=x={}:y={}:format=auto[wmout]", chain, last, x, y));
+        last = "wmout".into();
+    }
+
     // Audio mix (optional)
     let mut maps: Vec<(String, String)> = Vec::new();
-    let vout = last;
-    maps.push((vout.clone(), "v".into()));
+    let mut vout = last;
+    if (draft || design.encodeSize.is_some()) && video {
+        // fit inside the encoded size; a rendition with another aspect ratio is letterboxed
+        let (w, h) = encoded_size(design);
+        filter_parts.push(format!("[{}]scale={w}:{h}:force_original_aspect_ratio=decrease:force_divisible_by=2,pad={w}:{h}:-1:-1:color=black@0[scaled]", vout, w = w, h = h));
+        vout = "scaled".into();
+    }
+    if format == OutputFormat::Gif {
+        // Single-graph two-pass gif: build an optimized palette from the whole clip, then map through it
+        filter_parts.push(format!("[{}]split[gifa][gifb];[gifa]palettegen=stats_mode=diff[gifpal];[gifb][gifpal]paletteuse=dither=bayer:bayer_scale=5[gifout]", vout));
+        vout = "gifout".into();
+    }
+    if let (true, Some(upload)) = (h264, encoder.upload_filter()) {
+        filter_parts.push(format!("[{}]{}[hwout]", vout, upload));
+        vout = "hwout".into();
+    }
+    if video { maps.push((vout.clone(), "v".into())); }
 
-    if !audio_labels.is_empty() {
+    if !audio_labels.is_empty() && format.has_audio() {
         if audio_labels.len() == 1 {
-            filter_parts.push(format!("[{}]anull[aout]", audio_labels[0]));
+            audio_parts.push(format!("[{}]anull[aout]", audio_labels[0]));
         } else {
             let list = audio_labels.iter().map(|l| format!("[{}]", l)).collect::<String>();
-            filter_parts.push(format!("{}amix=inputs={}:normalize=0[aout]", list, audio_labels.len()));
+            // normalize=0 keeps levels but can clip once sources overlap; tame the sum afterwards
+            let post = match design.audioMix.as_deref().map(str::to_ascii_lowercase).as_deref() {
+                Some("none") => "",
+                Some("dynaudnorm") => ",dynaudnorm",
+                _ => ",alimiter=limit=0.95",
+            };
+            audio_parts.push(format!("{}amix=inputs={}:normalize=0{}[aout]", list, audio_labels.len(), post));
         }
         maps.push(("aout".into(), "a".into()));
     }
 
-    let filter_complex = filter_parts.join(";");
+    let inputs_len = args.len();
+    let video_graph = filter_parts.join(";");
+    let filter_complex = filter_parts.iter().chain(&audio_parts).cloned().collect::<Vec<_>>().join(";");
     if !filter_complex.is_empty() { args.extend(["-filter_complex".into(), filter_complex]); }
 
     // Map outputs
+    let draft_q = draft.then(|| draft_quality(encoder));
+    let q = draft_q.as_ref().unwrap_or(&design.quality);
+    if q.crf.is_some() && q.videoBitrate.is_some() { return Err(anyhow!("crf and videoBitrate are mutually exclusive")); }
+    // VP9 hits a target bitrate much better with a separate analysis pass
+    let two_pass = format == OutputFormat::Webm && q.videoBitrate.is_some();
+    let passlog = workdir.join(PASSLOG_PREFIX).to_string_lossy().to_string();
+    let mut first_pass = None;
+    let mut audio_args = format.audio_codec_args();
+    if let Some(ab) = &q.audioBitrate { set_arg(&mut audio_args, "-b:a", ab); }
     let mut mapped_audio = false;
     for (src, kind) in &maps {
         args.extend(["-map".into(), format!("[{}]", src)]);
         if kind == "v" {
-            if caps.nvenc { args.extend(["-c:v".into(), "h264_nvenc".into(), "-preset".into(), "p4".into()]); }
-            else { args.extend(["-c:v".into(), "libx264".into(), "-preset".into(), "veryfast".into()]); }
-            args.extend(["-pix_fmt".into(), "yuv420p".into()]);
+            let mut video_args: Vec<String> = match format {
+                OutputFormat::Mov if alpha => ["-c:v", "prores_ks", "-profile:v", "4444", "-pix_fmt", "yuva444p10le"].iter().map(|a| a.to_string()).collect(),
+                OutputFormat::Mp4 | OutputFormat::Mov => encoder.codec_args(),
+                OutputFormat::Webm => {
+                    let pix_fmt = if alpha { "yuva420p" } else { "yuv420p" };
+                    ["-c:v", "libvpx-vp9", "-b:v", "0", "-crf", "32", "-row-mt", "1", "-pix_fmt", pix_fmt].iter().map(|a| a.to_string()).collect()
+                }
+                OutputFormat::Gif => vec!["-loop".into(), "0".into()],
+                OutputFormat::Mp3 | OutputFormat::M4a => Vec::new(), // never mapped: no video stream
+            };
+            if format != OutputFormat::Gif { apply_quality(&mut video_args, q, format, encoder); }
+            // HDR sources were mapped to BT.709; say so rather than leave players guessing
+            if tone_mapped && format != OutputFormat::Gif { video_args.extend(["-colorspace", "bt709", "-color_primaries", "bt709", "-color_trc", "bt709"].map(String::from)); }
+            if draft && format == OutputFormat::Webm { video_args.extend(["-deadline", "realtime", "-cpu-used", "8"].map(String::from)); }
             // Honor desired fps from design/options
-            args.extend(["-r".into(), fps.to_string()]);
+            video_args.extend(["-r".into(), fps.to_string()]);
+            if two_pass {
+                let mut pass1 = args[..inputs_len].to_vec();
+                if !video_graph.is_empty() { pass1.extend(["-filter_complex".into(), video_graph.clone()]); }
+                pass1.extend(["-map".into(), format!("[{}]", src)]);
+                pass1.extend(video_args.clone());
+                pass1.extend(["-pass", "1", "-passlogfile", &passlog, "-an", "-f", "null", "-progress", "pipe:1", if cfg!(windows) { "NUL" } else { "/dev/null" }].map(String::from));
+                first_pass = Some(pass1);
+                video_args.extend(["-pass", "2", "-passlogfile", &passlog].map(String::from));
+            }
+            args.extend(video_args);
         } else if kind == "a" {
             mapped_audio = true;
-            args.extend(["-c:a".into(), "aac".into(), "-b:a".into(), "192k".into()]);
+            args.extend(audio_args.clone());
         }
     }
-    // If no explicit audio items, attempt to map base input's audio if present
-    if !mapped_audio {
-        args.extend(["-map".into(), "0:a?".into(), "-c:a".into(), "aac".into(), "-b:a".into(), "192k".into()]);
+    if !format.has_audio() || mute {
+        args.push("-an".into());
+    } else if !video {
+        args.push("-vn".into());
+    } else if !mapped_audio {
+        // If no explicit audio items, attempt to map base input's audio if present
+        args.extend(["-map".into(), "0:a?".into()]);
+        args.extend(audio_args);
     }
 
     args.extend(["-progress".into(), "pipe:1".into()]);
     args.push(out_path.to_string_lossy().to_string());
 
-    Ok(BuiltCommand { args })
+    Ok(BuiltCommand { args, first_pass, output_path: out_path, text_files })
 }
 
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn caps() -> BackendCaps { BackendCaps { ffmpeg: true, nvenc: false, qsv: false, vaapi: false, videotoolbox: false, ffprobe: false } }
+
+    fn design(v: serde_json::Value) -> Design { serde_json::from_value(v).unwrap() }
+
+    /// Build `design` the way a dry run does: each source path stands in for its downloaded file.
+    fn build_with(design: &Design, caps: &BackendCaps) -> Result<BuiltCommand> {
+        let sources = asset_sources(design);
+        let assets: Vec<(usize, &TrackItem, PathBuf)> = sources.iter().enumerate().map(|(i, (it, src))| (i, it, PathBuf::from(src))).collect();
+        let fonts: HashMap<String, PathBuf> = font_sources(design).into_iter().map(|(id, url, _)| (id, PathBuf::from(url))).collect();
+        let files = DesignFiles {
+            subtitles: design.subtitles.as_ref().map(|s| Path::new(&s.src)),
+            background: background_image(design).map(Path::new),
+            watermark: design.watermark.as_ref().map(|w| Path::new(&w.src)),
+        };
+        build_ffmpeg_command(Path::new("/jobs/test"), design, &assets, caps, &fonts, files)
+    }
+
+    fn build(design: &Design) -> BuiltCommand { build_with(design, &caps()).unwrap() }
+
+    #[tokio::test]
+    async fn local_sources_stay_under_the_roots() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("clip.mp4"), b"x").unwrap();
+        std::fs::write(outside.path().join("secret"), b"x").unwrap();
+        let roots = vec![std::fs::canonicalize(root.path()).unwrap()];
+
+        let err = local_source(&root.path().join("clip.mp4"), &[]).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<UrlError>(), Some(UrlError::Scheme(s)) if s == "file"));
+        assert!(local_source(&root.path().join("clip.mp4"), &roots).await.is_ok());
+        let escape = root.path().join("..").join(outside.path().file_name().unwrap()).join("secret");
+        assert!(local_source(&escape, &roots).await.is_err());
+        assert!(local_source(Path::new("/etc/passwd"), &roots).await.is_err());
+        assert!(local_source(&root.path().join("missing.mp4"), &roots).await.is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(outside.path().join("secret"), root.path().join("link.mp4")).unwrap();
+            assert!(local_source(&root.path().join("link.mp4"), &roots).await.is_err());
+        }
+    }
+
+    #[test]
+    fn argv_is_identical_across_builds() {
+        // parsed twice, so the item maps hash in different orders
+        let v = json!({
+            "size": { "width": 1280, "height": 720 }, "fps": 30,
+            "trackItemsMap": {
+                "z": { "type": "video", "details": { "src": "/in/z.mp4" }, "display": { "from": 0, "to": 4000 } },
+                "a": { "type": "image", "details": { "src": "/in/a.png", "left": "10px", "top": "20px" }, "display": { "from": 1000, "to": 3000 } },
+                "m": { "type": "audio", "details": { "src": "/in/m.mp3" }, "display": { "from": 500 } },
+                "t": { "type": "text", "details": { "text": "hello", "fontUrl": "/in/f.ttf" }, "display": { "from": 0, "to": 2000 } },
+                "k": { "type": "audio", "details": { "src": "/in/k.wav" } }
+            }
+        });
+        let first = build(&design(v.clone()));
+        for _ in 0..8 {
+            let again = build(&design(v.clone()));
+            assert_eq!(first.args, again.args);
+            assert_eq!(first.first_pass, again.first_pass);
+        }
+    }
+
+    /// `-progress pipe:1` output of a 4 s render, as ffmpeg 6 writes it (N/A and a negative position
+    /// before the first frame, one step back in out_time between blocks).
+    const PROGRESS_SAMPLE: &str = "frame=0\nfps=0.00\nstream_0_0_q=0.0\nbitrate=N/A\ntotal_size=48\nout_time_us=N/A\nout_time_ms=N/A\nout_time=N/A\ndup_frames=0\ndrop_frames=0\nspeed=N/A\nprogress=continue\n\
+frame=1\nout_time_us=-23220\nout_time_ms=-23220\nout_time=-00:00:00.023220\nprogress=continue\n\
+frame=31\nbitrate=112.3kbits/s\ntotal_size=14070\nout_time_us=1000000\nout_time_ms=1000000\nout_time=00:00:01.000000\nspeed=1.98x\nprogress=continue\n\
+frame=62\nout_time_us=2066667\nout_time_ms=2066667\nout_time=00:00:02.066667\nprogress=continue\n\
+frame=61\nout_time_us=2033333\nout_time_ms=2033333\nout_time=00:00:02.033333\nprogress=continue\n\
+frame=120\nout_time_us=4000000\nout_time_ms=4000000\nout_time=00:00:04.000000\nspeed=2.01x\nprogress=end\n";
+
+    #[test]
+    fn progress_parser_reads_a_captured_sample() {
+        let mut parser = ProgressParser::new(4000);
+        let pcts: Vec<u32> = PROGRESS_SAMPLE.lines().filter_map(|l| parser.feed(l)).collect();
+        // out_time_us wins over the duplicate out_time_ms lines; N/A is skipped, negatives are 0
+        assert_eq!(pcts, vec![0, 25, 51, 50, 100, 100]);
+        assert_eq!(parser.feed("progress=end"), Some(100));
+        assert_eq!(parser.feed("speed=1.0x"), None);
+
+        // builds without out_time_us fall back to out_time_ms, which is microseconds as well
+        let mut old = ProgressParser::new(2000);
+        assert_eq!(old.feed("out_time_ms=500000"), Some(25));
+        assert_eq!(old.feed("out_time_us=1000000"), Some(50));
+        assert_eq!(old.feed("out_time_ms=1500000"), None);
+        assert_eq!(ProgressParser::new(1000).feed("out_time_us=9000000"), Some(100));
+
+        // the job's percent never goes back, even when ffmpeg's position does
+        let mut job = crate::jobs::Job::new(Path::new("/jobs"));
+        let mut seen = Vec::new();
+        for p in pcts { job.set_progress(p); seen.push(job.progress); }
+        assert_eq!(seen, vec![0, 25, 51, 51, 100, 100]);
+    }
+
+    #[test]
+    fn output_file_name_is_sanitized() {
+        let name = |n: &str| output_file_name(Some(n), OutputFormat::Mp4);
+        assert_eq!(output_file_name(None, OutputFormat::Webm).unwrap(), "output.webm");
+        // only the last path component survives, so nothing escapes the job dir
+        assert_eq!(name("../../etc/passwd").unwrap(), "passwd.mp4");
+        assert_eq!(name("..\\..\\Windows\\win.ini").unwrap(), "win.ini.mp4");
+        assert_eq!(name("/abs/clip.mp4").unwrap(), "clip.mp4");
+        assert!(name("..").is_err());
+        assert!(name("../").is_err());
+        assert!(name("...").is_err());
+        // control characters, NUL and newlines included, are dropped
+        assert_eq!(name("a\0b.mp4").unwrap(), "ab.mp4");
+        assert_eq!(name("clip\r\nX-Injected: 1").unwrap(), "clipX-Injected 1.mp4");
+        assert!(name("\0\n\t").is_err());
+        assert_eq!(name("say \"hi\" <now>?").unwrap(), "say hi now.mp4");
+        assert_eq!(name(".hidden").unwrap(), "hidden.mp4");
+        // a matching extension is kept, another output format's is an error
+        assert_eq!(name("Clip.MP4").unwrap(), "Clip.MP4");
+        assert!(name("clip.webm").is_err());
+        assert_eq!(name("clip.v2").unwrap(), "clip.v2.mp4");
+        assert_eq!(name(&"x".repeat(300)).unwrap().len(), 204);
+    }
+
+    fn graph(built: &BuiltCommand) -> &str {
+        let i = built.args.iter().position(|a| a == "-filter_complex").expect("no -filter_complex");
+        &built.args[i + 1]
+    }
+
+    /// ffmpeg's av_get_token: a token up to an unquoted, unescaped char of `term`. `\` takes the next
+    /// char literally, '...' quotes, leading and trailing unescaped whitespace is dropped. Returns the
+    /// token and the rest from the terminator on.
+    fn get_token<'a>(s: &'a str, term: &str) -> (String, &'a str) {
+        let s = s.trim_start_matches([' ', '\n', '\t', '\r']);
+        let (mut out, mut end) = (String::new(), 0);
+        let mut chars = s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            if term.contains(c) { out.truncate(end); return (out, &s[i..]); }
+            match c {
+                '\\' => { if let Some((_, n)) = chars.next() { out.push(n); } end = out.len(); }
+                '\'' => { for (_, n) in chars.by_ref() { if n == '\'' { break; } out.push(n); } end = out.len(); }
+                c => { out.push(c); if !" \n\t\r".contains(c) { end = out.len(); } }
+            }
+        }
+        out.truncate(end);
+        (out, "")
+    }
+
+    /// A filtergraph split the way ffmpeg parses it: `(filter name, its option string)` in order.
+    fn parse_graph(graph: &str) -> Vec<(String, String)> {
+        fn skip_labels(mut s: &str) -> &str { s = s.trim_start(); while s.starts_with('[') { s = s[s.find(']').unwrap() + 1..].trim_start(); } s }
+        let (mut filters, mut s) = (Vec::new(), graph);
+        loop {
+            s = skip_labels(s);
+            if s.is_empty() { return filters; }
+            let (name, rest) = get_token(s, "=,;[");
+            let (opts, rest) = match rest.strip_prefix('=') { Some(r) => get_token(r, "[],;"), None => (String::new(), rest) };
+            filters.push((name, opts));
+            s = skip_labels(rest);
+            s = s.strip_prefix([',', ';']).unwrap_or(s);
+        }
+    }
+
+    /// A filter's `key=value:...` options after the option parser's unescaping.
+    fn parse_options(mut s: &str) -> Vec<(String, String)> {
+        let mut opts = Vec::new();
+        while !s.is_empty() {
+            let (key, rest) = s.split_once('=').unwrap_or((s, ""));
+            let (value, rest) = get_token(rest, ":");
+            opts.push((key.to_string(), value));
+            s = rest.strip_prefix(':').unwrap_or(rest);
+        }
+        opts
+    }
+
+    const NASTY: &[&str] = &[
+        "it's", "'", "''", "a:b", "::", "C:\\jobs\\x.srt", "\\", "back\\\\slash", "100%", "%{pts}", "%{eif:n:d}", "{}",
+        "a;b", ";", "[in]", "x[0]y", "a,b", "line\nbreak", "\n", "trail ", " lead", "tab\t", "\r\n", "a\\:b", "'[;,:]'\\",
+        "emoji 🎬 ✓", "%{localtime}:'quoted'[x];y,z\\",
+    ];
+
+    #[test]
+    fn escaped_values_survive_both_parsers() {
+        for s in NASTY {
+            let escaped = escape_filter_value(s);
+            let (graph_level, rest) = get_token(&escaped, "[],;");
+            assert_eq!(rest, "", "{:?} ended the filter early: {}", s, escaped);
+            let (value, rest) = get_token(&graph_level, ":");
+            assert_eq!(rest, "", "{:?} ended the option early: {}", s, escaped);
+            assert_eq!(&value, s, "escaped as {}", escaped);
+        }
+    }
+
+    #[test]
+    fn drawtext_keeps_nasty_text_literal() {
+        for s in NASTY.iter().filter(|s| !s.trim().is_empty()) {
+            let d = design(json!({ "size": { "width": 1920, "height": 1080 }, "trackItems": [
+                { "id": "t1", "type": "text", "details": { "text": s, "fontUrl": "/fonts/a b:c.ttf" }, "display": { "from": 0, "to": 1000 } }
+            ] }));
+            let built = build(&d);
+            let filters = parse_graph(graph(&built));
+            let drawn: Vec<String> = filters.iter().filter(|(name, _)| name == "drawtext").map(|(_, opts)| {
+                let opts = parse_options(opts);
+                let get = |k: &str| opts.iter().find(|(key, _)| key == k).map(|(_, v)| v.clone());
+                assert_eq!(get("expansion").as_deref(), Some("none"));
+                assert_eq!(get("fontfile").as_deref(), Some("/fonts/a b:c.ttf"));
+                match (get("text"), get("textfile")) {
+                    (Some(text), None) => text,
+                    (None, Some(file)) => built.text_files.iter().find(|(p, _)| p == Path::new(&file)).expect("textfile not written").1.clone(),
+                    other => panic!("{:?}: {:?}", s, other),
+                }
+            }).collect();
+            let lines: Vec<&str> = s.split('\n').map(|l| l.trim_end_matches('\r')).filter(|l| !l.trim().is_empty()).collect();
+            assert_eq!(drawn, lines, "{:?} in {}", s, graph(&built));
+        }
+    }
+
+    /// The filter chain that produces `[label]`.
+    fn chain_for<'a>(graph: &'a str, label: &str) -> &'a str {
+        graph.split(';').find(|c| c.ends_with(&format!("[{}]", label))).unwrap_or_else(|| panic!("no [{}] in {}", label, graph))
+    }
+
+    #[test]
+    fn trimmed_audio_starts_exactly_at_display_from() {
+        let d = design(json!({ "size": { "width": 640, "height": 360 }, "trackItems": [
+            { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 8000 } },
+            { "type": "audio", "details": { "src": "/in/a.mp3" }, "trim": { "from": 2000, "to": 5000 }, "display": { "from": 3000 } }
+        ] }));
+        let built = build(&d);
+        let chain = chain_for(graph(&built), "a2");
+        // source 2-5 s, played from clip time 0, delayed to 3 s on the timeline and capped at the output end
+        assert_eq!(chain, "[2:a]volume=1,atrim=start=2:end=5,asetpts=PTS-STARTPTS,adelay=3000:all=1,atrim=end=8[a2]");
+
+        // with display.to the clip also stops at the end of its window
+        let mut d = d;
+        d.trackItems[1].display.to = Some(6000);
+        assert_eq!(chain_for(graph(&build(&d)), "a2"), "[2:a]volume=1,atrim=start=2:end=5,asetpts=PTS-STARTPTS,atrim=end=3,adelay=3000:all=1,atrim=end=8[a2]");
+
+        // a render starting inside the clip cuts its head instead of delaying it
+        d.startMs = Some(4000);
+        let chain = chain_for(graph(&build(&d)), "a2").to_string();
+        assert!(chain.contains(",atrim=start=1,asetpts=PTS-STARTPTS"), "{}", chain);
+        assert!(!chain.contains("adelay"), "{}", chain);
+        assert!(chain.ends_with(",atrim=end=4[a2]"), "{}", chain);
+
+        // starting at 0 needs no delay at all
+        d.startMs = None;
+        d.trackItems[1].display = crate::types::Trim { from: Some(0), to: None };
+        assert!(!chain_for(graph(&build(&d)), "a2").contains("adelay"));
+    }
+
+    /// Every value given to `flag`, in order.
+    fn args_of<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+        args.windows(2).filter(|w| w[0] == flag).map(|w| w[1].as_str()).collect()
+    }
+
+    /// A 640x360 design of `items`.
+    fn items(v: serde_json::Value) -> Design { design(json!({ "size": { "width": 640, "height": 360 }, "trackItems": v })) }
+
+    #[test]
+    fn layers_follow_z_index_then_item_order() {
+        let d = items(json!([
+            { "type": "image", "details": { "src": "/in/a.png", "zIndex": 5 }, "display": { "from": 0, "to": 1000 } },
+            { "type": "video", "details": { "src": "/in/b.mp4" }, "display": { "from": 0, "to": 1000 } },
+            { "type": "image", "details": { "src": "/in/c.png", "zIndex": 0 }, "display": { "from": 0, "to": 1000 } }
+        ]));
+        let built = build(&d);
+        // c (zIndex 0) at the bottom, b at its position 1, a (zIndex 5) on top
+        let g = graph(&built);
+        assert!(g.contains("[0:v][v3]overlay=") && g.contains("[m3][v2]overlay=") && g.contains("[m2][v1]overlay="), "{}", g);
+
+        // `order` reorders the items themselves
+        let d = items(json!([
+            { "type": "image", "order": 2, "details": { "src": "/in/a.png" } },
+            { "type": "image", "order": 1, "details": { "src": "/in/b.png" } }
+        ]));
+        let built = build(&d);
+        assert_eq!(args_of(&built.args, "-i")[1..], ["/in/b.png", "/in/a.png"]);
+        assert!(graph(&built).contains("[m1][v2]overlay="));
+    }
+
+    #[test]
+    fn fades_run_on_alpha_inside_the_display_window() {
+        let fade = |fade_out: u64| {
+            let d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4", "fadeInMs": 500, "fadeOutMs": fade_out }, "display": { "from": 1000, "to": 5000 } }]));
+            chain_for(graph(&build(&d)), "v1").to_string()
+        };
+        assert_eq!(fade(1000), "[1:v]format=rgba,scale=640:360,fade=t=in:st=1.000:d=0.500:alpha=1,fade=t=out:st=4.000:d=1.000:alpha=1[v1]");
+        // never longer than the window, so the fade-out still ends at display.to
+        assert!(fade(10_000).ends_with(",fade=t=out:st=1.000:d=4.000:alpha=1[v1]"));
+    }
+
+    /// Options of each drawtext pass, in graph order.
+    fn drawtexts(built: &BuiltCommand) -> Vec<HashMap<String, String>> {
+        parse_graph(graph(built)).into_iter().filter(|(name, _)| name == "drawtext").map(|(_, opts)| parse_options(&opts).into_iter().collect()).collect()
+    }
+
+    fn text_item(details: serde_json::Value) -> Design {
+        let mut d = details;
+        d["fontUrl"] = json!("/in/f.ttf");
+        items(json!([{ "id": "t1", "type": "text", "details": d, "display": { "from": 0, "to": 2000 } }]))
+    }
+
+    #[test]
+    fn text_wraps_into_lines_at_the_line_height() {
+        let d = text_item(json!({ "text": "first line\nsecond one wraps", "fontSize": 40, "maxWidth": 250, "lineHeight": "1.5", "top": "100px" }));
+        let passes = drawtexts(&build(&d));
+        // 250px at 0.55em of 40px fits 11 characters
+        assert_eq!(passes.iter().map(|o| o["text"].as_str()).collect::<Vec<_>>(), ["first line", "second one", "wraps"]);
+        assert_eq!(passes.iter().map(|o| o["y"].as_str()).collect::<Vec<_>>(), ["100", "160", "220"]);
+    }
+
+    #[test]
+    fn text_alignment_sets_the_x_expression() {
+        let x = |details: serde_json::Value| {
+            let mut details = details;
+            details["text"] = json!("hi");
+            drawtexts(&build(&text_item(details)))[0]["x"].clone()
+        };
+        assert_eq!(x(json!({ "textAlign": "center" })), "(w-text_w)/2");
+        assert_eq!(x(json!({ "textAlign": "center", "left": "100px" })), "100-text_w/2");
+        assert_eq!(x(json!({ "textAlign": "center", "left": "100px", "maxWidth": 200 })), "100+(200-text_w)/2");
+        assert_eq!(x(json!({ "textAlign": "right" })), "w-text_w");
+        assert_eq!(x(json!({ "textAlign": "right", "left": "600px" })), "600-text_w");
+        assert_eq!(x(json!({ "left": "30px" })), "30");
+    }
+
+    #[test]
+    fn text_box_takes_the_background_color_and_opacity() {
+        let d = text_item(json!({ "text": "hi", "backgroundColor": "#ff0000", "boxPadding": 8, "opacity": 50 }));
+        let opts = &drawtexts(&build(&d))[0];
+        assert_eq!((opts["box"].as_str(), opts["boxcolor"].as_str(), opts["boxborderw"].as_str()), ("1", "0xff0000@0.5", "8"));
+        assert_eq!(opts["fontcolor"], "0xffffff@0.5");
+        assert!(!drawtexts(&build(&text_item(json!({ "text": "hi" }))))[0].contains_key("box"));
+    }
+
+    /// The value following `flag` in `args`.
+    fn arg<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+        args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).map(String::as_str)
+    }
+
+    #[test]
+    fn encoder_follows_the_detected_caps() {
+        let d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4" } }]));
+        let with = |d: &Design, set: fn(&mut BackendCaps)| { let mut c = caps(); set(&mut c); build_with(d, &c).unwrap().args };
+
+        let nvenc = with(&d, |c| c.nvenc = true);
+        assert_eq!((arg(&nvenc, "-hwaccel"), arg(&nvenc, "-c:v")), (Some("cuda"), Some("h264_nvenc")));
+        assert_eq!(arg(&with(&d, |c| { c.nvenc = true; c.qsv = true; c.videotoolbox = true; c.vaapi = true; }), "-c:v"), Some("h264_nvenc"));
+        let qsv = with(&d, |c| { c.qsv = true; c.videotoolbox = true; c.vaapi = true; });
+        assert_eq!((arg(&qsv, "-hwaccel"), arg(&qsv, "-c:v")), (None, Some("h264_qsv")));
+        assert_eq!(arg(&with(&d, |c| { c.videotoolbox = true; c.vaapi = true; }), "-c:v"), Some("h264_videotoolbox"));
+        // vaapi needs the device and an upload at the end of the graph
+        let vaapi = with(&d, |c| c.vaapi = true);
+        assert_eq!(arg(&vaapi, "-c:v"), Some("h264_vaapi"));
+        assert!(arg(&vaapi, "-vaapi_device").is_some());
+        assert_eq!(arg(&vaapi, "-map"), Some("[hwout]"));
+        assert!(chain_for(&vaapi[vaapi.iter().position(|a| a == "-filter_complex").unwrap() + 1], "hwout").ends_with("format=nv12,hwupload[hwout]"));
+        let sw = with(&d, |_| {});
+        assert_eq!((arg(&sw, "-hwaccel"), arg(&sw, "-c:v")), (None, Some("libx264")));
+
+        // the hardware encoders are h264 only
+        let mut webm = d.clone();
+        webm.format = Some("webm".into());
+        let webm = with(&webm, |c| c.nvenc = true);
+        assert_eq!((arg(&webm, "-hwaccel"), arg(&webm, "-c:v")), (None, Some("libvpx-vp9")));
+    }
+
+    /// The item chain `[v1]` of a design holding one item of `kind` with `details`.
+    fn item_chain(kind: &str, details: serde_json::Value) -> String {
+        let built = build(&items(json!([{ "type": kind, "details": details, "display": { "from": 0, "to": 4000 } }])));
+        chain_for(graph(&built), "v1").to_string()
+    }
+
+    #[test]
+    fn flips_come_after_scale_and_before_rotate() {
+        let chain = |flips: serde_json::Value| {
+            let mut details = json!({ "src": "/in/i.png", "rotate": "90deg" });
+            details.as_object_mut().unwrap().extend(flips.as_object().unwrap().clone());
+            item_chain("image", details)
+        };
+        assert_eq!(chain(json!({ "flipX": true })), "[1:v]format=rgba,scale=640:360,hflip,rotate=90.000000*PI/180[v1]");
+        assert_eq!(chain(json!({ "flipY": true })), "[1:v]format=rgba,scale=640:360,vflip,rotate=90.000000*PI/180[v1]");
+        assert_eq!(chain(json!({ "flipX": true, "flipY": true })), "[1:v]format=rgba,scale=640:360,hflip,vflip,rotate=90.000000*PI/180[v1]");
+        assert_eq!(chain(json!({ "flipX": false })), "[1:v]format=rgba,scale=640:360,rotate=90.000000*PI/180[v1]");
+    }
+
+    /// `value` and the `n` args before it.
+    fn before<'a>(args: &'a [String], value: &str, n: usize) -> &'a [String] {
+        let i = args.iter().position(|a| a == value).unwrap_or_else(|| panic!("no {} in {:?}", value, args));
+        &args[i - n..=i]
+    }
+
+    #[test]
+    fn looping_video_repeats_its_input() {
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4", "loop": true }, "display": { "from": 0, "to": 6000 } },
+            { "type": "image", "details": { "src": "/in/i.png" } },
+            { "type": "video", "details": { "src": "/in/w.mp4" } }
+        ]));
+        let built = build(&d);
+        assert_eq!(before(&built.args, "/in/v.mp4", 5), ["-stream_loop", "-1", "-t", "6.000", "-i", "/in/v.mp4"]);
+        assert_eq!(before(&built.args, "/in/i.png", 5), ["-loop", "1", "-t", "6.000", "-i", "/in/i.png"]);
+        assert_eq!(before(&built.args, "/in/w.mp4", 2), ["/in/i.png", "-i", "/in/w.mp4"]);
+        assert_eq!(args_of(&built.args, "-stream_loop").len(), 1);
+        // visibility is still the display window
+        assert!(chain_for(graph(&built), "m1").ends_with(":enable='between(t,0.000,6.000)'[m1]"));
+    }
+
+    #[test]
+    fn two_keyframes_pan_linearly() {
+        let d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4", "keyframes": [
+            { "timeMs": 0, "left": 0, "top": 0 }, { "timeMs": 2000, "left": 100, "top": 50 }
+        ] }, "display": { "from": 1000, "to": 4000 } }]));
+        // keyframe times count from display.from; the value holds before the first and after the last
+        assert_eq!(chain_for(graph(&build(&d)), "m1"), "[0:v][v1]overlay=\
+            x='if(lt(t,1.000),0.0000,if(lt(t,3.000),0.0000+(100.0000)*(t-1.000)/2.000,100.0000))':\
+            y='if(lt(t,1.000),0.0000,if(lt(t,3.000),0.0000+(50.0000)*(t-1.000)/2.000,50.0000))':format=auto:enable='between(t,1.000,4.000)'[m1]");
+    }
+
+    #[test]
+    fn render_range_shifts_every_window() {
+        let mut d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 20000 } },
+            { "type": "image", "details": { "src": "/in/i.png" }, "display": { "from": 6000, "to": 8000 } },
+            { "type": "audio", "details": { "src": "/in/a.mp3" }, "display": { "from": 7000 } }
+        ]));
+        d.startMs = Some(5000);
+        d.endMs = Some(10_000);
+        let built = build(&d);
+        assert_eq!(args_of(&built.args, "-i")[0], "color=c=black:s=640x360:r=30:d=5");
+        assert_eq!(before(&built.args, "/in/v.mp4", 3), ["-ss", "5.000", "-i", "/in/v.mp4"]);
+        assert_eq!(before(&built.args, "/in/i.png", 5), ["-loop", "1", "-t", "5.000", "-i", "/in/i.png"]);
+        let g = graph(&built);
+        assert!(chain_for(g, "m2").ends_with(":enable='between(t,1.000,3.000)'[m2]"), "{}", g);
+        assert!(chain_for(g, "a3").ends_with(",adelay=2000:all=1,atrim=end=5[a3]"), "{}", g);
+    }
+
+    #[test]
+    fn border_radius_masks_between_scale_and_opacity() {
+        let chain = item_chain("image", json!({ "src": "/in/i.png", "width": 200, "height": 200, "borderRadius": "50%", "opacity": 80 }));
+        let at = |s: &str| chain.find(s).unwrap_or_else(|| panic!("no {} in {}", s, chain));
+        assert!(at(",scale=200:200") < at(",geq=") && at(",geq=") < at(",colorchannelmixer=aa=0.8"), "{}", chain);
+        assert!(chain.contains("a='if(gt(abs(X-W/2)-(W/2-min(min(W,H)*0.5,min(W,H)/2)),0)*"), "{}", chain);
+        assert!(item_chain("image", json!({ "src": "/in/i.png", "borderRadius": "24px" })).contains("min(24,min(W,H)/2)"));
+        assert!(!item_chain("image", json!({ "src": "/in/i.png", "borderRadius": "0" })).contains("geq="));
+    }
+
+    #[test]
+    fn shadows_are_drawn_under_the_item() {
+        let d = items(json!([{ "type": "image", "details": { "src": "/in/i.png", "shadowColor": "#000000", "shadowX": 10, "shadowY": 20 }, "display": { "from": 0, "to": 4000 } }]));
+        let built = build(&d);
+        let g = graph(&built);
+        assert_eq!(chain_for(g, "s1"), "[1:v]format=rgba,scale=640:360,split[v1][s1]");
+        assert_eq!(chain_for(g, "sh1"), "[s1]lutrgb=r=0:g=0:b=0,colorchannelmixer=aa=1[sh1]");
+        assert_eq!(chain_for(g, "ms1"), "[0:v][sh1]overlay=x='0+(10)':y='0+(20)':format=auto:enable='between(t,0.000,4.000)'[ms1]");
+        assert_eq!(chain_for(g, "m1"), "[ms1][v1]overlay=x=0:y=0:format=auto:enable='between(t,0.000,4.000)'[m1]");
+
+        // a blurred shadow is padded first and moved back by the padding
+        let d = items(json!([{ "type": "image", "details": { "src": "/in/i.png", "shadowColor": "#000000", "shadowX": 10, "shadowBlur": 4 } }]));
+        let built = build(&d);
+        assert!(chain_for(graph(&built), "sh1").ends_with(",pad=w=iw+16:h=ih+16:x=8:y=8:color=black@0,boxblur=4:1[sh1]"));
+        assert!(chain_for(graph(&built), "ms1").contains("overlay=x='0+(2)':y='0+(-8)'"));
+
+        let opts = &drawtexts(&build(&text_item(json!({ "text": "hi", "shadowColor": "rgba(0,0,0,0.5)", "shadowX": 2, "shadowY": 3 }))))[0];
+        assert_eq!((opts["shadowcolor"].as_str(), opts["shadowx"].as_str(), opts["shadowy"].as_str()), ("0x000000@0.5", "2", "3"));
+    }
+
+    #[test]
+    fn audio_only_output_maps_no_video() {
+        let mut d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 3000 } },
+            { "type": "audio", "details": { "src": "/in/a.mp3" } }
+        ]));
+        d.format = Some("mp3".into());
+        let built = build(&d);
+        // no canvas: the assets are inputs 0 and 1
+        assert_eq!(args_of(&built.args, "-i"), ["/in/v.mp4", "/in/a.mp3"]);
+        assert!(!built.args.iter().any(|a| a == "lavfi" || a == "-c:v" || a == "-r"));
+        assert!(graph(&built).starts_with("[1:a]volume=1,"));
+        assert_eq!(args_of(&built.args, "-map"), ["[aout]"]);
+        assert!(built.args.iter().any(|a| a == "-vn"));
+        assert_eq!(arg(&built.args, "-c:a"), Some("libmp3lame"));
+
+        // a design of audio items alone becomes m4a without a format
+        let built = build(&items(json!([{ "type": "audio", "details": { "src": "/in/a.mp3" } }])));
+        assert_eq!(built.output_path, Path::new("/jobs/test/output.m4a"));
+        assert_eq!(args_of(&built.args, "-map"), ["[aout]"]);
+    }
+
+    #[test]
+    fn chroma_key_runs_before_scaling() {
+        let chain = item_chain("video", json!({ "src": "/in/v.mp4", "chromaKey": { "color": "#00ff00", "similarity": 0.2 } }));
+        assert_eq!(chain, "[1:v]format=rgba,colorkey=color=0x00ff00:similarity=0.2:blend=0.1,scale=640:360[v1]");
+        assert!(item_chain("video", json!({ "src": "/in/v.mp4", "chromaKey": { "color": "rgb(0,0,255)", "blend": 0 } })).contains("colorkey=color=0x0000ff:similarity=0.3:blend=0,"));
+    }
+
+    #[test]
+    fn alpha_output_keeps_a_clear_canvas() {
+        let d = |format: &str| {
+            let mut d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } }]));
+            d.format = Some(format.into());
+            d.alpha = Some(true);
+            d
+        };
+        // nvenc is there, but ProRes doesn't go through it
+        let nvenc = BackendCaps { nvenc: true, ..caps() };
+        let mov = build_with(&d("mov"), &nvenc).unwrap().args;
+        assert_eq!(args_of(&mov, "-i")[0], "color=c=black@0.0:s=640x360:r=30:d=4,format=rgba");
+        assert_eq!((arg(&mov, "-c:v"), arg(&mov, "-profile:v"), arg(&mov, "-pix_fmt")), (Some("prores_ks"), Some("4444"), Some("yuva444p10le")));
+        assert_eq!(arg(&mov, "-hwaccel"), None);
+        let webm = build(&d("webm")).args;
+        assert_eq!((arg(&webm, "-c:v"), arg(&webm, "-pix_fmt")), (Some("libvpx-vp9"), Some("yuva420p")));
+        assert!(build_with(&d("mp4"), &caps()).is_err());
+
+        let mut opaque = d("mov");
+        opaque.alpha = None;
+        let opaque = build(&opaque).args;
+        assert_eq!(args_of(&opaque, "-i")[0], "color=c=black:s=640x360:r=30:d=4");
+        assert_eq!((arg(&opaque, "-c:v"), arg(&opaque, "-pix_fmt")), (Some("libx264"), Some("yuv420p")));
+    }
+
+    #[test]
+    fn webm_at_a_bitrate_encodes_in_two_passes() {
+        let d = |format: &str, bitrate: Option<&str>| {
+            let mut d = items(json!([
+                { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } },
+                { "type": "audio", "details": { "src": "/in/m.mp3" }, "display": { "from": 0, "to": 4000 } }
+            ]));
+            d.format = Some(format.into());
+            d.quality.videoBitrate = bitrate.map(String::from);
+            d
+        };
+        let built = build(&d("webm", Some("2M")));
+        let pass1 = built.first_pass.expect("a target bitrate on webm runs an analysis pass");
+        let passlog = "/jobs/test/ffmpeg2pass";
+
+        // the analysis pass encodes video only and throws the result away
+        assert_eq!((arg(&pass1, "-c:v"), arg(&pass1, "-b:v"), arg(&pass1, "-crf")), (Some("libvpx-vp9"), Some("2M"), None));
+        assert_eq!((arg(&pass1, "-pass"), arg(&pass1, "-passlogfile")), (Some("1"), Some(passlog)));
+        assert_eq!(args_of(&pass1, "-f").last(), Some(&"null"));
+        assert!(pass1.iter().any(|a| a == "-an"));
+        assert_eq!(arg(&pass1, "-c:a"), None);
+        assert_eq!(args_of(&pass1, "-map").len(), 1);
+        // same video graph, without the audio chains after it
+        let (graph1, graph2) = (arg(&pass1, "-filter_complex").unwrap(), arg(&built.args, "-filter_complex").unwrap());
+        assert!(graph2.starts_with(graph1) && graph2.len() > graph1.len());
+        assert_eq!(pass1.last().map(String::as_str), Some("/dev/null"));
+        assert_eq!(args_of(&pass1, "-i"), args_of(&built.args, "-i"));
+
+        // the real pass reads the same log and keeps the audio
+        let pass2 = &built.args;
+        assert_eq!((arg(pass2, "-c:v"), arg(pass2, "-b:v"), arg(pass2, "-crf")), (Some("libvpx-vp9"), Some("2M"), None));
+        assert_eq!((arg(pass2, "-pass"), arg(pass2, "-passlogfile")), (Some("2"), Some(passlog)));
+        assert_eq!(arg(pass2, "-c:a"), Some("libopus"));
+        assert!(pass2.last().unwrap().ends_with(".webm"));
+
+        // constant quality, or a bitrate in another container, is a single pass
+        let crf = build(&d("webm", None));
+        assert!(crf.first_pass.is_none());
+        assert_eq!((arg(&crf.args, "-crf"), arg(&crf.args, "-pass")), (Some("32"), None));
+        let mp4 = build(&d("mp4", Some("2M")));
+        assert!(mp4.first_pass.is_none());
+        assert_eq!((arg(&mp4.args, "-b:v"), arg(&mp4.args, "-pass")), (Some("2M"), None));
+    }
+
+    #[test]
+    fn ken_burns_spans_the_display_window() {
+        let mut d = items(json!([{ "type": "image", "details": { "src": "/in/i.png", "kenBurns": { "startZoom": 1, "endZoom": 1.5 } }, "display": { "from": 1000, "to": 5000 } }]));
+        d.fps = Some(25);
+        let built = build(&d);
+        let chain = chain_for(graph(&built), "v1");
+        // 4 s at 25 fps: 100 frames, moved to start at 1 s
+        assert!(chain.contains(",zoompan=z='(1+(0.5)*min(on/99,1))':"), "{}", chain);
+        assert!(chain.contains(":d=100:s=640x360:fps=25,setpts=PTS-STARTPTS+(1.000)/TB,"), "{}", chain);
+        // a single frame in, zoompan makes the rest
+        assert!(!built.args.iter().any(|a| a == "-loop"));
+    }
+
+    #[test]
+    fn css_colors_become_ffmpeg_colors() {
+        assert_eq!(ff_color("#f00", 1.0), "0xff0000@1");
+        assert_eq!(ff_color("#112233", 0.5), "0x112233@0.5");
+        assert_eq!(ff_color("#0000ff00", 1.0), "0x0000ff@0");
+        assert_eq!(ff_color("rgba(255, 128, 0, 0.5)", 0.5), "0xff8000@0.25");
+        assert_eq!(ff_color("rgb(1,2,3)", 1.0), "0x010203@1");
+        assert_eq!(ff_color("Orange", 1.0), "0xffa500@1");
+        assert_eq!(ff_color("transparent", 1.0), "0x000000@0");
+        // names ffmpeg may know are left to it; garbage is black
+        assert_eq!(ff_color("RebeccaPurple", 0.5), "rebeccapurple@0.5");
+        assert_eq!(ff_color("#zzz", 1.0), "0x000000@1");
+        assert_eq!(ff_color("rgb(1,2)", 2.0), "0x000000@1");
+
+        let opts = &drawtexts(&build(&text_item(json!({ "text": "hi", "color": "rgba(255,255,255,0.5)", "opacity": 50 }))))[0];
+        assert_eq!(opts["fontcolor"], "0xffffff@0.25");
+    }
+
+    #[test]
+    fn strokes_stack_widest_first_under_the_fill() {
+        let d = text_item(json!({ "text": "hi", "borderWidth": 4, "borderColor": "blue", "strokes": [
+            { "color": "#000000", "width": 2 }, { "color": "#ff0000", "width": 8 }
+        ] }));
+        let passes = drawtexts(&build(&d));
+        let col = |k: &str| passes.iter().map(|o| o[k].as_str()).collect::<Vec<_>>();
+        assert_eq!(col("borderw"), ["8", "4", "2", "0"]);
+        assert_eq!(col("fontcolor"), ["0xff0000@1", "0x0000ff@1", "0x000000@1", "0xffffff@1"]);
+
+        // a lone borderWidth stays drawtext's own outline
+        let passes = drawtexts(&build(&text_item(json!({ "text": "hi", "borderWidth": 4, "borderColor": "blue" }))));
+        assert_eq!(passes.len(), 1);
+        assert_eq!((passes[0]["borderw"].as_str(), passes[0]["bordercolor"].as_str()), ("4", "0x0000ff@1"));
+    }
+
+    #[test]
+    fn background_sets_the_canvas() {
+        let with = |bg: &str| {
+            let mut d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } }]));
+            d.background = Some(bg.into());
+            build(&d)
+        };
+        assert_eq!(args_of(&with("#336699").args, "-i")[0], "color=c=0x336699@1:s=640x360:r=30:d=4");
+        let built = with("/in/bg.jpg");
+        assert_eq!(before(&built.args, "/in/bg.jpg", 7), ["-loop", "1", "-framerate", "30", "-t", "4.000", "-i", "/in/bg.jpg"]);
+        assert_eq!(args_of(&built.args, "-i"), ["/in/bg.jpg", "/in/v.mp4"]);
+        let g = graph(&built);
+        assert!(g.starts_with("[0:v]scale=640:360:force_original_aspect_ratio=increase,crop=640:360,setsar=1,format=rgba[bg];"), "{}", g);
+        assert!(g.contains("[bg][v1]overlay="), "{}", g);
+    }
+
+    #[test]
+    fn fit_pads_or_crops_before_the_scale() {
+        let chain = |fit: Option<&str>| item_chain("image", json!({ "src": "/in/i.png", "width": 320, "height": 320, "fit": fit }));
+        assert_eq!(chain(Some("contain")), "[1:v]format=rgba,scale=320:320:force_original_aspect_ratio=decrease,pad=320:320:(ow-iw)/2:(oh-ih)/2:color=black@0,scale=320:320[v1]");
+        assert_eq!(chain(Some("cover")), "[1:v]format=rgba,scale=320:320:force_original_aspect_ratio=increase,crop=320:320,scale=320:320[v1]");
+        for stretch in [None, Some("fill"), Some("stretch")] { assert_eq!(chain(stretch), "[1:v]format=rgba,scale=320:320[v1]"); }
+    }
+
+    #[test]
+    fn speed_retimes_video_and_audio() {
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4", "speed": 2 }, "trim": { "from": 0, "to": 8000 }, "display": { "from": 1000 } },
+            { "type": "audio", "details": { "src": "/in/a.mp3", "speed": 2 }, "trim": { "from": 0, "to": 6000 } }
+        ]));
+        // 8 s of source at twice the speed from 1 s
+        assert_eq!(compute_duration_ms(&d), 5000);
+        let built = build(&d);
+        let g = graph(&built);
+        assert!(chain_for(g, "v1").starts_with("[1:v]setpts=1.000/TB+(PTS-1.000/TB)/2,format=rgba,"), "{}", g);
+        assert!(chain_for(g, "m1").ends_with(":enable='between(t,1.000,5.000)'[m1]"), "{}", g);
+        assert!(chain_for(g, "a2").contains(",asetpts=PTS-STARTPTS,atempo=2,atrim="), "{}", g);
+
+        // slow motion: 2 s of source at half speed fills 4 s
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4", "speed": 0.5 }, "trim": { "from": 0, "to": 2000 } },
+            { "type": "audio", "details": { "src": "/in/a.mp3", "speed": 0.5 }, "trim": { "from": 0, "to": 2000 } }
+        ]));
+        assert_eq!(compute_duration_ms(&d), 4000);
+        let built = build(&d);
+        let g = graph(&built);
+        assert!(chain_for(g, "v1").starts_with("[1:v]setpts=0.000/TB+(PTS-0.000/TB)/0.5,format=rgba,"), "{}", g);
+        assert!(chain_for(g, "m1").ends_with(":enable='between(t,0.000,4.000)'[m1]"), "{}", g);
+        assert!(chain_for(g, "a2").contains(",asetpts=PTS-STARTPTS,atempo=0.5,atrim=end=4"), "{}", g);
+
+        // atempo only takes 0.5-2, so bigger changes are chained
+        assert_eq!(atempo_chain(3.0), "atempo=2,atempo=1.5");
+        assert_eq!(atempo_chain(0.25), "atempo=0.5,atempo=0.5");
+        assert_eq!(atempo_chain(1.25), "atempo=1.25");
+    }
+
+    #[test]
+    fn watermark_is_the_top_layer() {
+        let d = design(json!({ "size": { "width": 640, "height": 360 },
+            "watermark": { "src": "/in/logo.png", "position": "top-right", "opacity": 50 },
+            "trackItems": [
+                { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } },
+                { "id": "t1", "type": "text", "details": { "text": "hi", "fontUrl": "/in/f.ttf" } }
+            ] }));
+        let built = build(&d);
+        assert_eq!(args_of(&built.args, "-i").last(), Some(&"/in/logo.png"));
+        assert_eq!(before(&built.args, "/in/logo.png", 7), ["-loop", "1", "-framerate", "30", "-t", "4.000", "-i", "/in/logo.png"]);
+        // after the text, and what gets encoded
+        assert!(graph(&built).ends_with("[2:v]format=rgba,colorchannelmixer=aa=0.5[wm];[txtt1][wm]overlay=x=W-w-24:y=24:format=auto[wmout]"));
+        assert_eq!(arg(&built.args, "-map"), Some("[wmout]"));
+    }
+
+    #[test]
+    fn on_end_holds_loops_or_blanks() {
+        let d = |on_end: &str| items(json!([{ "type": "video", "details": { "src": "/in/v.mp4", "onEnd": on_end }, "display": { "from": 1000, "to": 6000 } }]));
+        let hold = build(&d("hold"));
+        assert_eq!(chain_for(graph(&hold), "v1"), "[1:v]format=rgba,tpad=stop_mode=clone:stop_duration=6.000,scale=640:360[v1]");
+        let blank = build(&d("blank"));
+        assert!(chain_for(graph(&blank), "m1").contains(":format=auto:eof_action=pass:enable="));
+        assert!(!graph(&blank).contains("tpad"));
+        assert_eq!(before(&build(&d("loop")).args, "/in/v.mp4", 5)[..2], ["-stream_loop", "-1"]);
+        let default = build(&items(json!([{ "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 1000, "to": 6000 } }])));
+        assert!(!graph(&default).contains("tpad") && !graph(&default).contains("eof_action"));
+    }
+
+    #[test]
+    fn blur_and_sharpen_follow_rotate_and_grade() {
+        let chain = item_chain("image", json!({ "src": "/in/i.png", "width": 200, "height": 100, "rotate": "10deg", "brightness": 120, "blur": 3, "sharpen": 1, "opacity": 50 }));
+        assert_eq!(chain, "[1:v]format=rgba,scale=200:100,rotate=10.000000*PI/180,eq=brightness=0.2,gblur=sigma=3,unsharp=lx=5:ly=5:la=1:cx=5:cy=5:ca=0,colorchannelmixer=aa=0.5[v1]");
+        let clamped = item_chain("image", json!({ "src": "/in/i.png", "blur": 500, "sharpen": 9 }));
+        assert!(clamped.contains(",gblur=sigma=100,unsharp=lx=5:ly=5:la=5:"), "{}", clamped);
+        assert!(!item_chain("image", json!({ "src": "/in/i.png", "blur": 0 })).contains("gblur"));
+    }
+
+    #[test]
+    fn eq_carries_only_the_non_neutral_grades() {
+        let chain = |details: serde_json::Value| {
+            let mut details = details;
+            details["src"] = json!("/in/i.png");
+            item_chain("image", details)
+        };
+        // all four in one filter
+        let all = chain(json!({ "brightness": 120, "contrast": 150, "saturation": 0, "gamma": 1.8 }));
+        assert!(all.ends_with(",eq=brightness=0.2:contrast=1.5:saturation=0:gamma=1.8[v1]"), "{}", all);
+        assert_eq!(all.matches("eq=").count(), 1);
+        assert!(chain(json!({ "brightness": 120, "contrast": 150, "saturation": 0, "gamma": 1.0 })).ends_with(",eq=brightness=0.2:contrast=1.5:saturation=0[v1]"));
+        assert!(chain(json!({ "gamma": 2 })).ends_with(",eq=gamma=2[v1]"));
+        assert!(!chain(json!({ "brightness": 100, "contrast": 100, "saturation": 100 })).contains("eq="));
+    }
+
+    #[test]
+    fn percentages_resolve_against_the_output() {
+        let d = design(json!({ "size": { "width": 1080, "height": 1920 }, "trackItems": [
+            { "type": "image", "details": { "src": "/in/i.png", "width": "50%", "height": "10%", "left": "50%", "top": "25%" } },
+            { "id": "t1", "type": "text", "details": { "text": "hi", "fontUrl": "/in/f.ttf", "left": "10%", "top": "12em" } }
+        ] }));
+        let built = build(&d);
+        assert!(chain_for(graph(&built), "v1").ends_with(",scale=540:192[v1]"));
+        assert!(chain_for(graph(&built), "m1").contains("overlay=x=540:y=480:"));
+        // unknown units are 0
+        let opts = &drawtexts(&built)[0];
+        assert_eq!((opts["x"].as_str(), opts["y"].as_str()), ("108", "0"));
+        assert_eq!(length_px(&Length::Px(12.5), 100), Some(12.5));
+        assert_eq!(length_px(&Length::Css("12px".into()), 100), Some(12.0));
+        assert_eq!(length_px(&Length::Css("abc".into()), 100), None);
+    }
+
+    #[test]
+    fn resolution_presets_size_the_canvas() {
+        let size = |n: &str| resolution_preset(n).map(|s| (s.width, s.height));
+        assert_eq!(size("1080p"), Some((1920, 1080)));
+        assert_eq!(size(" Vertical-1080 "), Some((1080, 1920)));
+        assert_eq!(size("4K"), Some((3840, 2160)));
+        assert_eq!(size("8k"), None);
+        let mut d = items(json!([{ "type": "image", "details": { "src": "/in/i.png" } }]));
+        d.size = resolution_preset("square-1080");
+        assert!(args_of(&build(&d).args, "-i")[0].starts_with("color=c=black:s=1080x1080:"));
+    }
+
+    #[test]
+    fn animated_images_play_as_video() {
+        let mut d = items(json!([
+            { "type": "image", "details": { "src": "/in/s.gif" }, "display": { "from": 0, "to": 4000 } },
+            { "type": "image", "details": { "src": "/in/a.png" } },
+            { "type": "image", "details": { "src": "/in/p.png" } }
+        ]));
+        d.trackItems[1].probe = Some(FfprobeInfo { video_codec: Some("apng".into()), ..Default::default() });
+        let built = build(&d);
+        assert_eq!(before(&built.args, "/in/s.gif", 7), ["-f", "gif", "-ignore_loop", "0", "-t", "4.000", "-i", "/in/s.gif"]);
+        assert_eq!(before(&built.args, "/in/a.png", 7), ["-f", "apng", "-ignore_loop", "0", "-t", "4.000", "-i", "/in/a.png"]);
+        assert_eq!(before(&built.args, "/in/p.png", 5), ["-loop", "1", "-t", "4.000", "-i", "/in/p.png"]);
+    }
+
+    #[test]
+    fn fills_are_generated_inputs_after_the_assets() {
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } },
+            { "type": "image", "details": { "fill": "#ff0000", "width": 200, "height": 100, "left": "10px", "top": "20px" } },
+            { "type": "image", "details": { "fill": { "stops": ["#ff0000", "#0000ff"] }, "width": 200, "height": 100 } }
+        ]));
+        let built = build(&d);
+        assert_eq!(args_of(&built.args, "-i")[1..], [
+            "/in/v.mp4",
+            "color=c=0xff0000@1:s=200x100:r=30:d=4.000",
+            "gradients=s=200x100:r=30:d=4.000:speed=0:n=2:x0=100:y0=0:x1=100:y1=100:c0=0xff0000@1:c1=0x0000ff@1",
+        ]);
+        let g = graph(&built);
+        assert_eq!(chain_for(g, "v2"), "[2:v]format=rgba,scale=200:100[v2]");
+        assert!(chain_for(g, "m2").starts_with("[m1][v2]overlay=x=10:y=20:"), "{}", g);
+        assert!(chain_for(g, "m3").starts_with("[m2][v3]overlay=x=0:y=0:"), "{}", g);
+    }
+
+    #[test]
+    fn mute_drops_every_audio_map() {
+        let mut d = items(json!([
+            { "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } },
+            { "type": "audio", "details": { "src": "/in/a.mp3" } }
+        ]));
+        d.mute = Some(true);
+        let built = build(&d);
+        assert!(built.args.iter().any(|a| a == "-an"));
+        assert_eq!(args_of(&built.args, "-map"), ["[m1]"]);
+        assert!(!built.args.iter().any(|a| a == "/in/a.mp3" || a == "0:a?" || a == "-c:a"));
+        assert!(!graph(&built).contains(":a]") && !graph(&built).contains("aout"));
+    }
+
+    #[test]
+    fn complex_scripts_go_through_libass() {
+        let built = build(&text_item(json!({ "text": "مرحبا بالعالم" })));
+        let filters = parse_graph(graph(&built));
+        assert!(!filters.iter().any(|(name, _)| name == "drawtext"));
+        let (_, opts) = filters.iter().find(|(name, _)| name == "ass").expect("no ass filter");
+        let opts: HashMap<String, String> = parse_options(opts).into_iter().collect();
+        assert_eq!((opts["filename"].as_str(), opts["fontsdir"].as_str()), ("/jobs/test/text0.ass", "/in"));
+        assert_eq!(built.text_files.len(), 1);
+        assert_eq!(built.text_files[0].0, Path::new("/jobs/test/text0.ass"));
+        assert!(built.text_files[0].1.contains("Dialogue: 0,0:00:00.00,0:00:02.00,Default,,0,0,0,,{\\an7\\pos(0,0)}مرحبا بالعالم\n"));
+
+        // details.shaping overrides the detection either way
+        assert!(parse_graph(graph(&build(&text_item(json!({ "text": "مرحبا", "shaping": false }))))).iter().any(|(n, _)| n == "drawtext"));
+        assert!(parse_graph(graph(&build(&text_item(json!({ "text": "hello", "shaping": true }))))).iter().any(|(n, _)| n == "ass"));
+    }
+
+    #[test]
+    fn minterpolate_only_when_asked_and_needed() {
+        let chain = |interpolate: bool, source_fps: f64, kind: &str| {
+            let mut d = items(json!([{ "type": kind, "details": { "src": "/in/v.mp4" }, "display": { "from": 0, "to": 4000 } }]));
+            d.fps = Some(60);
+            d.interpolate = Some(interpolate);
+            d.trackItems[0].probe = Some(FfprobeInfo { fps: Some(source_fps), ..Default::default() });
+            chain_for(graph(&build(&d)), "v1").to_string()
+        };
+        assert!(chain(true, 24.0, "video").starts_with("[1:v]minterpolate=fps=60:mi_mode=mci:mc_mode=aobmc:me_mode=bidir:vsbmc=1,format=rgba,"));
+        assert!(!chain(false, 24.0, "video").contains("minterpolate"));
+        assert!(!chain(true, 60.0, "video").contains("minterpolate"));
+        assert!(!chain(true, 24.0, "image").contains("minterpolate"));
+    }
+
+    #[test]
+    fn frame_sequences_use_the_image2_numbering() {
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/f_%04d.png", "sequence": { "count": 48, "start": 1, "fps": 24 } } },
+            { "type": "video", "details": { "src": "/in/g_%d.png", "sequence": { "count": 10 } } }
+        ]));
+        let built = build(&d);
+        assert_eq!(before(&built.args, "/in/f_%04d.png", 5), ["-framerate", "24", "-start_number", "1", "-i", "/in/f_%04d.png"]);
+        assert_eq!(before(&built.args, "/in/g_%d.png", 5), ["-framerate", "30", "-start_number", "0", "-i", "/in/g_%d.png"]);
+    }
+
+    #[test]
+    fn odd_sizes_round_up_to_even() {
+        let mut d = items(json!([
+            { "type": "image", "details": { "src": "/in/i.png", "width": 101, "height": 51 } },
+            { "type": "image", "details": { "src": "/in/j.png", "width": 101, "height": 51, "transform": "scale(1.5)" } }
+        ]));
+        d.size = Some(Size { width: 1081, height: 1921 });
+        let built = build(&d);
+        assert!(args_of(&built.args, "-i")[0].starts_with("color=c=black:s=1082x1922:"));
+        assert!(chain_for(graph(&built), "v1").ends_with(",scale=102:52[v1]"));
+        assert!(chain_for(graph(&built), "v2").ends_with(",scale=152:76[v2]"));
+    }
+
+    #[test]
+    fn draft_lowers_size_fps_and_effort() {
+        let d = design(json!({ "size": { "width": 1280, "height": 720 }, "fps": 60, "draft": true, "quality": { "crf": 18, "preset": "slow" }, "trackItems": [
+            { "type": "video", "details": { "src": "/in/v.mp4", "blur": 5 }, "display": { "from": 0, "to": 4000 } }
+        ] }));
+        let built = build(&d);
+        assert_eq!(arg(&built.args, "-r"), Some("15"));
+        assert_eq!(args_of(&built.args, "-i")[0], "color=c=black:s=1280x720:r=15:d=4");
+        let g = graph(&built);
+        // composed at full size, halved just before encoding
+        assert!(chain_for(g, "v1").ends_with(",scale=1280:720[v1]"), "{}", g);
+        assert!(!g.contains("gblur"), "{}", g);
+        assert_eq!(chain_for(g, "scaled"), "[m1]scale=640:360:force_original_aspect_ratio=decrease:force_divisible_by=2,pad=640:360:-1:-1:color=black@0[scaled]");
+        assert_eq!(arg(&built.args, "-map"), Some("[scaled]"));
+        assert_eq!((arg(&built.args, "-preset"), arg(&built.args, "-crf")), (Some("ultrafast"), Some("30")));
+    }
+
+    #[test]
+    fn hdr_sources_are_tone_mapped() {
+        let build_hdr = |tone_map: Option<&str>, transfer: &str, color_space: Option<&str>| {
+            let mut d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4", "colorSpace": color_space } }]));
+            d.toneMap = tone_map.map(String::from);
+            d.trackItems[0].probe = Some(FfprobeInfo { color_transfer: Some(transfer.into()), ..Default::default() });
+            build(&d)
+        };
+        let built = build_hdr(None, "smpte2084", None);
+        assert!(chain_for(graph(&built), "v1").starts_with("[1:v]zscale=tin=smpte2084:min=bt2020nc:pin=bt2020:t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=rgba,"));
+        assert_eq!((arg(&built.args, "-colorspace"), arg(&built.args, "-color_trc")), (Some("bt709"), Some("bt709")));
+        // details.colorSpace beats the probe
+        let hlg = build_hdr(Some("mobius"), "bt709", Some("bt2020-hlg"));
+        assert!(chain_for(graph(&hlg), "v1").contains("zscale=tin=arib-std-b67:") && chain_for(graph(&hlg), "v1").contains("tonemap=tonemap=mobius:"));
+        for sdr in [build_hdr(None, "bt709", None), build_hdr(Some("none"), "smpte2084", None)] {
+            assert!(!graph(&sdr).contains("zscale") && arg(&sdr.args, "-colorspace").is_none());
+        }
+    }
+
+    #[test]
+    fn duration_and_fps_fall_back_to_the_defaults() {
+        let mut d = items(json!([{ "type": "image", "details": { "src": "/in/i.png" } }]));
+        let built = build(&d);
+        assert_eq!(args_of(&built.args, "-i")[0], "color=c=black:s=640x360:r=30:d=10");
+        assert_eq!(arg(&built.args, "-r"), Some("30"));
+        d.defaultDurationMs = Some(4000);
+        assert_eq!(args_of(&build(&d).args, "-i")[0], "color=c=black:s=640x360:r=30:d=4");
+
+        // an item with a start but no end lasts as long as its probed source
+        let mut d = items(json!([{ "type": "video", "details": { "src": "/in/v.mp4" }, "display": { "from": 2000 } }]));
+        d.trackItems[0].probe = Some(FfprobeInfo { duration_ms: Some(3000), ..Default::default() });
+        assert_eq!(compute_duration_ms(&d), 5000);
+        assert!(chain_for(graph(&build(&d)), "m1").ends_with(":enable='between(t,2.000,5.000)'[m1]"));
+    }
+
+    #[test]
+    fn blend_modes_replace_the_plain_overlay() {
+        let g = |mode: &str| {
+            let built = build(&items(json!([{ "type": "image", "details": { "src": "/in/i.png", "blendMode": mode }, "display": { "from": 0, "to": 4000 } }])));
+            graph(&built).to_string()
+        };
+        let multiply = g("multiply");
+        assert!(multiply.contains("[bsg1][blg1]blend=all_mode=multiply[bx1]"), "{}", multiply);
+        // the item is placed on a clear frame during its window only, and blended where its alpha is
+        assert!(multiply.contains("[bk1][v1]overlay=x=0:y=0:format=auto:enable='between(t,0.000,4.000)',format=rgba,split[bl1][ba1]"), "{}", multiply);
+        assert!(multiply.ends_with("[bx1][bm1]alphamerge[by1];[bb1][by1]overlay=format=auto[m1]"), "{}", multiply);
+        assert!(!multiply.contains("[0:v][v1]overlay"));
+        assert!(g("screen").contains("[bsg1][blg1]blend=all_mode=screen[bx1]"));
+        assert!(g("add").contains("blend=all_mode=addition"));
+        assert!(!g("normal").contains("blend=") && g("normal").contains("[0:v][v1]overlay="));
+    }
+
+    #[test]
+    fn timeline_resolves_ends_layers_and_inputs() {
+        let d = items(json!([
+            { "id": "v", "type": "video", "details": { "src": "/in/v.mp4" }, "trim": { "from": 0, "to": 2500 }, "display": { "from": 0, "to": 3000 } },
+            { "id": "a", "type": "audio", "details": { "src": "/in/a.mp3" }, "trim": { "from": 1000, "to": 5000 }, "display": { "from": 2000 } },
+            { "id": "i", "type": "image", "details": { "src": "/in/i.png", "zIndex": -1 } },
+            { "id": "t1", "type": "text", "details": { "text": "hi", "fontUrl": "/in/f.ttf" }, "display": { "from": 500, "to": 1000 } }
+        ]));
+        let tl = resolve_timeline(&d);
+        assert_eq!(tl.duration_ms, 6000);
+        // display.to beats the trim window, which beats the timeline end; text is drawn above every layer
+        let resolved: Vec<_> = tl.items.iter().map(|it| (it.id.as_deref().unwrap(), it.start_ms, it.end_ms, it.layer, it.input)).collect();
+        assert_eq!(resolved, [("v", 0, 3000, Some(1), Some(1)), ("a", 2000, 6000, None, Some(2)), ("i", 0, 6000, Some(0), Some(3)), ("t1", 500, 1000, Some(2), None)]);
+        // and it is the order the command composites in
+        let g = build(&d);
+        assert!(graph(&g).contains("[0:v][v3]overlay=") && graph(&g).contains("[m3][v1]overlay="));
+    }
+
+    #[test]
+    fn format_allowlist_checks_container_codec_and_extension() {
+        let allowed: Vec<String> = DEFAULT_ALLOWED_FORMATS.split(',').map(String::from).collect();
+        let probe = |format: &str, codec: &str| FfprobeInfo { format_name: Some(format.into()), video_codec: Some(codec.into()), ..Default::default() };
+        let check = |path: &str, probe: Option<FfprobeInfo>| format_error(Path::new(path), probe.as_ref(), &allowed);
+        assert_eq!(check("/j/a.bin", Some(probe("mov,mp4,m4a,3gp,3g2,mj2", "h264"))), None);
+        assert_eq!(check("/j/a.mp4", Some(probe("avi", "mpeg4"))).as_deref(), Some("format 'avi' is not allowed"));
+        assert_eq!(check("/j/a.png", Some(probe("image2", "png"))), None);
+        assert_eq!(check("/j/a.png", Some(probe("image2", "tiff"))).as_deref(), Some("image codec 'tiff' is not allowed"));
+        // without ffprobe the extension decides
+        assert_eq!(check("/j/f_0001.PNG", None), None);
+        assert_eq!(check("/j/x.xyz", None).as_deref(), Some("file type '.xyz' is not allowed"));
+        assert_eq!(format_error(Path::new("/j/a.mp4"), Some(&probe("mov,mp4", "h264")), &["MP4".into()]), None);
+    }
+
+    #[test]
+    fn surround_audio_is_folded_before_the_mix() {
+        let chain = |details: serde_json::Value, channels: Option<u32>| {
+            let mut details = details;
+            details["src"] = json!("/in/a.mp4");
+            let mut d = items(json!([{ "type": "audio", "details": details }]));
+            d.trackItems[0].probe = Some(FfprobeInfo { audio_channels: channels, ..Default::default() });
+            chain_for(graph(&build(&d)), "a0").to_string()
+        };
+        assert!(chain(json!({}), Some(6)).starts_with("[0:a]aformat=channel_layouts=stereo,volume=1,"));
+        assert!(chain(json!({}), Some(2)).starts_with("[0:a]volume=1,"));
+        assert!(chain(json!({ "downmix": "left" }), Some(2)).starts_with("[0:a]pan=stereo|c0=c0|c1=c0,volume=1,"));
+        assert!(chain(json!({ "channelLayout": "5.1", "downmix": "mono" }), None).starts_with("[0:a]channelmap=channel_layout=5.1,aformat=channel_layouts=mono,volume=1,"));
+    }
+
+    /// Requests a `file_server` saw: path and Authorization header.
+    pub(crate) type RequestLog = std::sync::Arc<std::sync::Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Serve `files` by path on a local port, streamed in chunks without a Content-Length; 404 otherwise.
+    pub(crate) async fn file_server(files: HashMap<&'static str, Vec<u8>>) -> (String, RequestLog) {
+        let log = RequestLog::default();
+        let seen = log.clone();
+        let app = axum::Router::new().fallback(move |req: axum::extract::Request| {
+            let (files, seen) = (files.clone(), seen.clone());
+            async move {
+                let auth = req.headers().get("authorization").and_then(|v| v.to_str().ok()).map(String::from);
+                seen.lock().unwrap().push((req.uri().path().to_string(), auth));
+                match files.get(req.uri().path()) {
+                    Some(body) => {
+                        let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = body.chunks(64 * 1024).map(|c| Ok(bytes::Bytes::copy_from_slice(c))).collect();
+                        axum::response::Response::new(axum::body::Body::from_stream(futures_util::stream::iter(chunks)))
+                    }
+                    None => axum::response::Response::builder().status(404).body(axum::body::Body::empty()).unwrap(),
+                }
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), log)
+    }
+
+    /// Download settings for `file_server`: loopback allowed, no cache, no retries.
+    pub(crate) fn test_config() -> DownloadConfig {
+        let mut cfg = DownloadConfig::from_env();
+        cfg.url_policy.allow_private = true;
+        cfg.cache = None;
+        cfg.retries = 0;
+        cfg
+    }
+
+    #[tokio::test]
+    async fn size_limits_trip_mid_stream_and_leave_nothing_behind() {
+        // no Content-Length, so the limits trip while the body streams in
+        let (base, _) = file_server(HashMap::from([("/big.bin", vec![7u8; 4 << 20])])).await;
+        let url = format!("{}/big.bin", base);
+        let mut cfg = test_config();
+        cfg.max_asset_bytes = 1 << 20;
+        let dir = tempfile::tempdir().unwrap();
+        let job_bytes = AtomicU64::new(0);
+        let err = download_asset(&url, &HeaderMap::new(), dir.path(), &cfg, &job_bytes).await.unwrap_err();
+        assert!(err.to_string().contains("asset exceeds"), "{:#}", err);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert_eq!(job_bytes.load(Ordering::Relaxed), 0);
+
+        // the job-wide total counts what the job's other assets already used, and gets this one's bytes back
+        let mut cfg = test_config();
+        cfg.max_job_bytes = 5 << 20;
+        let job_bytes = AtomicU64::new(2 << 20);
+        let err = download_asset(&url, &HeaderMap::new(), dir.path(), &cfg, &job_bytes).await.unwrap_err();
+        assert!(err.to_string().contains("job download total exceeds"), "{:#}", err);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert_eq!(job_bytes.load(Ordering::Relaxed), 2 << 20);
+    }
+
+    #[tokio::test]
+    async fn asset_headers_go_with_the_request() {
+        let (base, log) = file_server(HashMap::from([("/clip.mp4", b"movie".to_vec())])).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer s3cret"));
+        let path = download_asset(&format!("{}/clip.mp4", base), &headers, dir.path(), &test_config(), &AtomicU64::new(0)).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"movie");
+        assert_eq!(*log.lock().unwrap(), vec![("/clip.mp4".to_string(), Some("Bearer s3cret".to_string()))]);
+    }
+
+    #[tokio::test]
+    async fn large_downloads_stream_to_disk() {
+        let body: Vec<u8> = (0..8 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let (base, _) = file_server(HashMap::from([("/big.bin", body.clone())])).await;
+        let dir = tempfile::tempdir().unwrap();
+        let job_bytes = AtomicU64::new(0);
+        let path = download_asset(&format!("{}/big.bin", base), &HeaderMap::new(), dir.path(), &test_config(), &job_bytes).await.unwrap();
+        assert!(std::fs::read(path).unwrap() == body);
+        assert_eq!(job_bytes.load(Ordering::Relaxed), body.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn same_file_names_from_different_urls_do_not_collide() {
+        let (base, _) = file_server(HashMap::from([("/a/clip.mp4", b"first".to_vec()), ("/b/clip.mp4", b"second".to_vec())])).await;
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, job_bytes) = (test_config(), AtomicU64::new(0));
+        let a = download_asset(&format!("{}/a/clip.mp4", base), &HeaderMap::new(), dir.path(), &cfg, &job_bytes).await.unwrap();
+        let b = download_asset(&format!("{}/b/clip.mp4", base), &HeaderMap::new(), dir.path(), &cfg, &job_bytes).await.unwrap();
+        assert_ne!(a, b);
+        assert!(a.to_string_lossy().ends_with("-clip.mp4") && b.to_string_lossy().ends_with("-clip.mp4"));
+        assert_eq!(std::fs::read(a).unwrap(), b"first");
+        assert_eq!(std::fs::read(b).unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn retries_reuse_the_previous_download() {
+        let (base, log) = file_server(HashMap::from([("/clip.mp4", b"movie".to_vec())])).await;
+        let url = format!("{}/clip.mp4", base);
+        let (cfg, job_bytes) = (test_config(), AtomicU64::new(0));
+        let first = tempfile::tempdir().unwrap();
+        let prev = download_asset(&url, &HeaderMap::new(), first.path(), &cfg, &job_bytes).await.unwrap();
+        let reuse = HashMap::from([(url.clone(), prev.clone())]);
+
+        let second = tempfile::tempdir().unwrap();
+        let path = download_or_reuse(&url, &HeaderMap::new(), second.path(), &cfg, &job_bytes, &reuse).await.unwrap();
+        assert!(path.starts_with(second.path()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"movie");
+        assert_eq!(log.lock().unwrap().len(), 1);
+
+        // the earlier file is gone, so it's fetched again
+        std::fs::remove_file(&prev).unwrap();
+        let third = tempfile::tempdir().unwrap();
+        let path = download_or_reuse(&url, &HeaderMap::new(), third.path(), &cfg, &job_bytes, &reuse).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"movie");
+        assert_eq!(log.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn incoming_transitions_are_drawn_over_the_outgoing_clip() {
+        // b comes in over a, though its zIndex would put it underneath
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/a.mp4", "zIndex": 5 }, "display": { "from": 0, "to": 2000 } },
+            { "type": "video", "details": { "src": "/in/b.mp4", "zIndex": 1, "transition": { "durationMs": 500 } }, "display": { "from": 2000, "to": 4000 } }
+        ]));
+        let built = build(&d);
+        let g = graph(&built);
+        assert!(g.contains("[0:v][v1]overlay=") && g.contains("[m1][v2]overlay="), "{}", g);
+        assert!(g.contains("fade=t=in:st=1.500:d=0.500:alpha=1[v2]"), "{}", g);
+        let layers: Vec<_> = resolve_timeline(&d).items.iter().map(|it| it.layer).collect();
+        assert_eq!(layers, [Some(0), Some(1)]);
+
+        // without the transition the zIndex order stands
+        let d = items(json!([
+            { "type": "video", "details": { "src": "/in/a.mp4", "zIndex": 5 }, "display": { "from": 0, "to": 2000 } },
+            { "type": "video", "details": { "src": "/in/b.mp4", "zIndex": 1 }, "display": { "from": 2000, "to": 4000 } }
+        ]));
+        let built = build(&d);
+        let g = graph(&built);
+        assert!(g.contains("[0:v][v2]overlay=") && g.contains("[m2][v1]overlay="), "{}", g);
+    }
+}
@@ -1,71 +1,405 @@
-use crate::types::StatusResponse;
-use std::{collections::HashMap, path::PathBuf, time::Instant, sync::Arc};
-use tokio::sync::RwLock;
+use crate::types::{Design, JobSummary, StatusResponse};
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap, VecDeque}, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+use tokio::{sync::{watch, RwLock}, task::AbortHandle};
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
-pub enum JobStatus { Pending, Running, Completed, Failed }
+/// Job metadata file written into each job's workdir so the store survives restarts.
+pub const JOB_FILE: &str = "job.json";
 
-#[derive(Debug, Clone)]
+/// Progress samples kept for the ETA moving average.
+const ETA_SAMPLES: usize = 10;
+/// No ETA below this percentage; the first few percent are dominated by ffmpeg startup.
+const ETA_MIN_PROGRESS: u32 = 3;
+/// Completed render durations kept for the queue wait estimate.
+const RECENT_RENDERS: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JobStatus { Pending, Running, Completed, Failed, Cancelled }
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "PENDING",
+            JobStatus::Running => "RUNNING",
+            JobStatus::Completed => "COMPLETED",
+            JobStatus::Failed => "FAILED",
+            JobStatus::Cancelled => "CANCELLED",
+        }
+    }
+    pub fn is_terminal(&self) -> bool { matches!(self, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled) }
+}
+
+/// Render queue priority (options.priority); waiting jobs start highest first, then oldest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority { Low, #[default] Normal, High }
+
+impl Priority {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s { "low" => Some(Priority::Low), "normal" => Some(Priority::Normal), "high" => Some(Priority::High), _ => None }
+    }
+}
+
+/// Why a job failed, for clients that need more than the human-readable message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Workdir or other local filesystem setup failed.
+    Internal,
+    DownloadFailed,
+    FontDownloadFailed,
+    /// A downloaded asset isn't media ffprobe can read as the item's type (e.g. an HTML error page).
+    InvalidAsset,
+    /// The design couldn't be turned into an ffmpeg command.
+    BuildFailed,
+    /// The render queue was shut down before the job got a slot.
+    QueueClosed,
+    SpawnFailed,
+    /// The render finished but uploading it to options.output.destination failed; the local file is kept.
+    UploadFailed,
+    /// ffmpeg ran and exited nonzero (or couldn't be waited on).
+    FfmpegExit,
+    /// ffmpeg was still running after RENDER_JOB_TIMEOUT_SECS and was killed.
+    TimedOut,
+    /// The renderer restarted while the job was in flight.
+    Interrupted,
+    /// The renderer was stopped and the job didn't finish within the shutdown grace period.
+    ShuttingDown,
+}
+
+impl ErrorCode {
+    /// Whether resubmitting the same design might succeed.
+    pub fn is_retryable(self) -> bool { !matches!(self, ErrorCode::BuildFailed | ErrorCode::FfmpegExit | ErrorCode::TimedOut) }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Job {
     pub id: Uuid,
+    /// Submission order, assigned by `JobStore::insert`.
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(default)]
+    pub priority: Priority,
     pub status: JobStatus,
     pub progress: u32,
     pub output_path: Option<PathBuf>,
+    /// Extra outputs by rendition name, set together with `output_path` when the job completes.
+    #[serde(default)]
+    pub renditions: BTreeMap<String, PathBuf>,
     pub error: Option<String>,
-    pub created_at: Instant,
+    #[serde(default)]
+    pub error_code: Option<ErrorCode>,
+    /// Last lines of ffmpeg stderr when the render itself failed.
+    #[serde(default)]
+    pub error_detail: Option<String>,
+    /// ffmpeg argv (URL credentials redacted), set once the command is built.
+    #[serde(default)]
+    pub ffmpeg_args: Option<Vec<String>>,
+    #[serde(default)]
+    pub first_pass_args: Option<Vec<String>>,
+    /// Last lines of ffmpeg stderr from the most recent run, successful or not.
+    #[serde(default)]
+    pub ffmpeg_stderr: Option<String>,
+    /// Where the output was uploaded (options.output.destination); replaces the local output URL.
+    #[serde(default)]
+    pub remote_url: Option<String>,
+    /// Timeline length of the rendered output, known once the job completes.
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    /// Time since the unix epoch, so it survives serialization.
+    pub created_at: Duration,
+    /// When ffmpeg was launched (since the epoch); queue and download time don't count toward the ETA.
+    #[serde(default)]
+    pub started_at: Option<Duration>,
+    #[serde(skip)]
+    /// Recent `(time since epoch, progress)` points for the ETA estimate.
+    pub progress_samples: VecDeque<(Duration, u32)>,
+    #[serde(skip)]
     pub workdir: PathBuf,
+    #[serde(skip)]
+    /// The worker task; stopping it drops the ffmpeg child (spawned with kill_on_drop).
+    pub worker: Option<Worker>,
+    #[serde(skip)]
+    /// Live progress feed for SSE subscribers; absent for jobs rehydrated from disk.
+    pub events: Option<Arc<watch::Sender<(JobStatus, u32)>>>,
+    #[serde(skip)]
+    /// The submitted design (options merged) and callback URL, for `POST /render/:id/retry`. Memory
+    /// only: asset URLs and headers can carry credentials, so jobs rehydrated from disk can't retry.
+    pub request: Option<(Design, Option<String>)>,
+    #[serde(skip)]
+    /// Files this job downloaded, by source URL; a retry links them instead of fetching again.
+    pub downloads: HashMap<String, PathBuf>,
+}
+
+/// Handle to a job's worker task. Aborting only schedules the cancellation; `stop` also waits until
+/// the task has been dropped, so nothing it started is still writing to the job's workdir.
+#[derive(Clone, Debug)]
+pub struct Worker {
+    abort: AbortHandle,
+    ended: watch::Receiver<()>,
+}
+
+impl Worker {
+    /// `ended` must close (its sender dropped) once the task's JoinHandle has resolved.
+    pub fn new(abort: AbortHandle, ended: watch::Receiver<()>) -> Self { Self { abort, ended } }
+
+    pub fn abort(&self) { self.abort.abort(); }
+
+    /// Abort the task and wait for it to be gone.
+    pub async fn stop(mut self) {
+        self.abort.abort();
+        // nothing is ever sent: this returns once the sender is dropped
+        let _ = self.ended.changed().await;
+    }
 }
 
 impl Job {
-    pub fn new(workdir: PathBuf) -> Self {
+    /// New pending job whose workdir is `<root>/<id>`.
+    pub fn new(root: &Path) -> Self {
+        let id = Uuid::new_v4();
         Self {
-            id: Uuid::new_v4(),
+            id,
+            seq: 0,
+            priority: Priority::Normal,
             status: JobStatus::Pending,
             progress: 0,
             output_path: None,
+            renditions: BTreeMap::new(),
             error: None,
-            created_at: Instant::now(),
-            workdir,
+            error_code: None,
+            error_detail: None,
+            ffmpeg_args: None,
+            first_pass_args: None,
+            ffmpeg_stderr: None,
+            remote_url: None,
+            duration_ms: None,
+            created_at: now_since_epoch(),
+            started_at: None,
+            progress_samples: VecDeque::new(),
+            workdir: root.join(id.to_string()),
+            worker: None,
+            events: Some(Arc::new(watch::channel((JobStatus::Pending, 0)).0)),
+            request: None,
+            downloads: HashMap::new(),
+        }
+    }
+
+    /// Mark the job failed with `code` and a human-readable `message`.
+    pub fn fail(&mut self, code: ErrorCode, message: impl Into<String>) {
+        self.status = JobStatus::Failed;
+        self.error = Some(message.into());
+        self.error_code = Some(code);
+    }
+
+    /// Record a new progress value, keeping a short history for the ETA. Progress only moves forward:
+    /// ffmpeg's out_time can step back briefly, and a lower value is ignored rather than shown.
+    pub fn set_progress(&mut self, pct: u32) {
+        if pct < self.progress || (pct == self.progress && !self.progress_samples.is_empty()) { return; }
+        self.progress = pct;
+        if self.progress_samples.len() == ETA_SAMPLES { self.progress_samples.pop_front(); }
+        self.progress_samples.push_back((now_since_epoch(), pct));
+    }
+
+    /// Start progress over at 0 for a new phase, the one way it can go down.
+    pub fn reset_progress(&mut self) {
+        self.progress = 0;
+        self.progress_samples.clear();
+    }
+
+    /// Seconds left at the average rate over the recent samples (or since ffmpeg started, with too
+    /// few samples). None unless running and past `ETA_MIN_PROGRESS`.
+    pub fn eta_seconds(&self) -> Option<u32> {
+        if !matches!(self.status, JobStatus::Running) || self.progress < ETA_MIN_PROGRESS || self.progress >= 100 { return None; }
+        let (t1, p1) = *self.progress_samples.back()?;
+        let (t0, p0) = match self.progress_samples.front() {
+            Some(&(t, p)) if p < p1 => (t, p),
+            _ => (self.started_at?, 0),
+        };
+        let rate = (p1 - p0) as f64 / t1.saturating_sub(t0).as_secs_f64().max(0.001); // percent per second
+        // count down between progress updates rather than jumping at each one
+        let since_last = now_since_epoch().saturating_sub(t1).as_secs_f64();
+        Some((((100 - self.progress) as f64 / rate) - since_last).max(0.0).round() as u32)
+    }
+
+    /// Push the current status/progress to SSE subscribers, if any.
+    pub fn notify(&self) {
+        if let Some(tx) = &self.events { tx.send_replace((self.status.clone(), self.progress)); }
+    }
+
+    pub fn subscribe(&self) -> Option<watch::Receiver<(JobStatus, u32)>> { self.events.as_ref().map(|tx| tx.subscribe()) }
+
+    /// Write the job metadata to `<workdir>/job.json`. Errors are logged, not propagated.
+    async fn persist(&self) {
+        let json = match serde_json::to_vec_pretty(self) { Ok(j) => j, Err(e) => { tracing::warn!(id = %self.id, "serialize job failed: {}", e); return; } };
+        let tmp = self.workdir.join(format!("{}.tmp", JOB_FILE));
+        let res = async {
+            tokio::fs::write(&tmp, &json).await?;
+            tokio::fs::rename(&tmp, self.workdir.join(JOB_FILE)).await
+        }.await;
+        if let Err(e) = res { tracing::debug!(id = %self.id, "persist job failed: {}", e); }
+    }
+
+    pub fn to_summary(&self) -> JobSummary {
+        JobSummary {
+            id: self.id.to_string(),
+            status: self.status.as_str().into(),
+            progress: self.progress,
+            age_secs: self.age().as_secs(),
         }
     }
 
-    pub fn to_status_response(&self, base_url: &str) -> StatusResponse {
+    pub fn age(&self) -> Duration { now_since_epoch().saturating_sub(self.created_at) }
+
+    /// Seconds until the cleanup task may remove this job; `None` while it is still in flight.
+    pub fn expires_in(&self, ttl: Duration) -> Option<u64> {
+        if !self.status.is_terminal() { return None; }
+        Some(ttl.saturating_sub(self.age()).as_secs())
+    }
+
+    pub fn to_status_response(&self, base_url: &str, ttl: Duration) -> StatusResponse {
         StatusResponse {
-            status: match self.status {
-                JobStatus::Pending => "PENDING".into(),
-                JobStatus::Running => "RUNNING".into(),
-                JobStatus::Completed => "COMPLETED".into(),
-                JobStatus::Failed => "FAILED".into(),
-            },
+            status: self.status.as_str().into(),
             progress: self.progress,
-            url: self
+            url: self.remote_url.clone().or_else(|| self
                 .output_path
                 .as_ref()
-                .map(|_| format!("{}/render/{}/output", base_url, self.id)),
+                .map(|_| format!("{}/render/{}/output", base_url, self.id))),
+            output_name: self.output_path.as_ref().and_then(|p| p.file_name()).map(|n| n.to_string_lossy().to_string()),
+            renditions: (!self.renditions.is_empty())
+                .then(|| self.renditions.keys().map(|n| (n.clone(), format!("{}/render/{}/output/{}", base_url, self.id, n))).collect()),
             error: self.error.clone(),
+            error_code: self.error_code,
+            retryable: self.error_code.map(ErrorCode::is_retryable),
+            error_detail: self.error_detail.clone(),
+            expires_in: self.expires_in(ttl),
+            eta_seconds: self.eta_seconds(),
+            queue_position: None,
+            queue_wait_seconds: None,
+            priority: self.priority,
         }
     }
 }
 
-#[derive(Clone)]
-pub struct JobStore(pub Arc<RwLock<HashMap<Uuid, Job>>>);
-
-impl Default for JobStore {
-    fn default() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())))
-    }
+#[derive(Clone, Default)]
+pub struct JobStore {
+    jobs: Arc<RwLock<HashMap<Uuid, Job>>>,
+    next_seq: Arc<AtomicU64>,
+    /// Wall time of the last few completed renders, newest last.
+    recent_renders: Arc<Mutex<VecDeque<Duration>>>,
+    /// Idempotency-Key -> (job id, when the key was first seen). Held across lookup and claim so
+    /// concurrent submissions with the same key resolve to one job.
+    idempotency_keys: Arc<tokio::sync::Mutex<HashMap<String, (Uuid, Duration)>>>,
 }
 
 impl JobStore {
-    pub async fn insert(&self, job: Job) -> Uuid {
+    /// Rehydrate a store from the `job.json` files under `root`. Jobs whose worker was
+    /// interrupted by the restart are marked as failed.
+    pub async fn load(root: &Path) -> Self {
+        let store = Self::default();
+        let mut entries = match tokio::fs::read_dir(root).await { Ok(e) => e, Err(_) => return store };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let workdir = entry.path();
+            let Ok(bytes) = tokio::fs::read(workdir.join(JOB_FILE)).await else { continue };
+            let mut job: Job = match serde_json::from_slice(&bytes) {
+                Ok(j) => j,
+                Err(e) => { tracing::warn!(path = %workdir.display(), "skipping unreadable job file: {}", e); continue; }
+            };
+            job.workdir = workdir;
+            if let JobStatus::Pending | JobStatus::Running = job.status {
+                job.fail(ErrorCode::Interrupted, "interrupted by renderer restart");
+                job.persist().await;
+            }
+            store.next_seq.fetch_max(job.seq + 1, Ordering::Relaxed);
+            store.jobs.write().await.insert(job.id, job);
+        }
+        store
+    }
+
+    pub async fn insert(&self, mut job: Job) -> Uuid {
         let id = job.id;
-        self.0.write().await.insert(id, job);
+        job.seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        job.persist().await;
+        self.jobs.write().await.insert(id, job);
         id
     }
-    pub async fn get(&self, id: &Uuid) -> Option<Job> { self.0.read().await.get(id).cloned() }
+    pub async fn get(&self, id: &Uuid) -> Option<Job> { self.jobs.read().await.get(id).cloned() }
     pub async fn update<F: FnOnce(&mut Job)>(&self, id: &Uuid, f: F) {
-        if let Some(job) = self.0.write().await.get_mut(id) { f(job); }
+        // Snapshot under the lock, write the file after releasing it
+        let snapshot = {
+            let mut guard = self.jobs.write().await;
+            guard.get_mut(id).map(|job| { f(job); job.clone() })
+        };
+        if let Some(job) = snapshot { job.notify(); job.persist().await; }
+    }
+    /// Summaries of all jobs, optionally only those whose status string matches `status` (case-insensitive).
+    pub async fn list(&self, status: Option<&str>) -> Vec<JobSummary> {
+        let guard = self.jobs.read().await;
+        guard
+            .values()
+            .filter(|j| status.map(|s| j.status.as_str().eq_ignore_ascii_case(s)).unwrap_or(true))
+            .map(|j| j.to_summary())
+            .collect()
+    }
+    pub async fn active_count(&self) -> usize { self.jobs.read().await.values().filter(|j| !j.status.is_terminal()).count() }
+    /// `(running, pending)` job counts.
+    pub async fn in_flight_counts(&self) -> (usize, usize) {
+        let guard = self.jobs.read().await;
+        let running = guard.values().filter(|j| matches!(j.status, JobStatus::Running)).count();
+        let pending = guard.values().filter(|j| matches!(j.status, JobStatus::Pending)).count();
+        (running, pending)
+    }
+    /// Pending jobs submitted before `job`.
+    pub async fn pending_ahead(&self, job: &Job) -> usize {
+        self.jobs.read().await.values()
+            .filter(|j| matches!(j.status, JobStatus::Pending) && (j.priority, std::cmp::Reverse(j.seq)) > (job.priority, std::cmp::Reverse(job.seq)))
+            .count()
+    }
+    pub fn record_render(&self, elapsed: Duration) {
+        let mut recent = self.recent_renders.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_RENDERS { recent.pop_front(); }
+        recent.push_back(elapsed);
+    }
+    /// Mean of the recent render durations; None until something has completed.
+    pub fn average_render(&self) -> Option<Duration> {
+        let recent = self.recent_renders.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.is_empty() { return None; }
+        Some(recent.iter().sum::<Duration>() / recent.len() as u32)
+    }
+    /// Insert `job` under an idempotency key, unless the key already maps to a job that is still in
+    /// the store and was first seen less than `ttl` ago; then `job` is dropped and that job is returned.
+    pub async fn insert_idempotent(&self, key: &str, job: Job, ttl: Duration) -> Result<Uuid, Job> {
+        let mut keys = self.idempotency_keys.lock().await;
+        if let Some(&(existing, at)) = keys.get(key) {
+            if now_since_epoch().saturating_sub(at) < ttl {
+                if let Some(j) = self.get(&existing).await { return Err(j); }
+            }
+        }
+        keys.insert(key.to_string(), (job.id, now_since_epoch()));
+        Ok(self.insert(job).await)
+    }
+    pub async fn remove_expired_idempotency_keys(&self, ttl: Duration) {
+        let now = now_since_epoch();
+        self.idempotency_keys.lock().await.retain(|_, (_, at)| now.saturating_sub(*at) < ttl);
+    }
+    /// Abort and fail every job that hasn't finished; returns how many there were.
+    pub async fn fail_unfinished(&self, code: ErrorCode, message: &str) -> usize {
+        let ids: Vec<Uuid> = self.jobs.read().await.values().filter(|j| !j.status.is_terminal()).map(|j| j.id).collect();
+        for id in &ids {
+            self.update(id, |j| {
+                if let Some(worker) = j.worker.take() { worker.abort(); }
+                j.fail(code, message);
+            }).await;
+        }
+        ids.len()
+    }
+    pub async fn remove(&self, id: &Uuid) -> Option<Job> { self.jobs.write().await.remove(id) }
+    /// Take finished jobs older than `ttl` out of the store; the caller deletes their workdirs.
+    pub async fn remove_expired(&self, ttl: Duration) -> Vec<Job> {
+        let mut guard = self.jobs.write().await;
+        let expired: Vec<Uuid> = guard.values().filter(|j| j.status.is_terminal() && j.age() >= ttl).map(|j| j.id).collect();
+        expired.iter().filter_map(|id| guard.remove(id)).collect()
     }
 }
 
+
+pub fn now_since_epoch() -> Duration { SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() }
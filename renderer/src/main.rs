@@ -1,173 +1,1277 @@
-mod types; mod jobs; mod ffmpeg;
-use axum::{routing::{get, post}, Json, Router, extract::{Path, State}};
-use jobs::{Job, JobStatus, JobStore};
-use std::{net::SocketAddr, path::PathBuf, collections::HashMap};
-use tokio::{process::Command, io::{AsyncBufReadExt, BufReader}};
+mod types; mod jobs; mod ffmpeg; mod net; mod validate; mod cache; mod metrics; mod upload; mod queue;
+use axum::{routing::{get, post}, Json, Router, extract::{Path, Query, State}, response::IntoResponse};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use jobs::{ErrorCode, Job, JobStatus, JobStore};
+use metrics::Metrics;
+use sha2::{Digest, Sha256};
+use std::{net::SocketAddr, path::PathBuf, collections::{BTreeMap, HashMap, VecDeque}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::Duration};
+use tokio::{process::Command, io::{AsyncBufReadExt, BufReader}, task::JoinHandle};
 use tracing::info;
-use types::{DesignEnvelope, StatusResponse, SubmitResponse};
+use types::{DesignEnvelope, DesignUrlEnvelope, DryRunQuery, DryRunResponse, FieldError, HealthResponse, JobSummary, ListQuery, LogsResponse, ProgressEvent, StatusResponse, SubmitResponse, ThumbnailQuery, ValidationErrorResponse, WebhookPayload};
 
 #[derive(Clone)]
 struct AppState {
     store: JobStore,
     base_url: String,
     caps: ffmpeg::BackendCaps,
+    download_concurrency: usize,
+    downloads: Arc<ffmpeg::DownloadConfig>,
+    /// Bounds how many ffmpeg processes run at once; waiting jobs start by priority.
+    render_slots: Arc<queue::RenderQueue>,
+    max_concurrent: usize,
+    jobs_root: PathBuf,
+    job_ttl: Duration,
+    metrics: Arc<Metrics>,
+    /// Request body limit, also applied to designs fetched by URL.
+    max_body: usize,
+    /// How long an Idempotency-Key keeps returning the job it created.
+    idempotency_ttl: Duration,
+    /// Per-job tasks (worker plus outcome/callback handling) that shutdown waits for.
+    workers: Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>,
+    /// Set once a shutdown signal arrived; new submissions get a 503.
+    shutting_down: Arc<AtomicBool>,
+    /// Credentials for s3:// output destinations, if configured.
+    s3: Option<Arc<upload::S3Config>>,
+    /// RENDER_API_TOKEN; when set every route but /healthz needs `Authorization: Bearer <token>`.
+    api_token: Option<Arc<str>>,
+    /// RENDER_JOB_TIMEOUT_SECS: wall-clock limit on a job's ffmpeg run(s); None = unlimited.
+    job_timeout: Option<Duration>,
+    /// RENDER_MAX_QUEUE_DEPTH: pending plus running jobs beyond which submissions get a 429; None = unlimited.
+    max_queue_depth: Option<usize>,
+    /// `POST /probe` results by URL hash, with when they were probed.
+    probe_cache: Arc<tokio::sync::Mutex<HashMap<String, (std::time::Instant, types::ProbeResponse)>>>,
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt().with_env_filter("info").init();
-    let caps = ffmpeg::detect_caps().await;
+    let caps = ffmpeg::detect_caps(&ffmpeg::ffmpeg_bin(), &ffmpeg::ffprobe_bin()).await;
     info!(?caps, "Detected backend capabilities");
 
-    let store = JobStore::default();
+    let jobs_root = match jobs_root().await {
+        Ok(root) => root,
+        Err(e) => { tracing::error!("job directory unusable: {:#}", e); std::process::exit(1); }
+    };
+    info!(root = %jobs_root.display(), "Job directory");
+    let store = JobStore::load(&jobs_root).await;
+    info!(jobs = store.list(None).await.len(), "Loaded persisted jobs");
     let port: u16 = std::env::var("RENDER_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(6108);
     let base_url = format!("http://127.0.0.1:{}", port);
 
-    let state = AppState { store, base_url: base_url.clone(), caps };
+    let download_concurrency: usize = std::env::var("RENDER_DOWNLOAD_CONCURRENCY").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0).unwrap_or(4);
 
-    let app = Router::new()
-        .route("/render", post(submit_render))
-        .route("/render/:id", get(get_status))
-        .route("/render/:id/output", get(get_output))
-        .with_state(state);
+    let job_ttl = Duration::from_secs(std::env::var("RENDER_JOB_TTL_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(3600));
+    let cleanup_interval = Duration::from_secs(std::env::var("RENDER_CLEANUP_INTERVAL_SECS").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0).unwrap_or(300));
+    let idempotency_ttl = Duration::from_secs(std::env::var("RENDER_IDEMPOTENCY_TTL_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(86400));
+    tokio::spawn(cleanup_expired_jobs(store.clone(), job_ttl, idempotency_ttl, cleanup_interval));
+
+    let downloads = Arc::new(ffmpeg::DownloadConfig::from_env());
+    let max_concurrent: usize = std::env::var("RENDER_MAX_CONCURRENT").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    let render_slots = queue::RenderQueue::new(max_concurrent);
+
+    // Designs can carry base64 data: URIs, so the default 2MB body limit is too tight; oversized bodies get a 413
+    let max_body: usize = std::env::var("RENDER_MAX_BODY_BYTES").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0).unwrap_or(32 * 1024 * 1024);
+    let shutdown_grace = Duration::from_secs(std::env::var("RENDER_SHUTDOWN_GRACE_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(30));
+    let api_token: Option<Arc<str>> = std::env::var("RENDER_API_TOKEN").ok().filter(|s| !s.is_empty()).map(Into::into);
+    if api_token.is_none() { tracing::warn!("RENDER_API_TOKEN not set, the API is unauthenticated"); }
+    let job_timeout = std::env::var("RENDER_JOB_TIMEOUT_SECS").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0).map(Duration::from_secs);
+    let max_queue_depth: Option<usize> = std::env::var("RENDER_MAX_QUEUE_DEPTH").ok().and_then(|s| s.parse().ok()).filter(|n| *n > 0);
+
+    let state = AppState { store, base_url: base_url.clone(), caps, download_concurrency, downloads, render_slots, max_concurrent, jobs_root, job_ttl, metrics: Arc::new(Metrics::default()), max_body, idempotency_ttl,
+        workers: Default::default(), shutting_down: Default::default(), api_token, job_timeout, max_queue_depth, s3: upload::S3Config::from_env().map(Arc::new), probe_cache: Default::default() };
+
+    let app = app(state.clone());
 
     let addr = SocketAddr::from(([127,0,0,1], port));
     info!(?addr, "Renderer listening");
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(false);
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app).with_graceful_shutdown(async move { let _ = stop_rx.changed().await; }).await
+    });
+
+    shutdown_signal().await;
+    info!(grace = ?shutdown_grace, "Shutting down, waiting for in-flight renders");
+    state.shutting_down.store(true, Ordering::SeqCst);
+    let _ = stop_tx.send(true);
+    drain_workers(&state, shutdown_grace).await;
+    if let Ok(Err(e)) = server.await { tracing::warn!("server error: {}", e); }
+}
+
+/// The API routes over `state`.
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_text))
+        .route("/render", post(submit_render).get(list_jobs))
+        .route("/render/dryrun", post(dry_run))
+        .route("/render/validate", post(validate_render))
+        .route("/render/from-url", post(submit_render_from_url))
+        .route("/render/batch", post(submit_batch))
+        .route("/render/preview", post(submit_preview))
+        .route("/render/:id", get(get_status).delete(cancel_render))
+        .route("/render/:id/retry", post(retry_render))
+        .route("/render/:id/output", get(get_output))
+        .route("/render/:id/output/:rendition", get(get_rendition_output))
+        .route("/render/:id/events", get(progress_events))
+        .route("/render/:id/thumbnail", get(get_thumbnail))
+        .route("/render/:id/logs", get(get_logs))
+        .route("/probe", post(probe_url))
+        // only the routes above need the token; health checks stay open
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), require_api_token))
+        .route("/healthz", get(healthz))
+        .layer(axum::extract::DefaultBodyLimit::max(state.max_body))
+        .with_state(state)
+}
+
+/// 401 unless the request carries `Authorization: Bearer <RENDER_API_TOKEN>`; a no-op without a token.
+async fn require_api_token(State(state): State<AppState>, req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let Some(token) = &state.api_token else { return next.run(req).await };
+    let given = req.headers().get(axum::http::header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer ")).map(str::trim);
+    // compare every byte so the response time doesn't leak how much of the token matched
+    let ok = given.is_some_and(|g| g.len() == token.len() && g.bytes().zip(token.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0);
+    if !ok {
+        return (axum::http::StatusCode::UNAUTHORIZED, [(axum::http::header::WWW_AUTHENTICATE, "Bearer")], "missing or invalid bearer token").into_response();
+    }
+    next.run(req).await
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async { let _ = tokio::signal::ctrl_c().await; };
+    #[cfg(unix)]
+    let term = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => { s.recv().await; }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+    tokio::select! { _ = ctrl_c => {}, _ = term => {} }
 }
 
-async fn submit_render(State(state): State<AppState>, Json(env): Json<DesignEnvelope>) -> Result<Json<SubmitResponse>, (axum::http::StatusCode, String)> {
+/// Wait up to `grace` for the tracked job tasks, then fail whatever is still in flight.
+async fn drain_workers(state: &AppState, grace: Duration) {
+    let handles: Vec<JoinHandle<()>> = std::mem::take(&mut *state.workers.lock().unwrap_or_else(|e| e.into_inner()));
+    if tokio::time::timeout(grace, futures_util::future::join_all(handles)).await.is_err() {
+        let n = state.store.fail_unfinished(ErrorCode::ShuttingDown, "server shutting down").await;
+        tracing::warn!(jobs = n, "Shutdown grace period elapsed, failed unfinished jobs");
+    }
+}
+
+/// Lines of ffmpeg stderr kept for `error_detail` when a render fails.
+const STDERR_TAIL_LINES: usize = 50;
+
+/// Why an ffmpeg invocation failed, ready to be stored on the job.
+struct RunFailure { code: ErrorCode, message: String, detail: Option<String> }
+
+/// Run one invocation of the ffmpeg binary `bin`, mapping its `-progress` output onto `range` of the
+/// job's progress. ffmpeg is killed if it is still running at `deadline`.
+async fn run_ffmpeg(bin: &str, args: &[String], store: &JobStore, job_id: uuid::Uuid, total_ms: u64, range: (u32, u32), deadline: Option<tokio::time::Instant>) -> Result<(), RunFailure> {
+    tracing::info!("Launching ffmpeg: {} {}", bin, ffmpeg::redact_args(args).join(" "));
+    let mut cmd = Command::new(bin);
+    cmd.args(args);
+    // Cancelling the job aborts the worker task; make sure ffmpeg dies with it
+    cmd.kill_on_drop(true);
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| RunFailure { code: ErrorCode::SpawnFailed, message: format!("spawn failed: {}", e), detail: None })?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    // Drain stderr to avoid pipe blocking; log it and keep the tail for the job error
+    let mut err_reader = BufReader::new(stderr).lines();
+    let mut stderr_tail = tokio::spawn(async move {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        while let Ok(Some(line)) = err_reader.next_line().await {
+            tracing::warn!(id = %job_id, "ffmpeg stderr: {}", line);
+            if tail.len() == STDERR_TAIL_LINES { tail.pop_front(); }
+            tail.push_back(line);
+        }
+        Vec::from(tail).join("\n")
+    });
+
+    // Read progress from stdout until ffmpeg exits
+    let run = async {
+        let mut progress = ffmpeg::ProgressParser::new(total_ms);
+        let mut out_reader = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = out_reader.next_line().await {
+            if let Some(pct) = progress.feed(&line) {
+                let pct = range.0 + pct * (range.1 - range.0) / 100;
+                store.update(&job_id, |j| j.set_progress(pct)).await;
+            }
+        }
+        child.wait().await
+    };
+    let status = match deadline {
+        Some(at) => tokio::time::timeout_at(at, run).await,
+        None => Ok(run.await),
+    };
+    let Ok(status) = status else {
+        // the progress reader went with the timed-out future; killing ffmpeg closes stderr, which ends the tail task
+        let _ = child.kill().await;
+        let tail = match tokio::time::timeout(Duration::from_secs(2), &mut stderr_tail).await {
+            Ok(t) => t.ok().filter(|t| !t.is_empty()),
+            Err(_) => { stderr_tail.abort(); None }
+        };
+        store.update(&job_id, |j| j.ffmpeg_stderr = tail.clone()).await;
+        return Err(RunFailure { code: ErrorCode::TimedOut, message: "ffmpeg timed out and was killed".into(), detail: tail });
+    };
+    // stderr hits EOF once ffmpeg has exited, so this doesn't wait long
+    let tail = if status.is_ok() { stderr_tail.await.ok().filter(|t| !t.is_empty()) } else { None };
+    store.update(&job_id, |j| j.ffmpeg_stderr = tail.clone()).await;
+    match status {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(RunFailure { code: ErrorCode::FfmpegExit, message: format!("ffmpeg exit status: {}", s), detail: tail }),
+        Err(e) => Err(RunFailure { code: ErrorCode::FfmpegExit, message: format!("wait failed: {}", e), detail: None }),
+    }
+}
+
+async fn cleanup_expired_jobs(store: JobStore, ttl: Duration, idempotency_ttl: Duration, interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        store.remove_expired_idempotency_keys(idempotency_ttl).await;
+        for job in store.remove_expired(ttl).await {
+            info!(id = %job.id, "Removing expired job");
+            remove_workdir(&job).await;
+        }
+    }
+}
+
+/// RENDER_WORKDIR (absolute), else ./render_jobs. Created up front and checked for writability so a
+/// bad mount fails at startup rather than on the first job.
+async fn jobs_root() -> anyhow::Result<PathBuf> { jobs_root_from(std::env::var_os("RENDER_WORKDIR")).await }
+
+/// `jobs_root` for a configured RENDER_WORKDIR value.
+async fn jobs_root_from(configured: Option<std::ffi::OsString>) -> anyhow::Result<PathBuf> {
+    let root = match configured.filter(|s| !s.is_empty()) {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            if !dir.is_absolute() { anyhow::bail!("RENDER_WORKDIR must be an absolute path, got {}", dir.display()); }
+            dir
+        }
+        None => std::env::current_dir()?.join("render_jobs"),
+    };
+    tokio::fs::create_dir_all(&root).await.map_err(|e| anyhow::anyhow!("cannot create {}: {}", root.display(), e))?;
+    let probe = root.join(".write-test");
+    tokio::fs::write(&probe, b"").await.map_err(|e| anyhow::anyhow!("{} is not writable: {}", root.display(), e))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(root)
+}
+
+async fn remove_workdir(job: &Job) {
+    if let Err(e) = tokio::fs::remove_dir_all(&job.workdir).await {
+        if e.kind() != std::io::ErrorKind::NotFound { tracing::warn!(id = %job.id, "failed to remove workdir: {}", e); }
+    }
+}
+
+/// Merge optional render options into the design so downstream logic can use a single source.
+fn merge_options(design: &mut types::Design, opts: types::RenderOptions) {
+    if let Some(fps) = opts.fps { design.fps = Some(fps); }
+    // an explicit size beats the preset; an unknown preset is reported by prepare_design
+    if let Some(sz) = opts.size.or_else(|| opts.resolution.as_deref().and_then(ffmpeg::resolution_preset)) { design.size = Some(sz); }
+    if let Some(fmt) = opts.format { design.format = Some(fmt); }
+    design.quality = opts.quality;
+    if let Some(mix) = opts.audioMix { design.audioMix = Some(mix); }
+    if opts.startMs.is_some() { design.startMs = opts.startMs; }
+    if opts.endMs.is_some() { design.endMs = opts.endMs; }
+    if opts.defaultDurationMs.is_some() { design.defaultDurationMs = opts.defaultDurationMs; }
+    if opts.outputName.is_some() { design.outputName = opts.outputName; }
+    if opts.alpha.is_some() { design.alpha = opts.alpha; }
+    if opts.mute.is_some() { design.mute = opts.mute; }
+    if opts.interpolate.is_some() { design.interpolate = opts.interpolate; }
+    if opts.draft.is_some() { design.draft = opts.draft; }
+    if opts.toneMap.is_some() { design.toneMap = opts.toneMap; }
+    if opts.subtitles.is_some() { design.subtitles = opts.subtitles; }
+    if opts.background.is_some() { design.background = opts.background; }
+    if opts.watermark.is_some() { design.watermark = opts.watermark; }
+    if opts.output.is_some() { design.output = opts.output; }
+    if opts.priority.is_some() { design.priority = opts.priority; }
+    if opts.renditions.is_some() { design.renditions = opts.renditions; }
+    design.headers.extend(opts.headers);
+}
+
+/// Merge the render options into the design and validate the result. Returns the design and
+/// callback URL, or the field-level errors.
+fn prepare_design(env: DesignEnvelope) -> Result<(types::Design, Option<String>), ValidationErrorResponse> {
     let mut design = env.design;
-    // Merge optional render options into the design so downstream logic can use a single source
-    if let Some(opts) = env.options {
-        if let Some(fps) = opts.fps { design.fps = Some(fps); }
-        if let Some(sz) = opts.size { design.size = Some(sz); }
-    }
-    let workdir = std::env::current_dir().unwrap().join("render_jobs");
-    let _ = tokio::fs::create_dir_all(&workdir).await;
-    let job = Job::new(workdir.join(uuid::Uuid::new_v4().to_string()));
+    let callback_url = env.options.as_ref().and_then(|o| o.callbackUrl.clone());
+    let bad_resolution = env.options.as_ref().and_then(|o| o.resolution.as_deref()).is_some_and(|r| ffmpeg::resolution_preset(r).is_none());
+    if let Some(opts) = env.options { merge_options(&mut design, opts); }
+    let mut errors = validate::validate_design(&design);
+    if bad_resolution {
+        let names: Vec<&str> = ffmpeg::RESOLUTION_PRESETS.iter().map(|(n, _)| *n).collect();
+        errors.push(FieldError { field: "options.resolution".into(), message: format!("unknown preset; expected one of {}", names.join(", ")) });
+    }
+    if let Some(cb) = &callback_url {
+        match url::Url::parse(cb) {
+            Ok(u) if matches!(u.scheme(), "http" | "https") => {}
+            Ok(_) => errors.push(FieldError { field: "options.callbackUrl".into(), message: "must be http(s)".into() }),
+            Err(e) => errors.push(FieldError { field: "options.callbackUrl".into(), message: e.to_string() }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ValidationErrorResponse { message: "invalid design".into(), errors });
+    }
+    Ok((design, callback_url))
+}
+
+/// Validate without starting a job: 400 with the field errors like `POST /render`, otherwise the
+/// merged design with the defaults a render would use filled in, plus warnings.
+async fn validate_render(Json(env): Json<DesignEnvelope>) -> Result<Json<types::ValidateResponse>, axum::response::Response> {
+    let (mut design, _) = prepare_design(env).map_err(invalid_design)?;
+    let warnings = validate::design_warnings(&design);
+    let (width, height) = ffmpeg::output_size(&design);
+    design.size = Some(types::Size { width, height });
+    design.fps = Some(design.fps.unwrap_or(30));
+    design.format = ffmpeg::output_format(&design).ok().map(|f| f.extension().to_string());
+    Ok(Json(types::ValidateResponse { design, warnings }))
+}
+
+fn invalid_design(e: ValidationErrorResponse) -> axum::response::Response { (axum::http::StatusCode::BAD_REQUEST, Json(e)).into_response() }
+
+async fn submit_render(State(state): State<AppState>, headers: axum::http::HeaderMap, Json(env): Json<DesignEnvelope>) -> Result<impl IntoResponse, axum::response::Response> {
+    start_render(state, headers, env).await
+}
+
+/// `POST /render` with `options.draft` forced on: a quick low-res preview through the same pipeline.
+async fn submit_preview(State(state): State<AppState>, headers: axum::http::HeaderMap, Json(mut env): Json<DesignEnvelope>) -> Result<impl IntoResponse, axum::response::Response> {
+    env.options.get_or_insert_with(Default::default).draft = Some(true);
+    start_render(state, headers, env).await
+}
+
+/// Like `submit_render`, with the design fetched from `designUrl` (same URL policy as assets,
+/// capped at RENDER_MAX_BODY_BYTES). Fetch and parse failures are 400s on the designUrl field.
+async fn submit_render_from_url(State(state): State<AppState>, headers: axum::http::HeaderMap, Json(env): Json<DesignUrlEnvelope>) -> Result<impl IntoResponse, axum::response::Response> {
+    let design_error = |message: String| invalid_design(ValidationErrorResponse { message: "invalid designUrl".into(), errors: vec![FieldError { field: "designUrl".into(), message }] });
+    let fetch_headers = env.options.as_ref().map(|o| ffmpeg::header_map(o.headers.iter())).unwrap_or_default();
+    let bytes = ffmpeg::fetch_to_memory(&env.designUrl, &fetch_headers, &state.downloads, state.max_body).await
+        .map_err(|e| design_error(format!("fetch failed: {:#}", e)))?;
+    // serde_json's message carries the line and column of the problem
+    let design = serde_json::from_slice(&bytes).map_err(|e| design_error(format!("invalid design JSON: {}", e)))?;
+    start_render(state, headers, DesignEnvelope { design, options: env.options }).await
+}
+
+async fn start_render(state: AppState, headers: axum::http::HeaderMap, env: DesignEnvelope) -> Result<impl IntoResponse, axum::response::Response> {
+    if state.shutting_down.load(Ordering::SeqCst) { return Err((axum::http::StatusCode::SERVICE_UNAVAILABLE, "server shutting down").into_response()); }
+    if let Some(resp) = queue_full(&state).await { return Err(resp); }
+    let (design, callback_url) = prepare_design(env).map_err(invalid_design)?;
+    let (code, resp) = launch_job(&state, design, callback_url, idempotency_key(&headers), HashMap::new()).await?;
+    // 202 with the status resource in Location; the body stays for existing clients
+    let location = [(axum::http::header::LOCATION, format!("/render/{}", resp.jobId))];
+    Ok((code, location, Json(resp)))
+}
+
+/// Run a finished job's design again as a new job, linking the files the old one already downloaded
+/// instead of fetching them again. 409 while the job is still in flight, or when it was reloaded
+/// from disk and its design is no longer known.
+async fn retry_render(State(state): State<AppState>, Path(id): Path<String>, headers: axum::http::HeaderMap) -> Result<impl IntoResponse, axum::response::Response> {
+    if state.shutting_down.load(Ordering::SeqCst) { return Err((axum::http::StatusCode::SERVICE_UNAVAILABLE, "server shutting down").into_response()); }
+    let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id").into_response())?;
+    let job = state.store.get(&uid).await.ok_or_else(|| (axum::http::StatusCode::NOT_FOUND, "not found").into_response())?;
+    if !job.status.is_terminal() { return Err((axum::http::StatusCode::CONFLICT, "job has not finished").into_response()); }
+    let (design, callback_url) = job.request.ok_or_else(|| (axum::http::StatusCode::CONFLICT, "job's design is not available (reloaded from disk); submit it again").into_response())?;
+    if let Some(resp) = queue_full(&state).await { return Err(resp); }
+    let (code, resp) = launch_job(&state, design, callback_url, idempotency_key(&headers), job.downloads).await?;
+    let location = [(axum::http::header::LOCATION, format!("/render/{}", resp.jobId))];
+    Ok((code, location, Json(resp)))
+}
+
+/// Retry-After for a full queue when no render has completed yet to estimate from.
+const QUEUE_FULL_RETRY_SECS: u64 = 30;
+
+/// 429 with a Retry-After when another job would take pending plus running past
+/// RENDER_MAX_QUEUE_DEPTH. The wait is about one average render per slot's worth of jobs over the limit.
+async fn queue_full(state: &AppState) -> Option<axum::response::Response> {
+    let max = state.max_queue_depth?;
+    let active = state.store.active_count().await;
+    if active < max { return None; }
+    let waves = (active + 1 - max).div_ceil(state.max_concurrent.max(1)) as u32;
+    let retry_after = state.store.average_render().map(|avg| (avg * waves).as_secs().max(1)).unwrap_or(QUEUE_FULL_RETRY_SECS);
+    let mut resp = (axum::http::StatusCode::TOO_MANY_REQUESTS, format!("render queue is full ({} jobs pending or running); retry later", active)).into_response();
+    resp.headers_mut().insert(axum::http::header::RETRY_AFTER, axum::http::HeaderValue::from(retry_after));
+    Some(resp)
+}
+
+/// Up to this many designs per `POST /render/batch`.
+const MAX_BATCH: usize = 1000;
+
+/// Submit several designs at once. Each is validated and started like `POST /render`; invalid ones
+/// are reported by index without holding up the rest. 202 if any job was created, else 400.
+async fn submit_batch(State(state): State<AppState>, headers: axum::http::HeaderMap, Json(envs): Json<Vec<DesignEnvelope>>) -> Result<impl IntoResponse, axum::response::Response> {
+    if state.shutting_down.load(Ordering::SeqCst) { return Err((axum::http::StatusCode::SERVICE_UNAVAILABLE, "server shutting down").into_response()); }
+    if envs.is_empty() || envs.len() > MAX_BATCH {
+        let message = format!("must hold 1 to {} designs, got {}", MAX_BATCH, envs.len());
+        return Err(invalid_design(ValidationErrorResponse { message: "invalid batch".into(), errors: vec![FieldError { field: "batch".into(), message }] }));
+    }
+    // a full queue turns the whole batch away; one that fills up partway rejects the rest by index
+    if let Some(resp) = queue_full(&state).await { return Err(resp); }
+    let key = idempotency_key(&headers);
+    let mut results = Vec::with_capacity(envs.len());
+    for (index, env) in envs.into_iter().enumerate() {
+        match prepare_design(env) {
+            Err(e) => results.push(types::BatchItemResult { index, jobId: None, status: None, errors: e.errors }),
+            Ok(_) if queue_full(&state).await.is_some() => {
+                results.push(types::BatchItemResult { index, jobId: None, status: None, errors: vec![FieldError { field: "queue".into(), message: "render queue is full; retry later".into() }] });
+            }
+            Ok((design, callback_url)) => {
+                // a retried batch maps each position to the job it created the first time
+                let key = key.map(|k| format!("{}:{}", k, index));
+                let (_, resp) = launch_job(&state, design, callback_url, key.as_deref(), HashMap::new()).await?;
+                results.push(types::BatchItemResult { index, jobId: Some(resp.jobId), status: Some(resp.status), errors: Vec::new() });
+            }
+        }
+    }
+    let accepted = results.iter().filter(|r| r.jobId.is_some()).count();
+    let code = if accepted > 0 { axum::http::StatusCode::ACCEPTED } else { axum::http::StatusCode::BAD_REQUEST };
+    Ok((code, Json(types::BatchResponse { accepted, rejected: results.len() - accepted, results })))
+}
+
+fn idempotency_key(headers: &axum::http::HeaderMap) -> Option<&str> {
+    headers.get("idempotency-key").and_then(|v| v.to_str().ok()).map(str::trim).filter(|k| !k.is_empty())
+}
+
+/// Create the job for a validated design and spawn its worker: 202 and the new job, or 200 and the
+/// existing one when `idempotency_key` was seen before.
+async fn launch_job(state: &AppState, design: types::Design, callback_url: Option<String>, idempotency_key: Option<&str>, reuse: HashMap<String, PathBuf>) -> Result<(axum::http::StatusCode, SubmitResponse), axum::response::Response> {
+    let mut job = Job::new(&state.jobs_root);
+    job.priority = design.priority.as_deref().and_then(jobs::Priority::parse).unwrap_or_default();
+    job.request = Some((design.clone(), callback_url.clone()));
+    tokio::fs::create_dir_all(&job.workdir).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
     let job_id = job.id;
     let job_dir = job.workdir.clone();
-    state.store.insert(job).await;
+    let status = job.to_status_response(&state.base_url, state.job_ttl);
 
     let store = state.store.clone();
     let caps = state.caps.clone();
+    let download_concurrency = state.download_concurrency;
+    let downloads = state.downloads.clone();
+    let render_slots = state.render_slots.clone();
+    let metrics = state.metrics.clone();
+    let (url_policy, s3) = (state.downloads.url_policy.clone(), state.s3.clone());
+    let job_timeout = state.job_timeout;
+
+    // Spawn worker. It waits for the job to be in the store, and the job goes in already holding the
+    // worker's handle, so a cancel can never find the job without a worker to stop.
+    let (start, started) = tokio::sync::oneshot::channel::<()>();
+    let worker = tokio::spawn(async move {
+        if started.await.is_err() { return; }
+        if let Err(e) = tokio::fs::create_dir_all(&job_dir).await { store.update(&job_id, |j| j.fail(ErrorCode::Internal, e.to_string())).await; return; }
 
-    // Spawn worker
-    tokio::spawn(async move {
-        if let Err(e) = tokio::fs::create_dir_all(&job_dir).await { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(e.to_string()); }).await; return; }
-
-        // Collect items with src and group by type, download assets
-        let mut assets: Vec<(usize, types::TrackItem, PathBuf)> = Vec::new();
-        let mut idx = 0usize;
-        let items: Vec<types::TrackItem> = if !design.trackItems.is_empty() { design.trackItems.clone() } else { design.trackItemsMap.values().cloned().collect() };
-        for it in items.into_iter() {
-            let src = it.details.as_ref().and_then(|d| d.src.clone());
-            if let Some(url) = src {
-                match ffmpeg::download_asset(&url, &job_dir).await {
-                    Ok(path) => { assets.push((idx, it, path)); idx += 1; },
-                    Err(e) => { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(format!("download failed: {}", e)); }).await; return; }
+        // Collect items with src and download them concurrently; position among src items is the input index
+        let sources = ffmpeg::asset_sources(&design);
+        let job_bytes = AtomicU64::new(0);
+        let (dir, downloads, job_bytes, reuse) = (&job_dir, &*downloads, &job_bytes, &reuse);
+        // each fetched file is noted on the job as it lands, so a retry after a failed download still skips it
+        let remember = |url: String, path: PathBuf| store.update(&job_id, move |j| { j.downloads.insert(url, path); });
+        let record_download_bytes = || metrics.download_bytes.fetch_add(job_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        let downloaded = stream::iter(sources.into_iter().enumerate())
+            .map(|(idx, (it, url))| {
+                let headers = ffmpeg::asset_headers(&design, &it);
+                async move {
+                    let path = match it.details.as_ref().and_then(|d| d.sequence.as_ref()) {
+                        Some(seq) => ffmpeg::download_sequence(&url, seq, &headers, &dir.join(format!("frames{}", idx)), downloads, job_bytes, download_concurrency).await,
+                        None => {
+                            let path = ffmpeg::download_or_reuse(&url, &headers, dir, downloads, job_bytes, reuse).await;
+                            if let Ok(p) = &path { remember(url.clone(), p.clone()).await; }
+                            path
+                        }
+                    };
+                    path.map(|path| (idx, it, path))
                 }
+            })
+            .buffer_unordered(download_concurrency)
+            .try_collect::<Vec<_>>()
+            .await;
+        let mut assets: Vec<(usize, types::TrackItem, PathBuf)> = match downloaded {
+            Ok(v) => v,
+            Err(e) => { record_download_bytes(); store.update(&job_id, |j| j.fail(ErrorCode::DownloadFailed, format!("download failed: {}", e))).await; return; }
+        };
+        assets.sort_by_key(|(idx, _, _)| *idx);
+
+        // Probe the sources: fail early on files that aren't the media they claim to be (error pages served
+        // with a 200), and learn the real frame rate and length
+        let mut design = design;
+        if caps.ffprobe {
+            let sources: Vec<(types::TrackItem, PathBuf)> = assets.iter().map(|(_, it, path)| (it.clone(), path.clone())).collect();
+            let probe_design = &design;
+            let probes: Vec<anyhow::Result<types::FfprobeInfo>> = stream::iter(sources)
+                .map(|(it, path)| async move { ffmpeg::probe_asset(probe_design, &it, &path).await })
+                .buffered(download_concurrency)
+                .collect()
+                .await;
+            if let Some(msg) = assets.iter().zip(&probes).find_map(|((_, it, _), probe)| ffmpeg::probe_error(it, probe)) {
+                store.update(&job_id, |j| j.fail(ErrorCode::InvalidAsset, msg)).await;
+                return;
             }
+            ffmpeg::apply_probes(&mut design, &probes.into_iter().map(Result::ok).collect::<Vec<_>>());
+            for ((_, it, _), (probed, _)) in assets.iter_mut().zip(ffmpeg::asset_sources(&design)) { *it = probed; }
         }
-        if assets.is_empty() { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some("no assets with 'src' found".into()); }).await; return; }
 
         // Download fonts for text items
-        let items2: Vec<types::TrackItem> = if !design.trackItems.is_empty() { design.trackItems.clone() } else { design.trackItemsMap.values().cloned().collect() };
-        let mut font_map: HashMap<String, PathBuf> = HashMap::new();
-        for it in items2.into_iter() {
-            if let types::TrackType::Text = it.kind {
-                if let Some(url) = it.details.as_ref().and_then(|d| d.fontUrl.clone()) {
-                    match ffmpeg::download_asset(&url, &job_dir).await {
-                        Ok(path) => { if let Some(id) = &it.id { font_map.insert(id.clone(), path); } },
-                        Err(e) => { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(format!("font download failed: {}", e)); }).await; return; }
-                    }
-                }
+        let font_map: HashMap<String, PathBuf> = match stream::iter(ffmpeg::font_sources(&design))
+            .map(|(id, url, headers)| async move {
+                let path = ffmpeg::download_or_reuse(&url, &headers, dir, downloads, job_bytes, reuse).await?;
+                remember(url, path.clone()).await;
+                Ok::<_, anyhow::Error>((id, path))
+            })
+            .buffer_unordered(download_concurrency)
+            .try_collect()
+            .await
+        {
+            Ok(m) => m,
+            Err(e) => { record_download_bytes(); store.update(&job_id, |j| j.fail(ErrorCode::FontDownloadFailed, format!("font download failed: {}", e))).await; return; }
+        };
+
+        // Download the subtitle file, if any
+        let subtitles = match &design.subtitles {
+            Some(sub) => match ffmpeg::download_or_reuse(&sub.src, &ffmpeg::design_headers(&design), dir, downloads, job_bytes, reuse).await {
+                Ok(p) => { remember(sub.src.clone(), p.clone()).await; Some(p) }
+                Err(e) => { record_download_bytes(); store.update(&job_id, |j| j.fail(ErrorCode::DownloadFailed, format!("subtitle download failed: {}", e))).await; return; }
+            },
+            None => None,
+        };
+        let background = match ffmpeg::background_image(&design) {
+            Some(src) => match ffmpeg::download_or_reuse(src, &ffmpeg::design_headers(&design), dir, downloads, job_bytes, reuse).await {
+                Ok(p) => { remember(src.to_string(), p.clone()).await; Some(p) }
+                Err(e) => { record_download_bytes(); store.update(&job_id, |j| j.fail(ErrorCode::DownloadFailed, format!("background download failed: {}", e))).await; return; }
+            },
+            None => None,
+        };
+        let watermark = match &design.watermark {
+            Some(wm) => match ffmpeg::download_or_reuse(&wm.src, &ffmpeg::design_headers(&design), dir, downloads, job_bytes, reuse).await {
+                Ok(p) => { remember(wm.src.clone(), p.clone()).await; Some(p) }
+                Err(e) => { record_download_bytes(); store.update(&job_id, |j| j.fail(ErrorCode::DownloadFailed, format!("watermark download failed: {}", e))).await; return; }
+            },
+            None => None,
+        };
+        record_download_bytes();
+
+        // Refuse inputs outside RENDER_ALLOWED_FORMATS before any ffmpeg demuxer parses them
+        if let Some(allowed) = &downloads.allowed_formats {
+            let mut rejected = assets.iter()
+                .find_map(|(_, it, path)| ffmpeg::format_error(path, it.probe.as_ref(), allowed).map(|e| format!("asset {}: {}", ffmpeg::item_label(it), e)));
+            for (name, path) in [("background", background.as_deref()), ("watermark", watermark.as_deref())] {
+                let Some(path) = path.filter(|_| rejected.is_none()) else { continue };
+                let probe = if caps.ffprobe { ffmpeg::probe_media(path).await.ok() } else { None };
+                rejected = ffmpeg::format_error(path, probe.as_ref(), allowed).map(|e| format!("{}: {}", name, e));
             }
+            if let Some(msg) = rejected { store.update(&job_id, |j| j.fail(ErrorCode::InvalidAsset, msg)).await; return; }
         }
 
         // Build command
-        let built = match ffmpeg::build_ffmpeg_command(&job_dir, &design, &assets.iter().map(|(i,it,p)|( *i, it, p.clone())).collect::<Vec<_>>(), &caps, &font_map) {
+        let inputs = assets.iter().map(|(i,it,p)|( *i, it, p.clone())).collect::<Vec<_>>();
+        let files = ffmpeg::DesignFiles { subtitles: subtitles.as_deref(), background: background.as_deref(), watermark: watermark.as_deref() };
+        let built = match ffmpeg::build_ffmpeg_command(&job_dir, &design, &inputs, &caps, &font_map, files) {
             Ok(b) => b,
-            Err(e) => { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(format!("build failed: {}", e)); }).await; return; }
+            Err(e) => { store.update(&job_id, |j| j.fail(ErrorCode::BuildFailed, format!("build failed: {}", e))).await; return; }
         };
+        // each rendition is a separate encode of the same composition, built up front so a bad one fails before rendering
+        let mut renditions = Vec::new();
+        for r in design.renditions.iter().flatten() {
+            match ffmpeg::build_ffmpeg_command(&job_dir, &ffmpeg::rendition_design(&design, r), &inputs, &caps, &font_map, files) {
+                Ok(b) => renditions.push((r.name.clone(), b)),
+                Err(e) => { store.update(&job_id, |j| j.fail(ErrorCode::BuildFailed, format!("build failed: rendition {}: {}", r.name, e))).await; return; }
+            }
+        }
 
-        // Run ffmpeg
-        tracing::info!("Launching ffmpeg: ffmpeg {}", built.args.join(" "));
-
-        store.update(&job_id, |j| j.status = JobStatus::Running).await;
-        let mut cmd = Command::new("ffmpeg");
-        for a in &built.args { cmd.arg(a); }
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
-        let mut child = match cmd.spawn() { Ok(c) => c, Err(e) => { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(format!("spawn failed: {}", e)); }).await; return; } };
-
-        let stdout = child.stdout.take().unwrap();
-        let stderr = child.stderr.take().unwrap();
-
-        // Drain stderr to avoid pipe blocking; also log errors
-        let store_err = store.clone();
-        let job_err = job_id.clone();
-        let mut err_reader = BufReader::new(stderr).lines();
-        tokio::spawn(async move {
-            while let Ok(Some(line)) = err_reader.next_line().await {
-                tracing::warn!("ffmpeg stderr: {}", line);
-                // Optional: detect failure keywords and mark job as failed early if desired
-                if line.contains("Error") || line.contains("error:") {
-                    // just log; final status handled after wait()
-                    let _ = &store_err; let _ = &job_err;
-                }
+        for (path, text) in &built.text_files {
+            if let Err(e) = tokio::fs::write(path, text).await {
+                store.update(&job_id, |j| j.fail(ErrorCode::BuildFailed, format!("build failed: writing {}: {}", path.display(), e))).await;
+                return;
             }
-        });
+        }
 
-        // Read progress from stdout
-        let total_ms = ffmpeg::compute_duration_ms(&design) as f64;
-        let mut out_reader = BufReader::new(stdout).lines();
-        while let Ok(Some(line)) = out_reader.next_line().await {
-            if let Some(val) = line.strip_prefix("out_time_ms=") {
-                if let Ok(mut n) = val.trim().parse::<u64>() {
-                    // Some ffmpeg builds output microseconds here; normalize to ms if too large
-                    if (n as f64) > total_ms * 1000.0 { n /= 1000; }
-                    let pct = ((n as f64 / total_ms) * 100.0).clamp(0.0, 100.0) as u32;
-                    store.update(&job_id, |j| j.progress = pct).await;
+        let (args, first_pass) = (ffmpeg::redact_args(&built.args), built.first_pass.as_deref().map(ffmpeg::redact_args));
+        store.update(&job_id, |j| { j.ffmpeg_args = Some(args); j.first_pass_args = first_pass; }).await;
+
+        // Wait for a render slot; the job stays Pending meanwhile. The permit is held until this task
+        // ends, so it is returned on success, failure, panic or cancellation alike.
+        let (priority, seq) = store.get(&job_id).await.map(|j| (j.priority, j.seq)).unwrap_or_default();
+        let _permit = match render_slots.acquire(priority, seq).await {
+            Ok(p) => p,
+            Err(e) => { store.update(&job_id, |j| j.fail(ErrorCode::QueueClosed, format!("render queue closed: {}", e))).await; return; }
+        };
+
+        // Run ffmpeg: the main output, then each rendition, with equal shares of the progress; a
+        // two-pass encode splits its share between the passes
+        store.update(&job_id, |j| { j.status = JobStatus::Running; j.started_at = Some(jobs::now_since_epoch()); j.reset_progress(); }).await;
+        let total_ms = ffmpeg::output_duration_ms(&design);
+        // the timeout covers every pass of every encode
+        let deadline = job_timeout.map(|t| tokio::time::Instant::now() + t);
+        let bin = ffmpeg::ffmpeg_bin();
+        let encodes: Vec<(Option<&str>, &ffmpeg::BuiltCommand)> = std::iter::once((None, &built)).chain(renditions.iter().map(|(n, b)| (Some(n.as_str()), b))).collect();
+        let n = encodes.len() as u32;
+        let mut result = Ok(());
+        for (i, (name, b)) in (0..).zip(&encodes) {
+            let (lo, hi) = (i * 100 / n, (i + 1) * 100 / n);
+            if let Some(first) = &b.first_pass {
+                result = run_ffmpeg(&bin, first, &store, job_id, total_ms, (lo, (lo + hi) / 2), deadline).await;
+            }
+            if result.is_ok() {
+                let range = if b.first_pass.is_some() { ((lo + hi) / 2, hi) } else { (lo, hi) };
+                result = run_ffmpeg(&bin, &b.args, &store, job_id, total_ms, range, deadline).await;
+            }
+            if b.first_pass.is_some() { ffmpeg::remove_pass_logs(&job_dir).await; }
+            // a killed encode leaves a truncated file behind
+            if matches!(&result, Err(f) if f.code == ErrorCode::TimedOut) { let _ = tokio::fs::remove_file(&b.output_path).await; }
+            if let Err(f) = &mut result {
+                if let Some(name) = name { f.message = format!("rendition {}: {}", name, f.message); }
+                break;
+            }
+        }
+        let rendition_paths: BTreeMap<String, PathBuf> = renditions.iter().map(|(n, b)| (n.clone(), b.output_path.clone())).collect();
+        match result {
+            Ok(()) => {
+                let out = built.output_path.clone();
+                // upload before completing, so a COMPLETED job's url is always the final one
+                let mut remote_url = None;
+                if let Some(dest) = design.output.as_ref().and_then(|o| upload::Destination::parse(&o.destination).ok()) {
+                    let content_type = ffmpeg::output_format(&design).map(|f| f.content_type()).unwrap_or("application/octet-stream");
+                    match upload::upload(&dest, &out, content_type, &url_policy, s3.as_deref()).await {
+                        Ok(url) => remote_url = Some(url),
+                        Err(e) => { store.update(&job_id, |j| { j.fail(ErrorCode::UploadFailed, format!("upload failed: {:#}", e)); j.output_path = Some(out); j.renditions = rendition_paths; }).await; return; }
+                    }
                 }
+                store.update(&job_id, |j| { j.status = JobStatus::Completed; j.progress = 100; j.output_path = Some(out); j.renditions = rendition_paths; j.remote_url = remote_url; j.duration_ms = Some(total_ms); }).await;
             }
+            Err(f) => { store.update(&job_id, |j| { j.fail(f.code, f.message); j.error_detail = f.detail; }).await; }
         }
+    });
+    // closed once the worker's JoinHandle resolves, by the finish task below
+    let (ended, worker_ended) = tokio::sync::watch::channel(());
+    job.worker = Some(jobs::Worker::new(worker.abort_handle(), worker_ended));
+    let workdir = job.workdir.clone();
+    // A retried submission with the same Idempotency-Key gets the job the first one created; its
+    // worker never starts
+    match idempotency_key {
+        Some(key) => if let Err(existing) = state.store.insert_idempotent(key, job, state.idempotency_ttl).await {
+            let _ = tokio::fs::remove_dir_all(&workdir).await;
+            let status = existing.to_status_response(&state.base_url, state.job_ttl);
+            return Ok((axum::http::StatusCode::OK, SubmitResponse { jobId: existing.id.to_string(), status }));
+        },
+        None => { state.store.insert(job).await; }
+    }
+    Metrics::inc(&state.metrics.jobs_submitted);
+    let _ = start.send(());
+
+    // Once the worker has finished, however it exited, record the outcome and deliver the callback
+    let workers = state.workers.clone();
+    let state = state.clone();
+    let finish = tokio::spawn(async move {
+        let outcome = worker.await;
+        drop(ended);
+        finish_job(&state, job_id, outcome, callback_url).await;
+    });
+    let mut workers = workers.lock().unwrap_or_else(|e| e.into_inner());
+    workers.retain(|h| !h.is_finished());
+    workers.push(finish);
+    drop(workers);
+
+    Ok((axum::http::StatusCode::ACCEPTED, SubmitResponse { jobId: job_id.to_string(), status }))
+}
 
-        let status = child.wait().await;
-        match status {
-            Ok(s) if s.success() => {
-                let out = job_dir.join("output.mp4");
-                store.update(&job_id, |j| { j.status = JobStatus::Completed; j.progress = 100; j.output_path = Some(out); }).await;
+/// Record how a worker ended and deliver the callback. A cancelled worker is left to cancel_render;
+/// one that panicked leaves its job mid-flight, so the job is failed here and reported like any other.
+async fn finish_job(state: &AppState, job_id: uuid::Uuid, outcome: Result<(), tokio::task::JoinError>, callback_url: Option<String>) {
+    match outcome {
+        Ok(()) => {}
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            let msg = panic.downcast_ref::<&str>().copied().or(panic.downcast_ref::<String>().map(String::as_str)).unwrap_or("unknown panic");
+            tracing::error!(id = %job_id, "render worker panicked: {}", msg);
+            state.store.update(&job_id, |j| if !j.status.is_terminal() { j.fail(ErrorCode::Internal, "internal error: the render worker crashed") }).await;
+        }
+        Err(_) => return,
+    }
+    let Some(job) = state.store.get(&job_id).await else { return };
+    match job.status {
+        JobStatus::Completed => {
+            Metrics::inc(&state.metrics.jobs_completed);
+            if let Some(started) = job.started_at {
+                let elapsed = jobs::now_since_epoch().saturating_sub(started);
+                state.metrics.observe_render(elapsed);
+                state.store.record_render(elapsed);
             }
-            Ok(s) => { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(format!("ffmpeg exit status: {}", s)); }).await; }
-            Err(e) => { store.update(&job_id, |j| { j.status = JobStatus::Failed; j.error = Some(format!("wait failed: {}", e)); }).await; }
         }
-    });
+        JobStatus::Failed => Metrics::inc(&state.metrics.jobs_failed),
+        _ => return,
+    }
+    if let Some(cb) = callback_url {
+        let payload = WebhookPayload { jobId: job_id.to_string(), status: job.to_status_response(&state.base_url, state.job_ttl) };
+        let cfg = &state.downloads;
+        match net::post_json(&cfg.url_policy, &cb, &payload, cfg.retries, cfg.retry_base_delay).await {
+            Ok(()) => info!(id = %job_id, "Delivered webhook to {}", cb),
+            Err(e) => tracing::warn!(id = %job_id, "webhook delivery to {} failed: {:#}", cb, e),
+        }
+    }
+}
+
+/// Build the ffmpeg argv for a design without downloading or running anything. Source URLs stand
+/// in for the downloaded files; `?checkAssets=true` also sends a HEAD request to each remote asset.
+async fn dry_run(State(state): State<AppState>, Query(q): Query<DryRunQuery>, Json(env): Json<DesignEnvelope>) -> Result<Json<DryRunResponse>, axum::response::Response> {
+    let (design, _) = prepare_design(env).map_err(invalid_design)?;
+    let sources = ffmpeg::asset_sources(&design);
+    let fonts = ffmpeg::font_sources(&design);
+
+    let mut asset_errors = Vec::new();
+    if q.checkAssets.unwrap_or(false) {
+        let urls: Vec<(String, reqwest::header::HeaderMap)> = sources.iter().map(|(it, u)| (u.clone(), ffmpeg::asset_headers(&design, it)))
+            .chain(fonts.iter().map(|(_, u, h)| (u.clone(), h.clone())))
+            .chain(design.subtitles.iter().map(|s| (s.src.clone(), ffmpeg::design_headers(&design))))
+            .chain(ffmpeg::background_image(&design).map(|s| (s.to_string(), ffmpeg::design_headers(&design))))
+            .chain(design.watermark.iter().map(|w| (w.src.clone(), ffmpeg::design_headers(&design)))).collect();
+        let policy = &state.downloads.url_policy;
+        asset_errors = stream::iter(urls)
+            .map(|(url, headers)| async move { net::head_check(policy, &url, headers).await.err().map(|e| FieldError { field: url, message: format!("{:#}", e) }) })
+            .buffer_unordered(state.download_concurrency)
+            .filter_map(|e| async move { e })
+            .collect()
+            .await;
+        asset_errors.sort_by(|a, b| a.field.cmp(&b.field));
+    }
+
+    // data: URIs would bloat the argv, so they get a short placeholder instead
+    let placeholder = |n: usize, src: &str| PathBuf::from(if src.get(..5).is_some_and(|p| p.eq_ignore_ascii_case("data:")) { format!("data-uri-{}", n) } else { src.to_string() });
+    let assets: Vec<(usize, &types::TrackItem, PathBuf)> = sources.iter().enumerate().map(|(i, (it, src))| (i, it, placeholder(i, src))).collect();
+    let font_map: HashMap<String, PathBuf> = fonts.iter().enumerate().map(|(i, (id, url, _))| (id.clone(), placeholder(assets.len() + i, url))).collect();
+    let subtitles = design.subtitles.as_ref().map(|s| placeholder(assets.len() + fonts.len(), &s.src));
+    let background = ffmpeg::background_image(&design).map(|s| placeholder(assets.len() + fonts.len() + 1, s));
+    let watermark = design.watermark.as_ref().map(|w| placeholder(assets.len() + fonts.len() + 2, &w.src));
+    let files = ffmpeg::DesignFiles { subtitles: subtitles.as_deref(), background: background.as_deref(), watermark: watermark.as_deref() };
+    let built = ffmpeg::build_ffmpeg_command(&state.jobs_root.join("dryrun"), &design, &assets, &state.caps, &font_map, files)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, format!("build failed: {}", e)).into_response())?;
+    let (width, height) = ffmpeg::encoded_size(&design);
+    Ok(Json(DryRunResponse { args: built.args, first_pass: built.first_pass, duration_ms: ffmpeg::output_duration_ms(&design), width, height, asset_errors, timeline: ffmpeg::resolve_timeline(&design) }))
+}
+
+/// How long a `POST /probe` result is reused, and how many are kept.
+const PROBE_CACHE_TTL: Duration = Duration::from_secs(600);
+const PROBE_CACHE_MAX: usize = 1000;
+
+/// Fetch a media URL like an asset (URL policy, size limits) into a scratch dir, ffprobe it and
+/// report what an editor needs before placing it. 422 when the file isn't media.
+async fn probe_url(State(state): State<AppState>, Json(req): Json<types::ProbeRequest>) -> Result<Json<types::ProbeResponse>, (axum::http::StatusCode, String)> {
+    if !state.caps.ffprobe { return Err((axum::http::StatusCode::SERVICE_UNAVAILABLE, "ffprobe is not available".into())); }
+    // remote URLs only: local paths and data URIs are for designs, not for probing the server's disk
+    if !url::Url::parse(&req.url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")) { return Err((axum::http::StatusCode::BAD_REQUEST, "url must be http(s)".into())); }
+    let key = hex::encode(Sha256::digest(req.url.as_bytes()));
+    if let Some((at, resp)) = state.probe_cache.lock().await.get(&key) {
+        if at.elapsed() < PROBE_CACHE_TTL { return Ok(Json(resp.clone())); }
+    }
+    let dir = state.jobs_root.join("probe").join(uuid::Uuid::new_v4().to_string());
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let probed = async {
+        let path = ffmpeg::download_asset(&req.url, &reqwest::header::HeaderMap::new(), &dir, &state.downloads, &AtomicU64::new(0)).await
+            .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, format!("download failed: {:#}", e)))?;
+        ffmpeg::probe_media(&path).await.map_err(|e| (axum::http::StatusCode::UNPROCESSABLE_ENTITY, format!("not a media file: {:#}", e)))
+    }.await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    let info = probed?;
+    if !info.has_video && !info.has_audio { return Err((axum::http::StatusCode::UNPROCESSABLE_ENTITY, "not a media file: no audio or video stream".into())); }
+    let resp = types::ProbeResponse { width: info.width, height: info.height, durationMs: info.duration_ms, hasAudio: info.has_audio, fps: info.fps, codec: info.video_codec.or(info.audio_codec) };
+    let mut cache = state.probe_cache.lock().await;
+    cache.retain(|_, (at, _)| at.elapsed() < PROBE_CACHE_TTL);
+    if cache.len() >= PROBE_CACHE_MAX {
+        if let Some(oldest) = cache.iter().min_by_key(|(_, (at, _))| *at).map(|(k, _)| k.clone()) { cache.remove(&oldest); }
+    }
+    cache.insert(key, (std::time::Instant::now(), resp.clone()));
+    Ok(Json(resp))
+}
+
+async fn healthz(State(state): State<AppState>) -> (axum::http::StatusCode, Json<HealthResponse>) {
+    // Capabilities are probed once at startup; a missing ffmpeg means every render would fail
+    let ok = state.caps.ffmpeg;
+    let code = if ok { axum::http::StatusCode::OK } else { axum::http::StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(HealthResponse { ok, caps: state.caps.clone(), active_jobs: state.store.active_count().await }))
+}
+
+async fn metrics_text(State(state): State<AppState>) -> impl IntoResponse {
+    let (running, pending) = state.store.in_flight_counts().await;
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render(running, pending))
+}
 
-    Ok(Json(SubmitResponse { jobId: job_id.to_string() }))
+async fn list_jobs(State(state): State<AppState>, Query(q): Query<ListQuery>) -> Json<Vec<JobSummary>> {
+    Json(state.store.list(q.status.as_deref()).await)
 }
 
 async fn get_status(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<StatusResponse>, (axum::http::StatusCode, String)> {
     let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
     match state.store.get(&uid).await {
-        Some(job) => Ok(Json(job.to_status_response(&state.base_url))),
+        Some(job) => {
+            let mut resp = job.to_status_response(&state.base_url, state.job_ttl);
+            if matches!(job.status, JobStatus::Pending) {
+                let ahead = state.store.pending_ahead(&job).await;
+                resp.queue_position = Some(ahead);
+                // everything ahead plus this job, spread over the render slots
+                resp.queue_wait_seconds = state.store.average_render()
+                    .map(|avg| (avg.as_secs_f64() * (ahead + 1) as f64 / state.max_concurrent.max(1) as f64).round() as u32);
+            }
+            Ok(Json(resp))
+        }
         None => Err((axum::http::StatusCode::NOT_FOUND, "not found".into())),
     }
 }
 
-async fn get_output(State(state): State<AppState>, Path(id): Path<String>) -> Result<axum::response::Response, (axum::http::StatusCode, String)> {
+/// Everything needed to reproduce or debug a render: argv, encoder caps and the ffmpeg stderr tail.
+async fn get_logs(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<LogsResponse>, (axum::http::StatusCode, String)> {
+    let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
+    let job = state.store.get(&uid).await.ok_or((axum::http::StatusCode::NOT_FOUND, "not found".into()))?;
+    Ok(Json(LogsResponse { args: job.ffmpeg_args, first_pass: job.first_pass_args, caps: state.caps.clone(), stderr: job.ffmpeg_stderr }))
+}
+
+async fn cancel_render(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<StatusResponse>, (axum::http::StatusCode, String)> {
+    let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
+    let mut job = state.store.remove(&uid).await.ok_or((axum::http::StatusCode::NOT_FOUND, "not found".into()))?;
+    // Only in-flight jobs have a worker worth stopping; finished jobs just get their files removed
+    if let JobStatus::Pending | JobStatus::Running = job.status {
+        // wait for the worker to be gone, so neither ffmpeg nor a download is still writing to the workdir
+        if let Some(worker) = job.worker.take() { worker.stop().await; }
+        job.status = JobStatus::Cancelled;
+        job.notify();
+        Metrics::inc(&state.metrics.jobs_cancelled);
+    }
+    job.output_path = None;
+    job.renditions.clear();
+    remove_workdir(&job).await;
+    Ok(Json(job.to_status_response(&state.base_url, state.job_ttl)))
+}
+
+async fn progress_events(State(state): State<AppState>, Path(id): Path<String>) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (axum::http::StatusCode, String)> {
+    let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
+    let job = state.store.get(&uid).await.ok_or((axum::http::StatusCode::NOT_FOUND, "not found".into()))?;
+    // Finished (or rehydrated) jobs get a single final event; live jobs stream until they reach a terminal state
+    let rx = if job.status.is_terminal() { None } else { job.subscribe() };
+    let stream = stream::unfold((Some((job.status, job.progress)), rx), |(pending, mut rx)| async move {
+        let (status, progress) = match pending {
+            Some(ev) => ev,
+            None => {
+                let r = rx.as_mut()?;
+                r.changed().await.ok()?;
+                r.borrow_and_update().clone()
+            }
+        };
+        // Stop right after forwarding a terminal status
+        if status.is_terminal() { rx = None; }
+        let ev = Event::default().json_data(ProgressEvent { status: status.as_str().into(), progress });
+        Some((ev, (None, rx)))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+async fn get_thumbnail(State(state): State<AppState>, Path(id): Path<String>, Query(q): Query<ThumbnailQuery>) -> Result<axum::response::Response, (axum::http::StatusCode, String)> {
+    let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
+    let job = state.store.get(&uid).await.ok_or((axum::http::StatusCode::NOT_FOUND, "not found".into()))?;
+    let output = match (&job.status, &job.output_path) {
+        (JobStatus::Completed, Some(p)) => p.clone(),
+        _ => return Err((axum::http::StatusCode::BAD_REQUEST, "not ready".into())),
+    };
+    if ffmpeg::OutputFormat::from_path(&output).is_some_and(|f| !f.has_video()) { return Err((axum::http::StatusCode::BAD_REQUEST, "audio-only render has no frames".into())); }
+    // default to the middle of the timeline
+    let at_ms = match q.t {
+        Some(t) if t.is_finite() && t >= 0.0 => (t * 1000.0).round() as u64,
+        Some(_) => return Err((axum::http::StatusCode::BAD_REQUEST, "invalid t".into())),
+        None => job.duration_ms.unwrap_or(0) / 2,
+    };
+    if job.duration_ms.is_some_and(|d| at_ms > d) { return Err((axum::http::StatusCode::BAD_REQUEST, "t is past the end of the render".into())); }
+    let thumb = job.workdir.join(format!("thumbnail-{}.jpg", at_ms));
+    if !thumb.exists() {
+        ffmpeg::extract_frame(&output, at_ms as f64 / 1000.0, &thumb).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    let bytes = tokio::fs::read(&thumb).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut resp = axum::response::Response::new(bytes.into());
+    resp.headers_mut().insert(axum::http::header::CONTENT_TYPE, axum::http::HeaderValue::from_static("image/jpeg"));
+    Ok(resp)
+}
+
+/// Parse a single `bytes=` range against a body of `len` bytes into an inclusive `(start, end)`.
+/// None means serve the whole body (no header, another unit, or several ranges); Some(Err) is a 416.
+fn parse_byte_range(header: Option<&axum::http::HeaderValue>, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header?.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') { return None; }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-N: the last N bytes
+        (Err(_), Ok(n)) if start.is_empty() => if n == 0 || len == 0 { Err(()) } else { Ok((len.saturating_sub(n), len - 1)) },
+        (Ok(s), Err(_)) if end.is_empty() => if s < len { Ok((s, len - 1)) } else { Err(()) },
+        (Ok(s), Ok(e)) if s <= e => if s < len { Ok((s, e.min(len - 1))) } else { Err(()) },
+        _ => return None, // malformed ranges are ignored
+    };
+    Some(range)
+}
+
+async fn get_output(State(state): State<AppState>, Path(id): Path<String>, headers: axum::http::HeaderMap) -> Result<axum::response::Response, (axum::http::StatusCode, String)> {
     let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
     if let Some(job) = state.store.get(&uid).await {
-        if let Some(path) = job.output_path {
-            let bytes = tokio::fs::read(&path).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            let mut resp = axum::response::Response::new(bytes.into());
-            resp.headers_mut().insert(axum::http::header::CONTENT_TYPE, axum::http::HeaderValue::from_static("video/mp4"));
-            Ok(resp)
-        } else { Err((axum::http::StatusCode::BAD_REQUEST, "not ready".into())) }
+        if let Some(path) = job.output_path { serve_output(&path, &headers).await } else { Err((axum::http::StatusCode::BAD_REQUEST, "not ready".into())) }
     } else {
         Err((axum::http::StatusCode::NOT_FOUND, "not found".into()))
     }
 }
 
+/// One of the job's options.renditions; 404 for a name the job didn't ask for.
+async fn get_rendition_output(State(state): State<AppState>, Path((id, name)): Path<(String, String)>, headers: axum::http::HeaderMap) -> Result<axum::response::Response, (axum::http::StatusCode, String)> {
+    let uid = uuid::Uuid::parse_str(&id).map_err(|_| (axum::http::StatusCode::BAD_REQUEST, "invalid id".into()))?;
+    let job = state.store.get(&uid).await.ok_or((axum::http::StatusCode::NOT_FOUND, "not found".into()))?;
+    if let Some(path) = job.renditions.get(&name) { return serve_output(path, &headers).await; }
+    // the names are only known from the request until the job completes
+    let requested = job.request.as_ref().and_then(|(d, _)| d.renditions.as_ref()).is_some_and(|rs| rs.iter().any(|r| r.name == name));
+    if requested && !job.status.is_terminal() { Err((axum::http::StatusCode::BAD_REQUEST, "not ready".into())) } else { Err((axum::http::StatusCode::NOT_FOUND, "no such rendition".into())) }
+}
+
+/// Stream a finished output file, honoring a single byte range.
+async fn serve_output(path: &std::path::Path, headers: &axum::http::HeaderMap) -> Result<axum::response::Response, (axum::http::StatusCode, String)> {
+    // stream from disk so large outputs aren't buffered per download
+    let mut file = tokio::fs::File::open(path).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let len = file.metadata().await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.len();
+    let mut resp = match parse_byte_range(headers.get(axum::http::header::RANGE), len) {
+        None => {
+            let mut resp = axum::response::Response::new(axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)));
+            resp.headers_mut().insert(axum::http::header::CONTENT_LENGTH, axum::http::HeaderValue::from(len));
+            resp
+        }
+        Some(Err(())) => {
+            let mut resp = axum::http::StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            if let Ok(v) = axum::http::HeaderValue::from_str(&format!("bytes */{}", len)) { resp.headers_mut().insert(axum::http::header::CONTENT_RANGE, v); }
+            return Ok(resp);
+        }
+        Some(Ok((start, end))) => {
+            use tokio::io::{AsyncReadExt, AsyncSeekExt};
+            file.seek(std::io::SeekFrom::Start(start)).await.map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            let part = end - start + 1;
+            let mut resp = axum::response::Response::new(axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file.take(part))));
+            *resp.status_mut() = axum::http::StatusCode::PARTIAL_CONTENT;
+            resp.headers_mut().insert(axum::http::header::CONTENT_LENGTH, axum::http::HeaderValue::from(part));
+            if let Ok(v) = axum::http::HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) { resp.headers_mut().insert(axum::http::header::CONTENT_RANGE, v); }
+            resp
+        }
+    };
+    resp.headers_mut().insert(axum::http::header::ACCEPT_RANGES, axum::http::HeaderValue::from_static("bytes"));
+    let content_type = ffmpeg::OutputFormat::from_path(path).unwrap_or(ffmpeg::OutputFormat::Mp4).content_type();
+    resp.headers_mut().insert(axum::http::header::CONTENT_TYPE, axum::http::HeaderValue::from_static(content_type));
+    if let Some(name) = path.file_name().map(|n| n.to_string_lossy().to_string()) {
+        if let Ok(v) = axum::http::HeaderValue::from_str(&ffmpeg::content_disposition(&name)) { resp.headers_mut().insert(axum::http::header::CONTENT_DISPOSITION, v); }
+    }
+    Ok(resp)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// No render slots: jobs download their assets and then wait, so no test runs ffmpeg.
+    fn test_state(root: &std::path::Path) -> AppState {
+        AppState {
+            store: JobStore::default(), base_url: "http://127.0.0.1".into(),
+            caps: ffmpeg::BackendCaps { ffmpeg: true, nvenc: false, qsv: false, vaapi: false, videotoolbox: false, ffprobe: false },
+            download_concurrency: 2, downloads: Arc::new(ffmpeg::DownloadConfig::from_env()), render_slots: queue::RenderQueue::new(0), max_concurrent: 1,
+            jobs_root: root.to_path_buf(), job_ttl: Duration::from_secs(3600), metrics: Default::default(), max_body: 1 << 20, idempotency_ttl: Duration::from_secs(60),
+            workers: Default::default(), shutting_down: Default::default(), api_token: None, job_timeout: None, max_queue_depth: None, s3: None, probe_cache: Default::default(),
+        }
+    }
+
+    /// Serve the API over `state` on an ephemeral port; returns its base URL.
+    async fn serve(state: AppState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(state)).await });
+        format!("http://{}", addr)
+    }
+
+    async fn post(url: &str, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new().post(url).header(reqwest::header::CONTENT_TYPE, "application/json").body(body.to_string()).send().await.unwrap()
+    }
+
+    /// A design whose one asset "downloads" without a network or RENDER_LOCAL_ROOTS.
+    fn one_clip() -> serde_json::Value { json!({ "design": { "trackItems": [{ "type": "video", "details": { "src": "data:video/mp4;base64,AAAA" } }] } }) }
+
+    async fn add_job(state: &AppState, status: JobStatus) -> uuid::Uuid {
+        let mut job = Job::new(&state.jobs_root);
+        job.status = status;
+        state.store.insert(job).await
+    }
+
+    #[tokio::test]
+    async fn submissions_past_the_queue_depth_get_429() {
+        let root = tempfile::tempdir().unwrap();
+        let mut state = test_state(root.path());
+        state.max_queue_depth = Some(2);
+        let running = add_job(&state, JobStatus::Running).await;
+        add_job(&state, JobStatus::Pending).await;
+        let base = serve(state.clone()).await;
+
+        let resp = post(&format!("{}/render", base), &one_clip()).await;
+        assert_eq!(resp.status(), 429);
+        // nothing has rendered yet to estimate from
+        assert_eq!(resp.headers()[axum::http::header::RETRY_AFTER], "30");
+        let resp = post(&format!("{}/render/preview", base), &one_clip()).await;
+        assert_eq!(resp.status(), 429);
+
+        // one render's worth of jobs over the limit on one slot: about one average render
+        state.store.record_render(Duration::from_secs(12));
+        let resp = post(&format!("{}/render", base), &one_clip()).await;
+        assert_eq!(resp.headers()[axum::http::header::RETRY_AFTER], "12");
+        assert_eq!(state.store.active_count().await, 2);
+
+        // a batch fills the one free place and reports the rest by index
+        state.store.update(&running, |j| j.status = JobStatus::Completed).await;
+        let resp = post(&format!("{}/render/batch", base), &json!([one_clip(), one_clip()])).await;
+        assert_eq!(resp.status(), 202);
+        let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        assert_eq!((body["accepted"].as_u64(), body["rejected"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(body["results"][1]["errors"][0]["field"], "queue");
+
+        // finished jobs, failed ones included, free their places
+        add_job(&state, JobStatus::Running).await;
+        assert_eq!(post(&format!("{}/render", base), &one_clip()).await.status(), 429);
+        state.store.fail_unfinished(ErrorCode::Interrupted, "test").await;
+        let resp = post(&format!("{}/render", base), &one_clip()).await;
+        assert_eq!(resp.status(), 202);
+    }
+
+    #[tokio::test]
+    async fn no_limit_without_a_queue_depth() {
+        let root = tempfile::tempdir().unwrap();
+        let state = test_state(root.path());
+        for _ in 0..5 { add_job(&state, JobStatus::Pending).await; }
+        let resp = post(&format!("{}/render", serve(state).await), &one_clip()).await;
+        assert_eq!(resp.status(), 202);
+    }
+
+    #[test]
+    fn explicit_size_beats_the_resolution_preset() {
+        let merged = |opts: serde_json::Value| {
+            let mut design: types::Design = serde_json::from_value(json!({ "size": { "width": 640, "height": 360 } })).unwrap();
+            merge_options(&mut design, serde_json::from_value(opts).unwrap());
+            design.size.map(|s| (s.width, s.height))
+        };
+        assert_eq!(merged(json!({ "resolution": "vertical-1080" })), Some((1080, 1920)));
+        assert_eq!(merged(json!({ "resolution": "vertical-1080", "size": { "width": 800, "height": 600 } })), Some((800, 600)));
+        assert_eq!(merged(json!({})), Some((640, 360)));
+    }
+
+    #[tokio::test]
+    async fn outputs_stream_from_disk_in_full() {
+        let root = tempfile::tempdir().unwrap();
+        let state = test_state(root.path());
+        let out = root.path().join("out.mp4");
+        let body: Vec<u8> = (0..8 << 20).map(|i: u32| (i % 251) as u8).collect();
+        std::fs::write(&out, &body).unwrap();
+        let id = add_job(&state, JobStatus::Completed).await;
+        state.store.update(&id, |j| j.output_path = Some(out)).await;
+
+        let resp = reqwest::get(format!("{}/render/{}/output", serve(state).await, id)).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()[reqwest::header::CONTENT_LENGTH], body.len().to_string().as_str());
+        assert_eq!(resp.headers()[reqwest::header::CONTENT_TYPE], "video/mp4");
+        assert!(resp.bytes().await.unwrap() == body);
+    }
+
+    /// An ffmpeg stand-in in `dir`: lists h264_qsv for `-encoders`, otherwise reports 1s of progress and hangs.
+    #[cfg(unix)]
+    fn stub_ffmpeg(dir: &std::path::Path) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let stub = dir.join("ffmpeg");
+        std::fs::write(&stub, "#!/bin/sh\ncase \"$*\" in *-encoders*) echo ' V....D h264_qsv  H.264 (Intel Quick Sync Video)'; exit 0;; esac\necho out_time_us=1000000\necho progress=continue\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+        stub.to_string_lossy().into_owned()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn caps_come_from_the_configured_binaries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let missing = tmp.path().join("missing").to_string_lossy().into_owned();
+        let caps = ffmpeg::detect_caps(&stub_ffmpeg(tmp.path()), &missing).await;
+        assert!(caps.ffmpeg && caps.qsv && !caps.nvenc && !caps.vaapi && !caps.videotoolbox && !caps.ffprobe);
+        // no ffmpeg, no encoders
+        let caps = ffmpeg::detect_caps(&missing, &missing).await;
+        assert!(!caps.ffmpeg && !caps.qsv);
+    }
+
+    #[tokio::test]
+    async fn only_the_api_token_gets_past_the_middleware() {
+        let root = tempfile::tempdir().unwrap();
+        let mut state = test_state(root.path());
+        state.api_token = Some("s3cret".into());
+        let base = serve(state).await;
+        let client = reqwest::Client::new();
+        let submit = |auth: Option<&str>| {
+            let req = client.post(format!("{}/render", base)).header(reqwest::header::CONTENT_TYPE, "application/json").body(one_clip().to_string());
+            match auth { Some(a) => req.header(reqwest::header::AUTHORIZATION, a), None => req }.send()
+        };
+
+        let resp = submit(None).await.unwrap();
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers()[reqwest::header::WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(submit(Some("Bearer s3cre")).await.unwrap().status(), 401);
+        assert_eq!(submit(Some("Bearer s3cret!")).await.unwrap().status(), 401);
+        assert_eq!(submit(Some("s3cret")).await.unwrap().status(), 401);
+        assert_eq!(submit(Some("Bearer s3cret")).await.unwrap().status(), 202);
+        // health checks stay open
+        assert_eq!(client.get(format!("{}/healthz", base)).send().await.unwrap().status(), 200);
+    }
+
+    #[tokio::test]
+    async fn jobs_work_under_the_configured_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("nested/jobs");
+        assert_eq!(jobs_root_from(Some(root.clone().into())).await.unwrap(), root);
+        assert!(root.is_dir());
+        assert!(jobs_root_from(Some("relative/jobs".into())).await.unwrap_err().to_string().contains("absolute"));
+
+        let base = serve(test_state(&root)).await;
+        let resp = post(&format!("{}/render", base), &one_clip()).await;
+        assert_eq!(resp.status(), 202);
+        let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        let dir = root.join(body["jobId"].as_str().unwrap());
+        let downloaded = || std::fs::read_dir(&dir).ok()?.filter_map(Result::ok).find(|e| e.file_name().to_string_lossy().ends_with("inline.mp4"));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while downloaded().is_none() {
+            assert!(tokio::time::Instant::now() < deadline, "asset never landed in {}", dir.display());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn ffmpeg_is_killed_at_the_deadline() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = JobStore::default();
+        let id = store.insert(Job::new(tmp.path())).await;
+        let started = tokio::time::Instant::now();
+        let result = run_ffmpeg(&stub_ffmpeg(tmp.path()), &[], &store, id, 4000, (0, 100), Some(started + Duration::from_millis(300))).await;
+        assert!(matches!(result, Err(RunFailure { code: ErrorCode::TimedOut, .. })));
+        // the stub would sleep for 30s
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // 1s of a 4s timeline
+        assert_eq!(store.get(&id).await.unwrap().progress, 25);
+    }
+
+    /// Wait up to 5s for `job` to have downloaded `url`; returns where it put it.
+    async fn downloaded(state: &AppState, job: &uuid::Uuid, url: &str) -> PathBuf {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(path) = state.store.get(job).await.and_then(|j| j.downloads.get(url).cloned()) { return path; }
+            assert!(tokio::time::Instant::now() < deadline, "job {} never downloaded {}", job, url);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn retrying_a_failed_job_reuses_its_downloads() {
+        let (files, log) = ffmpeg::tests::file_server(HashMap::from([("/clip.mp4", b"movie".to_vec())])).await;
+        let url = format!("{}/clip.mp4", files);
+        let root = tempfile::tempdir().unwrap();
+        let mut state = test_state(root.path());
+        state.downloads = Arc::new(ffmpeg::tests::test_config());
+        let base = serve(state.clone()).await;
+        let resp = post(&format!("{}/render", base), &json!({ "design": { "trackItems": [{ "type": "video", "details": { "src": url } }] } })).await;
+        let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        let first: uuid::Uuid = body["jobId"].as_str().unwrap().parse().unwrap();
+        downloaded(&state, &first, &url).await;
+        state.store.update(&first, |j| j.fail(ErrorCode::FfmpegExit, "test")).await;
+
+        let resp = post(&format!("{}/render/{}/retry", base, first), &json!({})).await;
+        assert_eq!(resp.status(), 202);
+        let location = resp.headers()[axum::http::header::LOCATION].to_str().unwrap().to_string();
+        let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        let second: uuid::Uuid = body["jobId"].as_str().unwrap().parse().unwrap();
+        assert_ne!(second, first);
+        assert_eq!(location, format!("/render/{}", second));
+
+        // the new job has its own copy of the clip, and the file server saw only the first job's request
+        let path = downloaded(&state, &second, &url).await;
+        assert!(path.starts_with(root.path().join(second.to_string())));
+        assert_eq!(std::fs::read(&path).unwrap(), b"movie");
+        assert_eq!(log.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancel_waits_for_the_worker_before_removing_the_workdir() {
+        let root = tempfile::tempdir().unwrap();
+        let state = test_state(root.path());
+        let base = serve(state.clone()).await;
+        let resp = post(&format!("{}/render", base), &one_clip()).await;
+        let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        let id: uuid::Uuid = body["jobId"].as_str().unwrap().parse().unwrap();
+        // the job was stored with its worker handle, before the 202 went out
+        let worker = state.store.get(&id).await.unwrap().worker.expect("worker handle");
+
+        let resp = reqwest::Client::new().delete(format!("{}/render/{}", base, id)).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        assert_eq!(body["status"], "CANCELLED");
+        // by the time the response is out the worker has ended, and its workdir is gone for good
+        assert!(tokio::time::timeout(Duration::ZERO, worker.stop()).await.is_ok());
+        assert!(!root.path().join(id.to_string()).exists());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!root.path().join(id.to_string()).exists());
+    }
+
+    #[tokio::test]
+    async fn stopping_a_worker_returns_once_the_task_is_dropped() {
+        struct Dropped(Arc<AtomicBool>);
+        impl Drop for Dropped { fn drop(&mut self) { self.0.store(true, Ordering::SeqCst); } }
+        let spawn = || {
+            let dropped = Arc::new(AtomicBool::new(false));
+            let guard = Dropped(dropped.clone());
+            let task = tokio::spawn(async move { let _guard = guard; std::future::pending::<()>().await });
+            let (ended, rx) = tokio::sync::watch::channel(());
+            let worker = jobs::Worker::new(task.abort_handle(), rx);
+            tokio::spawn(async move { let _ = task.await; drop(ended); });
+            (worker, dropped)
+        };
+        // aborting alone returns before the task is gone
+        let (worker, dropped) = spawn();
+        worker.abort();
+        assert!(!dropped.load(Ordering::SeqCst));
+        let (worker, dropped) = spawn();
+        worker.stop().await;
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn a_panicked_worker_fails_its_job_and_still_calls_back() {
+        let root = tempfile::tempdir().unwrap();
+        let mut state = test_state(root.path());
+        let mut downloads = ffmpeg::DownloadConfig::from_env();
+        downloads.url_policy.allow_private = true;
+        downloads.retries = 0;
+        state.downloads = Arc::new(downloads);
+        let (tx, rx) = tokio::sync::oneshot::channel::<serde_json::Value>();
+        let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+        let hook = Router::new().route("/hook", axum::routing::post(move |Json(body): Json<serde_json::Value>| async move {
+            if let Some(tx) = tx.lock().unwrap().take() { let _ = tx.send(body); }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let callback = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, hook).await });
+
+        let id = add_job(&state, JobStatus::Running).await;
+        let outcome = tokio::spawn(async { panic!("boom") }).await;
+        finish_job(&state, id, outcome, Some(callback)).await;
+
+        let job = state.store.get(&id).await.unwrap();
+        assert!(matches!(job.status, JobStatus::Failed));
+        assert_eq!(job.error_code, Some(ErrorCode::Internal));
+        assert_eq!(state.metrics.jobs_failed.load(Ordering::Relaxed), 1);
+        let body = tokio::time::timeout(Duration::from_secs(5), rx).await.unwrap().unwrap();
+        assert_eq!((body["jobId"].as_str(), body["status"].as_str(), body["error_code"].as_str()), (Some(id.to_string().as_str()), Some("FAILED"), Some("Internal")));
+
+        // a cancelled worker is cancel_render's business
+        let id = add_job(&state, JobStatus::Running).await;
+        let task = tokio::spawn(std::future::pending::<()>());
+        task.abort();
+        finish_job(&state, id, task.await, None).await;
+        assert!(matches!(state.store.get(&id).await.unwrap().status, JobStatus::Running));
+        assert_eq!(state.metrics.jobs_failed.load(Ordering::Relaxed), 1);
+    }
+}
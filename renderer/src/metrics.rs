@@ -0,0 +1,56 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+/// Upper bounds (seconds) of the render duration histogram buckets; +Inf is implied.
+const RENDER_BUCKETS: [f64; 9] = [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+/// Process-wide counters, rendered in Prometheus text format by `GET /metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    pub jobs_submitted: AtomicU64,
+    pub jobs_completed: AtomicU64,
+    pub jobs_failed: AtomicU64,
+    pub jobs_cancelled: AtomicU64,
+    pub download_bytes: AtomicU64,
+    render_buckets: [AtomicU64; RENDER_BUCKETS.len()],
+    render_count: AtomicU64,
+    render_sum_ms: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) { counter.fetch_add(1, Ordering::Relaxed); }
+
+    /// Record the ffmpeg wall time of a completed render.
+    pub fn observe_render(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, count) in RENDER_BUCKETS.iter().zip(&self.render_buckets) {
+            if secs <= *bound { count.fetch_add(1, Ordering::Relaxed); }
+        }
+        self.render_count.fetch_add(1, Ordering::Relaxed);
+        self.render_sum_ms.fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Prometheus exposition text; `running`/`pending` are gauges read from the job store at scrape time.
+    pub fn render(&self, running: usize, pending: usize) -> String {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+        };
+        counter("renderer_jobs_submitted_total", "Render jobs accepted.", get(&self.jobs_submitted));
+        counter("renderer_jobs_completed_total", "Render jobs that finished successfully.", get(&self.jobs_completed));
+        counter("renderer_jobs_failed_total", "Render jobs that failed.", get(&self.jobs_failed));
+        counter("renderer_jobs_cancelled_total", "Render jobs cancelled through the API.", get(&self.jobs_cancelled));
+        counter("renderer_download_bytes_total", "Bytes of assets and fonts fetched for jobs.", get(&self.download_bytes));
+        let _ = writeln!(out, "# HELP renderer_jobs_running Jobs with ffmpeg running.\n# TYPE renderer_jobs_running gauge\nrenderer_jobs_running {running}");
+        let _ = writeln!(out, "# HELP renderer_jobs_pending Jobs downloading or waiting for a render slot.\n# TYPE renderer_jobs_pending gauge\nrenderer_jobs_pending {pending}");
+        let _ = writeln!(out, "# HELP renderer_render_duration_seconds ffmpeg wall time of completed renders.\n# TYPE renderer_render_duration_seconds histogram");
+        for (bound, count) in RENDER_BUCKETS.iter().zip(&self.render_buckets) {
+            let _ = writeln!(out, "renderer_render_duration_seconds_bucket{{le=\"{}\"}} {}", bound, get(count));
+        }
+        let total = get(&self.render_count);
+        let _ = writeln!(out, "renderer_render_duration_seconds_bucket{{le=\"+Inf\"}} {}", total);
+        let _ = writeln!(out, "renderer_render_duration_seconds_sum {}", get(&self.render_sum_ms) as f64 / 1000.0);
+        let _ = writeln!(out, "renderer_render_duration_seconds_count {}", total);
+        out
+    }
+}
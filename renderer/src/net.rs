@@ -0,0 +1,197 @@
+use serde::Serialize;
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, time::Duration};
+use url::{Host, Url};
+
+#[derive(Debug, thiserror::Error)]
+pub enum UrlError {
+    #[error("scheme '{0}' is not allowed for assets")]
+    Scheme(String),
+    #[error("url has no host")]
+    NoHost,
+    #[error("could not resolve host {0}")]
+    Resolve(String),
+    #[error("host {host} resolves to blocked address {addr}")]
+    Blocked { host: String, addr: IpAddr },
+    #[error("http client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+/// Which remote URLs the renderer is willing to fetch.
+#[derive(Clone, Debug, Default)]
+pub struct UrlPolicy {
+    /// Allow loopback/private/link-local targets (RENDER_ALLOW_PRIVATE_URLS=1).
+    pub allow_private: bool,
+    /// Trusted hosts (and their subdomains) that skip the address checks (RENDER_ALLOWED_HOSTS, comma separated).
+    pub allowed_hosts: Vec<String>,
+}
+
+impl UrlPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            allow_private: std::env::var("RENDER_ALLOW_PRIVATE_URLS").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            allowed_hosts: std::env::var("RENDER_ALLOWED_HOSTS")
+                .map(|v| v.split(',').map(|h| h.trim().trim_start_matches('.').to_ascii_lowercase()).filter(|h| !h.is_empty()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn is_trusted_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|h| host == *h || host.ends_with(&format!(".{}", h)))
+    }
+
+    /// Validate `url` and resolve its host. Returns the addresses the connection should be pinned to
+    /// so a second DNS lookup can't swap in a blocked address; empty when no pinning is needed.
+    pub async fn check(&self, url: &Url) -> Result<Vec<SocketAddr>, UrlError> {
+        if !matches!(url.scheme(), "http" | "https") { return Err(UrlError::Scheme(url.scheme().to_string())); }
+        let host = url.host().ok_or(UrlError::NoHost)?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let domain = match host {
+            Host::Ipv4(ip) => return self.check_ip(&ip.to_string(), IpAddr::V4(ip)).map(|_| Vec::new()),
+            Host::Ipv6(ip) => return self.check_ip(&ip.to_string(), IpAddr::V6(ip)).map(|_| Vec::new()),
+            Host::Domain(d) => d,
+        };
+        if self.allow_private || self.is_trusted_host(domain) { return Ok(Vec::new()); }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port)).await.map_err(|_| UrlError::Resolve(domain.to_string()))?.collect();
+        if addrs.is_empty() { return Err(UrlError::Resolve(domain.to_string())); }
+        for a in &addrs { self.check_ip(domain, a.ip())?; }
+        Ok(addrs)
+    }
+
+    /// Client for a single request to `url`: redirects are not followed and connections go only
+    /// to the addresses `check` approved.
+    pub async fn client_for(&self, url: &Url) -> Result<reqwest::Client, UrlError> {
+        let addrs = self.check(url).await?;
+        let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+        if let (Some(host), false) = (url.host_str(), addrs.is_empty()) { builder = builder.resolve_to_addrs(host, &addrs); }
+        Ok(builder.build()?)
+    }
+
+    fn check_ip(&self, host: &str, addr: IpAddr) -> Result<(), UrlError> {
+        if self.allow_private || self.is_trusted_host(host) || !is_blocked_ip(addr) { Ok(()) } else { Err(UrlError::Blocked { host: host.to_string(), addr }) }
+    }
+}
+
+/// POST `body` as JSON to `url` under `policy`, retrying connection errors, 5xx and 429 with
+/// exponential backoff starting at `base_delay`.
+pub async fn post_json<T: Serialize>(policy: &UrlPolicy, url: &str, body: &T, retries: u32, base_delay: Duration) -> anyhow::Result<()> {
+    let url = Url::parse(url)?;
+    let body = serde_json::to_vec(body)?;
+    let mut attempt = 0u32;
+    loop {
+        let client = policy.client_for(&url).await?;
+        let (err, retryable) = match client.post(url.clone()).header(reqwest::header::CONTENT_TYPE, "application/json").body(body.clone()).send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => {
+                let status = resp.status();
+                (anyhow::anyhow!("bad status {}", status), status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS)
+            }
+            Err(e) => { let retryable = e.is_connect() || e.is_timeout() || e.is_request(); (e.into(), retryable) }
+        };
+        if !retryable || attempt >= retries { return Err(err); }
+        attempt += 1;
+        let delay = base_delay * 2u32.saturating_pow(attempt - 1);
+        tracing::warn!(attempt, max = retries, ?delay, "retrying POST to {}: {:#}", url, err);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// HEAD `url` under `policy` to see whether it looks fetchable. Non-http(s) sources are not checked.
+pub async fn head_check(policy: &UrlPolicy, url: &str, headers: reqwest::header::HeaderMap) -> anyhow::Result<()> {
+    let Ok(url) = Url::parse(url) else { return Ok(()) };
+    if !matches!(url.scheme(), "http" | "https") { return Ok(()); }
+    let resp = policy.client_for(&url).await?.head(url).headers(headers).send().await?;
+    let status = resp.status();
+    // some servers don't implement HEAD; that says nothing about GET
+    if status.is_success() || status.is_redirection() || matches!(status.as_u16(), 405 | 501) { Ok(()) } else { Err(anyhow::anyhow!("bad status {}", status)) }
+}
+
+/// Loopback, private, link-local, CGNAT, unspecified, broadcast and multicast ranges.
+pub fn is_blocked_ip(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => is_blocked_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() { return is_blocked_v4(v4); }
+            let seg0 = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (seg0 & 0xfe00) == 0xfc00 // unique local fc00::/7
+                || (seg0 & 0xffc0) == 0xfe80 // link-local fe80::/10
+        }
+    }
+}
+
+fn is_blocked_v4(ip: Ipv4Addr) -> bool {
+    let o = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local() // includes 169.254.169.254 cloud metadata
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || o[0] == 0
+        || (o[0] == 100 && (o[1] & 0xc0) == 64) // CGNAT 100.64.0.0/10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKED: &[&str] = &[
+        "http://127.0.0.1/a.mp4",
+        "http://10.1.2.3/a.mp4",
+        "http://172.16.0.1/a.mp4",
+        "http://172.31.255.254/a.mp4",
+        "http://192.168.1.10/a.mp4",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://100.64.0.1/a.mp4",
+        "http://100.127.255.254/a.mp4",
+        "http://0.0.0.0/a.mp4",
+        "http://[::1]/a.mp4",
+        "http://[fc00::1]/a.mp4",
+        "http://[fd12:3456::1]/a.mp4",
+        "http://[fe80::1]/a.mp4",
+        "http://[::ffff:127.0.0.1]/a.mp4",
+    ];
+
+    #[tokio::test]
+    async fn blocked_ranges_are_rejected() {
+        let policy = UrlPolicy::default();
+        for url in BLOCKED {
+            let err = policy.check(&Url::parse(url).unwrap()).await.unwrap_err();
+            assert!(matches!(err, UrlError::Blocked { .. }), "{} gave {:?}", url, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn public_addresses_and_neighbours_of_blocked_ranges_pass() {
+        let policy = UrlPolicy::default();
+        for url in ["http://8.8.8.8/a.mp4", "http://172.32.0.1/a.mp4", "http://100.128.0.1/a.mp4", "https://[2606:4700::1111]/a.mp4"] {
+            assert!(policy.check(&Url::parse(url).unwrap()).await.is_ok(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn allow_private_bypasses_the_address_checks() {
+        let policy = UrlPolicy { allow_private: true, ..Default::default() };
+        for url in BLOCKED { assert!(policy.check(&Url::parse(url).unwrap()).await.is_ok(), "{}", url); }
+    }
+
+    #[tokio::test]
+    async fn allowed_hosts_bypass_the_address_checks() {
+        let policy = UrlPolicy { allowed_hosts: vec!["127.0.0.1".into(), "media.internal".into()], ..Default::default() };
+        assert!(policy.check(&Url::parse("http://127.0.0.1/a.mp4").unwrap()).await.is_ok());
+        // trusted domains and their subdomains are not resolved at all
+        assert!(policy.check(&Url::parse("http://media.internal/a.mp4").unwrap()).await.is_ok());
+        assert!(policy.check(&Url::parse("http://cdn.media.internal/a.mp4").unwrap()).await.is_ok());
+        assert!(matches!(policy.check(&Url::parse("http://10.0.0.1/a.mp4").unwrap()).await, Err(UrlError::Blocked { .. })));
+    }
+
+    #[tokio::test]
+    async fn other_schemes_are_rejected() {
+        for url in ["file:///etc/passwd", "ftp://example.com/a.mp4", "gopher://example.com/"] {
+            assert!(matches!(UrlPolicy::default().check(&Url::parse(url).unwrap()).await, Err(UrlError::Scheme(_))), "{}", url);
+        }
+    }
+}
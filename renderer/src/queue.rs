@@ -0,0 +1,156 @@
+use crate::jobs::Priority;
+use std::{cmp::Ordering, collections::BinaryHeap, sync::{Arc, Mutex}};
+use tokio::sync::oneshot;
+
+/// Render slots handed out by priority, then submission order, instead of in whatever order the
+/// waiting tasks happen to be woken.
+pub struct RenderQueue {
+    max: usize,
+    state: Mutex<QueueState>,
+}
+
+struct QueueState {
+    running: usize,
+    waiting: BinaryHeap<Waiter>,
+}
+
+struct Waiter {
+    priority: Priority,
+    seq: u64,
+    tx: oneshot::Sender<SlotPermit>,
+}
+
+// Max-heap: higher priority first, then the lower (earlier) seq.
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering { self.priority.cmp(&other.priority).then(other.seq.cmp(&self.seq)) }
+}
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+impl Eq for Waiter {}
+
+/// One running render. Dropping it passes the slot to the next waiter.
+pub struct SlotPermit {
+    queue: Option<Arc<RenderQueue>>,
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) { if let Some(q) = self.queue.take() { q.release(); } }
+}
+
+impl RenderQueue {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self { max, state: Mutex::new(QueueState { running: 0, waiting: BinaryHeap::new() }) })
+    }
+
+    /// Wait for a slot. `seq` is the job's submission order. Dropping the future gives up the place
+    /// in line (or the slot, if one was already handed over).
+    pub async fn acquire(self: &Arc<Self>, priority: Priority, seq: u64) -> Result<SlotPermit, oneshot::error::RecvError> {
+        let rx = {
+            let mut st = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if st.running < self.max && st.waiting.is_empty() {
+                st.running += 1;
+                return Ok(SlotPermit { queue: Some(self.clone()) });
+            }
+            let (tx, rx) = oneshot::channel();
+            st.waiting.push(Waiter { priority, seq, tx });
+            rx
+        };
+        rx.await
+    }
+
+    /// A slot came free: hand it to the best waiter still listening, else shrink the running count.
+    fn release(self: &Arc<Self>) {
+        let mut st = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(w) = st.waiting.pop() {
+            match w.tx.send(SlotPermit { queue: Some(self.clone()) }) {
+                Ok(()) => return,
+                // the waiter went away; disarm the permit so it doesn't release again under our lock
+                Err(mut p) => { p.queue = None; }
+            }
+        }
+        st.running -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::poll;
+    use std::task::Poll;
+
+    #[tokio::test]
+    async fn slots_go_by_priority_then_submission_order() {
+        let q = RenderQueue::new(1);
+        let held = q.acquire(Priority::Normal, 0).await.unwrap();
+        let mut low = Box::pin(q.acquire(Priority::Low, 1));
+        let mut high = Box::pin(q.acquire(Priority::High, 2));
+        let mut normal = Box::pin(q.acquire(Priority::Normal, 3));
+        assert!(poll!(&mut low).is_pending());
+        assert!(poll!(&mut high).is_pending());
+        assert!(poll!(&mut normal).is_pending());
+
+        drop(held);
+        let Poll::Ready(Ok(p)) = poll!(&mut high) else { panic!("high priority job wasn't served first") };
+        assert!(poll!(&mut low).is_pending());
+        assert!(poll!(&mut normal).is_pending());
+        drop(p);
+        let Poll::Ready(Ok(p)) = poll!(&mut normal) else { panic!("normal priority job wasn't next") };
+        assert!(poll!(&mut low).is_pending());
+        drop(p);
+        assert!(matches!(poll!(&mut low), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn same_priority_goes_by_seq() {
+        let q = RenderQueue::new(1);
+        let held = q.acquire(Priority::Normal, 0).await.unwrap();
+        let mut later = Box::pin(q.acquire(Priority::Normal, 7));
+        let mut earlier = Box::pin(q.acquire(Priority::Normal, 3));
+        assert!(poll!(&mut later).is_pending());
+        assert!(poll!(&mut earlier).is_pending());
+        drop(held);
+        let Poll::Ready(Ok(_p)) = poll!(&mut earlier) else { panic!("later submission was served first") };
+        assert!(poll!(&mut later).is_pending());
+    }
+
+    #[tokio::test]
+    async fn dropped_waiters_give_up_their_place() {
+        let q = RenderQueue::new(1);
+        let held = q.acquire(Priority::Normal, 0).await.unwrap();
+        let mut gone = Box::pin(q.acquire(Priority::High, 1));
+        let mut next = Box::pin(q.acquire(Priority::Low, 2));
+        assert!(poll!(&mut gone).is_pending());
+        assert!(poll!(&mut next).is_pending());
+        // cancelled while queued: skipped
+        drop(gone);
+        drop(held);
+        let Poll::Ready(Ok(p)) = poll!(&mut next) else { panic!("slot wasn't passed on past the dropped waiter") };
+        drop(p);
+        // everything released: the next caller gets a slot straight away
+        let p = q.acquire(Priority::Low, 3).await.unwrap();
+
+        // cancelled after the slot was handed over but before it was picked up: passed on again
+        let mut handed = Box::pin(q.acquire(Priority::High, 4));
+        let mut after = Box::pin(q.acquire(Priority::Normal, 5));
+        assert!(poll!(&mut handed).is_pending());
+        assert!(poll!(&mut after).is_pending());
+        drop(p);
+        drop(handed);
+        assert!(matches!(poll!(&mut after), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn runs_up_to_max_at_once() {
+        let q = RenderQueue::new(2);
+        let a = q.acquire(Priority::Normal, 0).await.unwrap();
+        let _b = q.acquire(Priority::Normal, 1).await.unwrap();
+        let mut c = Box::pin(q.acquire(Priority::High, 2));
+        assert!(poll!(&mut c).is_pending());
+        drop(a);
+        assert!(matches!(poll!(&mut c), Poll::Ready(Ok(_))));
+    }
+}
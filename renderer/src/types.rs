@@ -1,11 +1,78 @@
+#![allow(non_snake_case)] // field names mirror the editor's camelCase JSON
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct RenderOptions {
     pub fps: Option<u32>,
     pub size: Option<Size>,
+    pub resolution: Option<String>,  // named size ("1080p", "vertical-1080", ...); options.size wins over it
+    pub format: Option<String>,
+    pub audioMix: Option<String>,    // "limiter" (default) | "dynaudnorm" | "none", applied when mixing several sources
+    pub callbackUrl: Option<String>, // POSTed a WebhookPayload when the job completes or fails
+    pub startMs: Option<u64>,        // render only [startMs, endMs) of the timeline
+    pub defaultDurationMs: Option<u64>, // timeline length when no item has a known end; default 10000
+    pub outputName: Option<String>,  // file name for the output; sanitized, extension follows format
+    pub alpha: Option<bool>,         // transparent canvas + alpha-capable encoder; mov (ProRes 4444) or webm (VP9) only
+    pub mute: Option<bool>,          // silent output: audio items are skipped and no audio stream is written
+    pub interpolate: Option<bool>,   // motion-interpolate video sources with a lower fps than the output (slow)
+    pub toneMap: Option<String>,     // HDR sources into the SDR output: "hable" (default) | "mobius" | "reinhard" | "clip" | "none"
+    pub draft: Option<bool>,         // fast throwaway preview: half size, <=15 fps, fastest encoder settings, no blur/interpolation
+    pub subtitles: Option<Subtitles>, // burned-in captions from an SRT/VTT file
+    pub background: Option<String>,  // canvas: a CSS color, or an image URL scaled to fill; default black
+    pub watermark: Option<Watermark>, // logo overlaid on top of everything for the whole output
+    pub output: Option<OutputTarget>, // upload the finished file instead of only serving it from the job dir
+    pub priority: Option<String>,     // "low" | "normal" (default) | "high": order among jobs waiting for a render slot
+    pub renditions: Option<Vec<Rendition>>, // extra outputs of the same render, e.g. 720p next to the 1080p main output
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // sent with every remote asset/font request, e.g. Authorization
+    pub endMs: Option<u64>,
+    #[serde(flatten)]
+    pub quality: Quality,
+}
+
+/// Encoder quality knobs; everything unset keeps the encoder defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Quality {
+    pub videoBitrate: Option<String>, // e.g. "8M"; exclusive with crf
+    pub crf: Option<u32>,
+    pub audioBitrate: Option<String>, // e.g. "320k"
+    pub preset: Option<String>,       // encoder preset, e.g. "slow" (x264) or "p7" (nvenc)
+    pub pixFmt: Option<String>,
+    pub profile: Option<String>,      // e.g. "high"
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subtitles {
+    pub src: String,             // SRT/VTT URL, fetched like other assets; cue times are timeline times
+    pub fontSize: Option<u32>,   // overrides the file's style
+    pub color: Option<String>,   // "#rrggbb", "#rrggbbaa" or "rgba(r,g,b,a)"
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watermark {
+    pub src: String,              // image URL, data URI or path, fetched like other assets
+    pub position: Option<String>, // "top-left" | "top" | "top-right" | "left" | "center" | "right" | "bottom-left" | "bottom" | "bottom-right" (default)
+    pub x: Option<i32>,           // explicit position in output pixels; overrides the anchor on that axis
+    pub y: Option<i32>,
+    pub margin: Option<u32>,      // gap to the frame edge for anchors, default 24
+    pub scale: Option<f32>,       // of the image's own size, default 1
+    pub opacity: Option<f32>,     // 0-100 like item opacity, default 100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputTarget {
+    pub destination: String, // "s3://bucket/key" or a presigned PUT URL
+}
+
+/// One extra output of a job, served at `/render/:id/output/<name>`. Unset fields follow the main output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rendition {
+    pub name: String,                 // 1-32 of [A-Za-z0-9_-], unique within the job
+    pub size: Option<Size>,           // encoded size; the frame is fitted inside it, letterboxed if the aspect differs
     pub format: Option<String>,
+    pub videoBitrate: Option<String>, // replaces the main output's crf/videoBitrate
+    pub audioBitrate: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +87,13 @@ pub struct DesignEnvelope {
     pub options: Option<RenderOptions>,
 }
 
+/// `POST /render/from-url`: the design JSON is fetched from `designUrl` instead of sent inline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesignUrlEnvelope {
+    pub designUrl: String,
+    pub options: Option<RenderOptions>, // options.headers are also sent with the design request
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Design {
     pub id: Option<String>,
@@ -29,6 +103,30 @@ pub struct Design {
     pub trackItemsMap: HashMap<String, TrackItem>,
     pub size: Option<Size>,
     pub fps: Option<u32>,
+    pub format: Option<String>, // merged from RenderOptions.format
+    #[serde(default)]
+    pub quality: Quality, // merged from RenderOptions
+    pub audioMix: Option<String>, // merged from RenderOptions.audioMix
+    pub startMs: Option<u64>, // merged from RenderOptions
+    pub endMs: Option<u64>,
+    pub defaultDurationMs: Option<u64>, // merged from RenderOptions
+    pub outputName: Option<String>, // merged from RenderOptions
+    pub alpha: Option<bool>, // merged from RenderOptions
+    pub mute: Option<bool>, // merged from RenderOptions
+    pub interpolate: Option<bool>, // merged from RenderOptions
+    pub draft: Option<bool>, // merged from RenderOptions
+    pub toneMap: Option<String>, // merged from RenderOptions
+    pub subtitles: Option<Subtitles>, // merged from RenderOptions
+    pub background: Option<String>, // merged from RenderOptions
+    pub watermark: Option<Watermark>, // merged from RenderOptions
+    pub output: Option<OutputTarget>, // merged from RenderOptions
+    pub priority: Option<String>, // merged from RenderOptions
+    pub renditions: Option<Vec<Rendition>>, // merged from RenderOptions
+    /// Size to encode at instead of the output size; set by the worker for each rendition.
+    #[serde(skip)]
+    pub encodeSize: Option<Size>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // merged from RenderOptions
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +136,7 @@ pub enum TrackType { Video, Image, Audio, Text, }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackItem {
     pub id: Option<String>,
+    pub order: Option<i64>, // explicit input/layer order; otherwise list position or map key
     #[serde(rename = "type")]
     pub kind: TrackType,
     pub details: Option<Details>,
@@ -45,6 +144,25 @@ pub struct TrackItem {
     pub trim: Trim,
     #[serde(default)]
     pub display: Trim,
+    /// What ffprobe found in the downloaded source; set by the worker, never taken from requests.
+    #[serde(skip)]
+    pub probe: Option<FfprobeInfo>,
+}
+
+/// Stream facts about a downloaded asset, from ffprobe.
+#[derive(Debug, Clone, Default)]
+pub struct FfprobeInfo {
+    pub fps: Option<f64>,         // average frame rate of the first video stream
+    pub duration_ms: Option<u64>, // container duration
+    pub has_video: bool,          // images count as video
+    pub has_audio: bool,
+    pub video_codec: Option<String>, // e.g. "h264", "png", "gif", "apng"
+    pub color_transfer: Option<String>, // e.g. "bt709", "smpte2084" (PQ), "arib-std-b67" (HLG)
+    pub format_name: Option<String>, // container with its aliases, e.g. "mov,mp4,m4a,3gp,3g2,mj2"
+    pub audio_channels: Option<u32>, // of the first audio stream
+    pub audio_codec: Option<String>,
+    pub width: Option<u32>,          // of the first video stream
+    pub height: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -53,39 +171,297 @@ pub struct Trim {
     pub to: Option<u64>,   // ms
 }
 
+/// An item dimension: a plain number of pixels, or a CSS-style string ("640px", "640", "50%").
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Length { Px(f32), Css(String) }
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Details {
     pub src: Option<String>,
-    pub width: Option<u32>,
-    pub height: Option<u32>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // extra request headers for src/fontUrl; override the design-wide ones
+    pub width: Option<Length>,     // px number, or "640px" / "50%" of the output width
+    pub height: Option<Length>,
     pub opacity: Option<f32>,      // 0-100
     pub volume: Option<f32>,       // 0-100
-    pub left: Option<String>,      // e.g., "100px"
-    pub top: Option<String>,       // e.g., "200px"
+    pub audioFadeMs: Option<u64>,  // audio fade in and out length
+    pub channelLayout: Option<String>, // audio: the source's real layout when its file is untagged or wrong ("mono", "stereo", "5.1", ...)
+    pub downmix: Option<String>,   // audio: "stereo" | "mono" | "left" / "right" (that channel alone on both sides); default stereo for >2 channels
+    pub speed: Option<f32>,        // video/audio playback rate; 2 = twice as fast, 0.5 = slow motion
+    pub left: Option<String>,      // e.g., "100px", "100" or "50%" of the output width
+    pub top: Option<String>,       // e.g., "200px" or "25%" of the output height
     pub transform: Option<String>, // e.g., "scale(1.25)"
     pub brightness: Option<f32>,   // default 100
+    pub contrast: Option<f32>,     // default 100
+    pub saturation: Option<f32>,   // default 100; 0 = grayscale
+    pub gamma: Option<f32>,        // default 1.0
+    pub blur: Option<f32>,         // gaussian blur sigma in pixels, 0-100
+    pub sharpen: Option<f32>,      // unsharp mask amount, 0-5
     // extended support
     pub flipX: Option<bool>,
     pub flipY: Option<bool>,
     pub rotate: Option<String>,    // e.g., "90deg" or "-45deg"
+    pub r#loop: Option<bool>,      // video: repeat when shorter than its display window
+    pub onEnd: Option<String>,     // video shorter than its window: "hold" the last frame, "loop" (same as loop: true) or "blank"
+    pub fadeInMs: Option<u64>,
+    pub fadeOutMs: Option<u64>,
+    pub zIndex: Option<i32>,       // layering; higher draws on top, defaults to track position
+    pub borderRadius: Option<String>, // video/image corner radius: "24px" or "50%" (circle for square items)
+    pub shadowColor: Option<String>,  // enables a drop shadow; "#rrggbb", "#rrggbbaa" or "rgba(r,g,b,a)"
+    pub shadowX: Option<i32>,         // px offset
+    pub shadowY: Option<i32>,
+    pub shadowBlur: Option<u32>,      // px; video/image only, drawtext can't blur
+    pub chromaKey: Option<ChromaKey>, // make a key color transparent (green screen)
+    pub kenBurns: Option<KenBurns>,   // image only: zoom/pan across the display window
+    pub transition: Option<Transition>, // how this item comes in after the clip before it
+    pub blendMode: Option<String>,    // video/image: "normal" (default), "multiply", "screen", "overlay", "add", "darken", "lighten", ...
+    pub fit: Option<String>,          // video/image into width x height: "stretch"/"fill" (default), "contain", "cover"
+    pub fill: Option<Fill>,           // image without src: generated color or gradient of width x height
+    pub colorSpace: Option<String>,   // video/image source: "bt709" | "bt2020-pq" | "bt2020-hlg"; overrides the probed transfer
+    pub sequence: Option<FrameSequence>, // video whose src is a numbered frame pattern ("frame_%04d.png")
+    #[serde(default)]
+    pub keyframes: Vec<Keyframe>,  // linear animation of position/scale/opacity
     // text-only fields
     pub text: Option<String>,
     pub fontFamily: Option<String>,
     pub fontUrl: Option<String>,
     pub fontSize: Option<u32>,
+    pub lineHeight: Option<String>, // CSS: "normal", "1.4", "32px", "120%"
+    pub maxWidth: Option<u32>,      // px; wraps text when set
+    pub textAlign: Option<String>,  // "left" | "center" | "right"
+    pub shaping: Option<bool>,      // draw through libass (bidi + shaping); default: when the text has RTL/complex scripts
     pub color: Option<String>,
     pub borderColor: Option<String>,
     pub borderWidth: Option<u32>,
+    #[serde(default)]
+    pub strokes: Vec<Stroke>,            // layered outlines, drawn widest first; borderColor/borderWidth counts as one more
+    pub backgroundColor: Option<String>, // draws a box behind text when set
+    pub boxPadding: Option<u32>,         // px around the text inside the box
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChromaKey {
+    pub color: String,           // "#00ff00", "rgb(0,255,0)"
+    pub similarity: Option<f32>, // 0-1, how close to the key color gets removed; default 0.3
+    pub blend: Option<f32>,      // 0-1, soft edge around the keyed area; default 0.1
+}
+
+/// Generated content for an image item instead of a downloaded src: a color ("#ff0000", "rgba(...)",
+/// a name) or a linear gradient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Fill { Color(String), Gradient(Gradient) }
+
+/// Colors are spread evenly along the gradient line; the angle follows CSS (0 = to top, 90 = to right).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gradient {
+    pub stops: Vec<String>,  // 2-8 colors
+    pub angle: Option<f32>,  // degrees, default 180 (top to bottom)
+}
+
+/// Numbered still frames played as a video. The item's src holds a printf-style frame number
+/// (`%d` or `%04d`), filled in with `start`, `start + 1`, ... for `count` frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameSequence {
+    pub count: u32,
+    pub start: Option<u32>, // first frame number, default 0
+    pub fps: Option<f32>,   // default the output fps
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stroke {
+    pub color: String,
+    pub width: u32, // px
+}
+
+/// Transition into an item from the one that ends where it starts. The item is brought in
+/// `durationMs` early and faded in over the previous clip; for audio the previous clip fades out too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    #[serde(rename = "type")]
+    pub kind: Option<String>, // "fade" (default; "crossfade" is an alias)
+    pub durationMs: u64,
 }
 
+/// Pan-and-zoom for still images. Zoom is a multiplier of the image (>= 1); x/y are the 0-1
+/// position of the visible window within the zoomed image (0.5 = centered).
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SubmitResponse { pub jobId: String }
+pub struct KenBurns {
+    pub startZoom: Option<f32>, // default 1
+    pub endZoom: Option<f32>,   // default 1.2
+    pub startX: Option<f32>,    // default 0.5
+    pub startY: Option<f32>,
+    pub endX: Option<f32>,
+    pub endY: Option<f32>,
+}
+
+/// One animation point; unset fields don't take part in that property's interpolation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub timeMs: u64,          // relative to the item's display start
+    pub left: Option<f32>,    // px
+    pub top: Option<f32>,     // px
+    pub scale: Option<f32>,   // multiplier of the item's width/height
+    pub opacity: Option<f32>, // 0-100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationErrorResponse {
+    pub message: String,
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmitResponse {
+    pub jobId: String,
+    #[serde(flatten)]
+    pub status: StatusResponse, // current status; for a replayed Idempotency-Key, the existing job's
+}
+
+/// `POST /render/batch` result, one entry per submitted design in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub results: Vec<BatchItemResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub jobId: Option<String>,           // set when the job was created
+    pub status: Option<StatusResponse>,
+    pub errors: Vec<FieldError>,         // validation errors when it wasn't
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StatusResponse {
     pub status: String,
     pub progress: u32,
     pub url: Option<String>,
+    pub output_name: Option<String>, // file name the output is saved and downloaded as
+    pub renditions: Option<BTreeMap<String, String>>, // rendition name -> download URL, once completed
     pub error: Option<String>,
+    pub error_code: Option<crate::jobs::ErrorCode>,
+    pub retryable: Option<bool>, // set alongside error_code
+    pub error_detail: Option<String>, // tail of ffmpeg stderr for FfmpegExit / TimedOut
+    pub expires_in: Option<u64>, // seconds until cleanup removes the job
+    pub eta_seconds: Option<u32>, // estimated time left while running
+    pub queue_position: Option<usize>, // while pending: pending jobs that start first (higher priority, or same and earlier)
+    pub queue_wait_seconds: Option<u32>, // while pending: rough wait from recent render times
+    pub priority: crate::jobs::Priority,
 }
 
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub id: String,
+    pub status: String,
+    pub progress: u32,
+    pub age_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListQuery {
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThumbnailQuery {
+    pub t: Option<f64>, // seconds
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DryRunQuery {
+    pub checkAssets: Option<bool>, // HEAD each remote asset
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunResponse {
+    pub args: Vec<String>, // ffmpeg argv, without the program name
+    pub first_pass: Option<Vec<String>>, // analysis pass run before args for two-pass encodes
+    pub duration_ms: u64,
+    pub width: u32,
+    pub height: u32,
+    pub asset_errors: Vec<FieldError>, // only filled with ?checkAssets=true
+    pub timeline: Timeline,
+}
+
+/// The renderer's reading of a design's timing, from `ffmpeg::resolve_timeline`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Timeline {
+    pub duration_ms: u64,    // whole timeline
+    pub range_start_ms: u64, // the rendered part, from startMs/endMs
+    pub range_end_ms: u64,
+    pub items: Vec<TimelineItem>, // in item order
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimelineItem {
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: TrackType,
+    pub start_ms: u64,        // display.from, else trim.from, else 0
+    pub end_ms: u64,          // display.to, else start + trim window (or probed length) at its speed, else the timeline end
+    pub shown_from_ms: u64,   // start_ms minus a transition's lead-in
+    pub layer: Option<usize>, // compositing order, 0 = bottom; None when not drawn (audio, audio-only output)
+    pub input: Option<usize>, // ffmpeg input index; None for text
+}
+
+/// `POST /render/validate`: the design as a render would see it, options merged and defaults filled in.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidateResponse {
+    pub design: Design,
+    pub warnings: Vec<FieldError>, // fields that are ignored or have no effect; never block a render
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogsResponse {
+    pub args: Option<Vec<String>>, // ffmpeg argv without the program name; None until the command is built
+    pub first_pass: Option<Vec<String>>,
+    pub caps: crate::ffmpeg::BackendCaps,
+    pub stderr: Option<String>, // tail of the last ffmpeg run
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressEvent {
+    pub status: String,
+    pub progress: u32,
+}
+
+/// `POST /probe`: a media URL to inspect without rendering.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProbeRequest {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResponse {
+    pub width: Option<u32>,      // none for audio
+    pub height: Option<u32>,
+    pub durationMs: Option<u64>, // none for stills
+    pub hasAudio: bool,
+    pub fps: Option<f64>,
+    pub codec: Option<String>,   // video codec, else the audio one
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthResponse {
+    pub ok: bool,
+    pub caps: crate::ffmpeg::BackendCaps,
+    pub active_jobs: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub jobId: String,
+    #[serde(flatten)]
+    pub status: StatusResponse,
+}
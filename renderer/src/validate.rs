@@ -0,0 +1,249 @@
+use crate::{jobs::Priority, upload::Destination, ffmpeg::{background_image, compute_duration_ms, is_color, item_end_ms, item_start_ms, length_px, output_file_name, output_format, output_size, render_range_ms, rendition_design, valid_rendition_name, resolve_length, sequence_frame_src, BLEND_MODES, CHANNEL_LAYOUTS, MAX_RENDITIONS, OutputFormat, TONE_MAP_CURVES, WATERMARK_POSITIONS}, types::{Design, Details, FieldError, Fill, TrackItem, TrackType}};
+
+/// Upper bound on items per design; larger ones are rejected before any work starts.
+pub const MAX_ITEMS: usize = 1000;
+
+/// Upper bound on frames in one details.sequence; each is a separate download.
+const MAX_SEQUENCE_FRAMES: u32 = 20_000;
+
+/// Synchronous checks run in `submit_render` so obviously broken designs get a 400 with
+/// field-level errors instead of a job that fails later in the worker.
+pub fn validate_design(design: &Design) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let mut err = |field: String, message: &str| errors.push(FieldError { field, message: message.into() });
+
+    if let Some(size) = &design.size {
+        if size.width == 0 { err("size.width".into(), "must be positive"); }
+        if size.height == 0 { err("size.height".into(), "must be positive"); }
+    }
+    if design.fps == Some(0) { err("fps".into(), "must be positive"); }
+    match output_format(design) {
+        Err(e) => err("format".into(), &e.to_string()),
+        Ok(format) => {
+            if let Err(e) = output_file_name(design.outputName.as_deref(), format) { err("outputName".into(), &e.to_string()); }
+            let has_audio_item = design.trackItems.iter().chain(design.trackItemsMap.values()).any(|it| matches!(it.kind, TrackType::Audio));
+            if !format.has_video() && !has_audio_item { err("format".into(), "audio-only output needs at least one audio item"); }
+            if design.mute == Some(true) && !format.has_video() { err("mute".into(), "needs a format with video"); }
+            if design.alpha == Some(true) {
+                if !format.supports_alpha() { err("alpha".into(), "needs format mov or webm"); }
+                if format == OutputFormat::Mov && (design.quality.crf.is_some() || design.quality.videoBitrate.is_some() || design.quality.preset.is_some()) {
+                    err("alpha".into(), "ProRes output doesn't take crf, videoBitrate or preset");
+                }
+            }
+        }
+    }
+    if let Some(sub) = &design.subtitles {
+        if sub.src.trim().is_empty() { err("subtitles.src".into(), "must not be empty"); }
+        if sub.fontSize == Some(0) { err("subtitles.fontSize".into(), "must be positive"); }
+        if output_format(design).is_ok_and(|f| !f.has_video()) { err("subtitles".into(), "audio-only output can't show subtitles"); }
+    }
+    if let Some(bg) = &design.background {
+        if bg.trim().is_empty() { err("background".into(), "must be a color or an image URL"); }
+        else if background_image(design).is_none() && !is_color(bg) { err("background".into(), "not a recognized color"); }
+    }
+    if let Some(wm) = &design.watermark {
+        if wm.src.trim().is_empty() { err("watermark.src".into(), "must not be empty"); }
+        if wm.position.as_deref().is_some_and(|p| !WATERMARK_POSITIONS.iter().any(|(n, _)| *n == p)) {
+            err("watermark.position".into(), "must be top-left, top, top-right, left, center, right, bottom-left, bottom or bottom-right");
+        }
+        if wm.scale.is_some_and(|s| !(s > 0.0 && s.is_finite())) { err("watermark.scale".into(), "must be positive"); }
+        if wm.opacity.is_some_and(|o| !(0.0..=100.0).contains(&o)) { err("watermark.opacity".into(), "must be between 0 and 100"); }
+        if output_format(design).is_ok_and(|f| !f.has_video()) { err("watermark".into(), "audio-only output can't show a watermark"); }
+    }
+    if design.toneMap.as_deref().is_some_and(|t| t != "none" && !TONE_MAP_CURVES.contains(&t)) { err("toneMap".into(), "must be hable, mobius, reinhard, clip or none"); }
+    if design.priority.as_deref().is_some_and(|p| Priority::parse(p).is_none()) { err("priority".into(), "must be low, normal or high"); }
+    if let Some(Err(e)) = design.output.as_ref().map(|o| Destination::parse(&o.destination)) { err("output.destination".into(), &e.to_string()); }
+    if design.quality.crf.is_some() && design.quality.videoBitrate.is_some() { err("crf".into(), "cannot be combined with videoBitrate"); }
+    if let Some(renditions) = &design.renditions {
+        if renditions.len() > MAX_RENDITIONS { err("renditions".into(), &format!("at most {} renditions", MAX_RENDITIONS)); }
+        for (i, r) in renditions.iter().enumerate() {
+            let field = |f: &str| format!("renditions[{}].{}", i, f);
+            if !valid_rendition_name(&r.name) { err(field("name"), "must be 1-32 letters, digits, '-' or '_'"); }
+            else if renditions[..i].iter().any(|o| o.name == r.name) { err(field("name"), "duplicate rendition name"); }
+            if r.size.as_ref().is_some_and(|s| s.width == 0 || s.height == 0) { err(field("size"), "width and height must be positive"); }
+            let rd = rendition_design(design, r);
+            match output_format(&rd) {
+                Err(e) => err(field("format"), &e.to_string()),
+                Ok(format) => {
+                    if !format.has_video() && !design.trackItems.iter().chain(design.trackItemsMap.values()).any(|it| matches!(it.kind, TrackType::Audio)) {
+                        err(field("format"), "audio-only output needs at least one audio item");
+                    }
+                    if r.size.is_some() && !format.has_video() { err(field("size"), "audio-only output has no frames to size"); }
+                    if design.alpha == Some(true) && !format.supports_alpha() { err(field("format"), "alpha output needs mov or webm"); }
+                    if design.mute == Some(true) && !format.has_video() { err(field("format"), "mute needs a format with video"); }
+                }
+            }
+        }
+    }
+
+    for (k, v) in &design.headers { if let Some(msg) = header_error(k, v) { err(format!("headers.{}", k), msg); } }
+
+    let items = item_paths(design);
+    if items.is_empty() { err("trackItems".into(), "design has no items"); }
+    if items.len() > MAX_ITEMS {
+        err("trackItems".into(), &format!("design has {} items, limit is {}", items.len(), MAX_ITEMS));
+        // don't produce per-item errors for thousands of items
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        return errors;
+    }
+
+    let mut renderable = false;
+    for (path, it) in &items {
+        let details = it.details.as_ref();
+        let has_src = details.and_then(|d| d.src.as_ref()).is_some_and(|s| !s.trim().is_empty());
+        match it.kind {
+            TrackType::Text => {
+                renderable = true;
+                if it.id.is_none() { err(format!("{}.id", path), "text items need an id"); }
+                if details.and_then(|d| d.fontUrl.as_ref()).is_none() { err(format!("{}.details.fontUrl", path), "text items need a fontUrl"); }
+            }
+            _ if has_src => renderable = true,
+            TrackType::Image if details.is_some_and(|d| d.fill.is_some()) => renderable = true,
+            _ => err(format!("{}.details.src", path), "media items need a src"),
+        }
+        if let Some(d) = details {
+            for (k, v) in &d.headers { if let Some(msg) = header_error(k, v) { err(format!("{}.details.headers.{}", path, k), msg); } }
+            if let Some(key) = &d.chromaKey {
+                if key.similarity.is_some_and(|s| !(0.00001..=1.0).contains(&s)) { err(format!("{}.details.chromaKey.similarity", path), "must be in (0, 1]"); }
+                if key.blend.is_some_and(|b| !(0.0..=1.0).contains(&b)) { err(format!("{}.details.chromaKey.blend", path), "must be in [0, 1]"); }
+            }
+            if let Some(kb) = &d.kenBurns {
+                if !matches!(it.kind, TrackType::Image) { err(format!("{}.details.kenBurns", path), "only supported on image items"); }
+                for (name, z) in [("startZoom", kb.startZoom), ("endZoom", kb.endZoom)] {
+                    if z.is_some_and(|z| !(1.0..=10.0).contains(&z)) { err(format!("{}.details.kenBurns.{}", path, name), "must be in [1, 10]"); }
+                }
+                for (name, v) in [("startX", kb.startX), ("startY", kb.startY), ("endX", kb.endX), ("endY", kb.endY)] {
+                    if v.is_some_and(|v| !(0.0..=1.0).contains(&v)) { err(format!("{}.details.kenBurns.{}", path, name), "must be in [0, 1]"); }
+                }
+            }
+            for (i, s) in d.strokes.iter().enumerate() {
+                if s.width == 0 { err(format!("{}.details.strokes[{}].width", path, i), "must be positive"); }
+            }
+            if let Some(fill) = &d.fill {
+                if !matches!(it.kind, TrackType::Image) { err(format!("{}.details.fill", path), "only image items can have a fill"); }
+                if has_src { err(format!("{}.details.fill", path), "an item has either a src or a fill"); }
+                match fill {
+                    Fill::Color(c) => if !is_color(c) { err(format!("{}.details.fill", path), "not a recognized color"); },
+                    Fill::Gradient(g) => {
+                        if !(2..=8).contains(&g.stops.len()) { err(format!("{}.details.fill.stops", path), "needs 2 to 8 colors"); }
+                        for (i, c) in g.stops.iter().enumerate() {
+                            if !is_color(c) { err(format!("{}.details.fill.stops[{}]", path, i), "not a recognized color"); }
+                        }
+                    }
+                }
+            }
+            if let Some(seq) = &d.sequence {
+                if !matches!(it.kind, TrackType::Video) { err(format!("{}.details.sequence", path), "only video items can be frame sequences"); }
+                if has_src && d.src.as_deref().and_then(|s| sequence_frame_src(s, 0)).is_none() { err(format!("{}.details.src", path), "a frame sequence src needs a frame number (%d or %04d)"); }
+                if !(1..=MAX_SEQUENCE_FRAMES).contains(&seq.count) { err(format!("{}.details.sequence.count", path), &format!("must be 1 to {}", MAX_SEQUENCE_FRAMES)); }
+                if seq.fps.is_some_and(|f| !(f > 0.0 && f <= 240.0)) { err(format!("{}.details.sequence.fps", path), "must be in (0, 240]"); }
+            }
+            if let Some(cs) = d.colorSpace.as_deref() {
+                if !matches!(it.kind, TrackType::Video | TrackType::Image) { err(format!("{}.details.colorSpace", path), "only video and image items have a source color space"); }
+                if !matches!(cs, "bt709" | "bt2020-pq" | "bt2020-hlg") { err(format!("{}.details.colorSpace", path), "must be bt709, bt2020-pq or bt2020-hlg"); }
+            }
+            if let Some(mode) = d.blendMode.as_deref() {
+                if !matches!(it.kind, TrackType::Video | TrackType::Image) { err(format!("{}.details.blendMode", path), "only video and image items can blend"); }
+                if !mode.eq_ignore_ascii_case("normal") && !BLEND_MODES.iter().any(|(css, _)| css.eq_ignore_ascii_case(mode)) {
+                    let names: Vec<&str> = BLEND_MODES.iter().map(|(css, _)| *css).collect();
+                    err(format!("{}.details.blendMode", path), &format!("must be normal, {}", names.join(", ")));
+                }
+            }
+            if let Some(layout) = d.channelLayout.as_deref() {
+                if !matches!(it.kind, TrackType::Audio) { err(format!("{}.details.channelLayout", path), "only used on audio items"); }
+                if !CHANNEL_LAYOUTS.contains(&layout) { err(format!("{}.details.channelLayout", path), &format!("must be one of {}", CHANNEL_LAYOUTS.join(", "))); }
+            }
+            if let Some(downmix) = d.downmix.as_deref() {
+                if !matches!(it.kind, TrackType::Audio) { err(format!("{}.details.downmix", path), "only used on audio items"); }
+                if !matches!(downmix, "stereo" | "mono" | "left" | "right") { err(format!("{}.details.downmix", path), "must be stereo, mono, left or right"); }
+            }
+            if let Some(fit) = &d.fit {
+                if !matches!(fit.as_str(), "stretch" | "fill" | "contain" | "cover") { err(format!("{}.details.fit", path), "must be stretch, fill, contain or cover"); }
+            }
+            if let Some(on_end) = d.onEnd.as_deref() {
+                if !matches!(it.kind, TrackType::Video) { err(format!("{}.details.onEnd", path), "only video items can set onEnd"); }
+                if !matches!(on_end, "hold" | "loop" | "blank") { err(format!("{}.details.onEnd", path), "must be hold, loop or blank"); }
+                if d.r#loop == Some(true) && on_end != "loop" { err(format!("{}.details.onEnd", path), "conflicts with loop: true"); }
+            }
+            if let Some(tr) = &d.transition {
+                if matches!(it.kind, TrackType::Text) { err(format!("{}.details.transition", path), "not supported on text items"); }
+                if !matches!(tr.kind.as_deref(), None | Some("fade") | Some("crossfade")) { err(format!("{}.details.transition.type", path), "must be fade or crossfade"); }
+                if tr.durationMs == 0 { err(format!("{}.details.transition.durationMs", path), "must be positive"); }
+            }
+            if let Some(speed) = d.speed {
+                if !matches!(it.kind, TrackType::Video | TrackType::Audio) { err(format!("{}.details.speed", path), "only supported on video and audio items"); }
+                if !(0.0625..=16.0).contains(&speed) { err(format!("{}.details.speed", path), "must be in [0.0625, 16]"); }
+            }
+            let (out_w, out_h) = output_size(design);
+            for (name, l, extent) in [("width", &d.width, out_w), ("height", &d.height, out_h)] {
+                match l.as_ref().map(|l| length_px(l, extent)) {
+                    Some(None) => err(format!("{}.details.{}", path, name), "must be pixels (640, \"640px\") or a percentage (\"50%\")"),
+                    Some(Some(v)) if v < 0.5 => err(format!("{}.details.{}", path, name), "must be positive"),
+                    _ => {}
+                }
+            }
+        }
+        if let (Some(from), Some(to)) = (it.display.from, it.display.to) {
+            if to <= from { err(format!("{}.display", path), "display.to must be after display.from"); }
+        }
+        if let (Some(from), Some(to)) = (it.trim.from, it.trim.to) {
+            if to <= from { err(format!("{}.trim", path), "trim.to must be after trim.from"); }
+        }
+    }
+    if let (Some(start), Some(end)) = (design.startMs, design.endMs) {
+        if end <= start { err("endMs".into(), "must be after startMs"); }
+    }
+    if design.defaultDurationMs == Some(0) { err("defaultDurationMs".into(), "must be positive"); }
+    if design.startMs.is_some_and(|s| !items.is_empty() && s >= compute_duration_ms(design)) { err("startMs".into(), "is past the end of the timeline"); }
+    if !items.is_empty() && !renderable { err("trackItems".into(), "at least one item needs a src or fill, or be text"); }
+
+    errors.sort_by(|a, b| a.field.cmp(&b.field));
+    errors
+}
+
+/// The items that get rendered, with their field path: trackItems when set, else trackItemsMap by key.
+fn item_paths(design: &Design) -> Vec<(String, &TrackItem)> {
+    if !design.trackItems.is_empty() {
+        design.trackItems.iter().enumerate().map(|(i, it)| (format!("trackItems[{}]", i), it)).collect()
+    } else {
+        let mut entries: Vec<(String, &TrackItem)> = design.trackItemsMap.iter().map(|(k, it)| (format!("trackItemsMap.{}", k), it)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
+
+/// Things in a valid design that won't show up in the output: parts the renderer ignores and items
+/// outside the rendered range. Reported by `POST /render/validate`; they never block a render.
+pub fn design_warnings(design: &Design) -> Vec<FieldError> {
+    let mut warnings = Vec::new();
+    let mut warn = |field: String, message: &str| warnings.push(FieldError { field, message: message.into() });
+    if !design.trackItems.is_empty() && !design.trackItemsMap.is_empty() { warn("trackItemsMap".into(), "ignored because trackItems is set"); }
+    let format = output_format(design).ok();
+    let (range_start, range_end) = render_range_ms(design);
+    for (path, it) in item_paths(design) {
+        let visual = matches!(it.kind, TrackType::Video | TrackType::Image | TrackType::Text);
+        if visual && format.is_some_and(|f| !f.has_video()) { warn(path.clone(), "not drawn in audio-only output"); }
+        if matches!(it.kind, TrackType::Audio) && format == Some(OutputFormat::Gif) { warn(path.clone(), "gif output has no audio"); }
+        if matches!(it.kind, TrackType::Audio) && design.mute == Some(true) { warn(path.clone(), "not used in muted output"); }
+        if matches!(it.kind, TrackType::Video) { warn_if(&mut warn, &path, it, "volume", |d| d.volume.is_some(), "video items are rendered without their audio"); }
+        if !matches!(it.kind, TrackType::Audio) { warn_if(&mut warn, &path, it, "audioFadeMs", |d| d.audioFadeMs.is_some(), "only used on audio items"); }
+        if matches!(it.kind, TrackType::Audio | TrackType::Text) { warn_if(&mut warn, &path, it, "keyframes", |d| !d.keyframes.is_empty(), "only used on video and image items"); }
+        for (name, v) in it.details.iter().flat_map(|d| [("left", &d.left), ("top", &d.top)]) {
+            if v.as_deref().is_some_and(|v| resolve_length(v, 100).is_none()) { warn(format!("{}.details.{}", path, name), "unsupported unit, treated as 0"); }
+        }
+        let (start, end) = (item_start_ms(it), item_end_ms(it).unwrap_or(u64::MAX));
+        if end <= range_start || start >= range_end { warn(format!("{}.display", path), "outside the rendered range"); }
+    }
+    warnings.sort_by(|a, b| a.field.cmp(&b.field));
+    warnings
+}
+
+fn warn_if(warn: &mut impl FnMut(String, &str), path: &str, it: &TrackItem, field: &str, set: impl Fn(&Details) -> bool, message: &str) {
+    if it.details.as_ref().is_some_and(set) { warn(format!("{}.details.{}", path, field), message); }
+}
+
+fn header_error(name: &str, value: &str) -> Option<&'static str> {
+    if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() { Some("invalid header name") }
+    else if reqwest::header::HeaderValue::from_str(value).is_err() { Some("invalid header value") }
+    else { None }
+}